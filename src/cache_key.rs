@@ -0,0 +1,252 @@
+use crate::helper::sha256_hash;
+use crate::models::Message;
+use serde_json::Value;
+
+/* Bump when the canonical form changes, so old entries in nsq_message_cache are no longer matched */
+pub const CACHE_KEY_VERSION: u32 = 2;
+
+/* Kwargs that change how a request is served, not what it computes */
+const IGNORED_KWARGS: [&str; 1] = ["force_refresh"];
+
+/* How a handler's args and kwargs take part in the cache key */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ArgRules {
+    // Args are a set, e.g. the sravz ids joined by leveraged funds
+    pub order_insensitive: bool,
+    // Args are identifiers whose case carries no meaning
+    pub case_insensitive: bool,
+    // Kwargs strings, json_keys included, carry no meaning in their case. Off for free text such as llm_query.
+    pub case_insensitive_kwargs: bool,
+}
+
+/* Canonical JSON of the fields that identify a request: id, args and kwargs.
+Object keys are sorted at every level, IGNORED_KWARGS are left out. */
+pub fn canonical_json(message: &Message, rules: ArgRules) -> String {
+    let mut args: Vec<String> = message
+        .p_i
+        .args
+        .iter()
+        .map(|arg| {
+            if rules.case_insensitive {
+                arg.to_lowercase()
+            } else {
+                arg.clone()
+            }
+        })
+        .collect();
+    if rules.order_insensitive {
+        args.sort();
+    }
+
+    let kwargs = match serde_json::to_value(&message.p_i.kwargs) {
+        Ok(Value::Object(map)) => Value::Object(
+            map.into_iter()
+                .filter(|(k, _)| !IGNORED_KWARGS.contains(&k.as_str()))
+                .map(|(k, v)| {
+                    if rules.case_insensitive_kwargs {
+                        (k, lowercase_strings(v))
+                    } else {
+                        (k, v)
+                    }
+                })
+                .collect(),
+        ),
+        Ok(value) => value,
+        Err(_) => Value::Null,
+    };
+
+    let mut canonical = String::new();
+    write_canonical(
+        &serde_json::json!({
+            "id": message.id,
+            "args": args,
+            "kwargs": kwargs,
+        }),
+        &mut canonical,
+    );
+    canonical
+}
+
+/* Versioned SHA-256 of the canonical JSON, `v<CACHE_KEY_VERSION>-<hex>` */
+pub fn cache_key(message: &Message, rules: ArgRules) -> String {
+    format!(
+        "v{}-{}",
+        CACHE_KEY_VERSION,
+        sha256_hash(&canonical_json(message, rules))
+    )
+}
+
+fn lowercase_strings(value: Value) -> Value {
+    match value {
+        Value::String(s) => Value::String(s.to_lowercase()),
+        Value::Array(items) => Value::Array(items.into_iter().map(lowercase_strings).collect()),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, lowercase_strings(v)))
+                .collect(),
+        ),
+        _ => value,
+    }
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(k.clone()).to_string());
+                out.push(':');
+                write_canonical(v, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        _ => out.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Kwargs, PI};
+
+    // Rules of the leveraged funds and earnings handlers
+    const SET_RULES: ArgRules = ArgRules {
+        order_insensitive: true,
+        case_insensitive: true,
+        case_insensitive_kwargs: false,
+    };
+    const POSITIONAL_RULES: ArgRules = ArgRules {
+        order_insensitive: false,
+        case_insensitive: true,
+        case_insensitive_kwargs: false,
+    };
+
+    fn request(id: f64, args: &[&str], llm_query: Option<&str>) -> Message {
+        Message {
+            id,
+            p_i: PI {
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                kwargs: Kwargs {
+                    device: "Mobile".to_string(),
+                    upload_to_aws: true,
+                    json_keys: None,
+                    llm_query: llm_query.map(String::from),
+                    force_refresh: None,
+                },
+            },
+            ..Default::default()
+        }
+    }
+
+    // Golden values: if these change, bump CACHE_KEY_VERSION
+    #[test]
+    fn test_canonical_json_golden() {
+        let message = request(1.0, &["etf_us_TQQQ", "etf_us_qld"], None);
+        assert_eq!(
+            canonical_json(&message, SET_RULES),
+            r#"{"args":["etf_us_qld","etf_us_tqqq"],"id":1.0,"kwargs":{"device":"Mobile","upload_to_aws":true}}"#
+        );
+        let message = request(3.0, &["stk_us_nvda", "NVDA"], Some("Plot EPS"));
+        assert_eq!(
+            canonical_json(&message, POSITIONAL_RULES),
+            r#"{"args":["stk_us_nvda","nvda"],"id":3.0,"kwargs":{"device":"Mobile","llm_query":"Plot EPS","upload_to_aws":true}}"#
+        );
+    }
+
+    #[test]
+    fn test_canonical_json_golden_case_insensitive_kwargs() {
+        let mut message = request(3.0, &["stk_us_nvda"], Some("Plot EPS"));
+        message.p_i.kwargs.json_keys = Some(vec!["EPS".to_string()]);
+        let rules = ArgRules {
+            case_insensitive_kwargs: true,
+            ..POSITIONAL_RULES
+        };
+        assert_eq!(
+            canonical_json(&message, rules),
+            r#"{"args":["stk_us_nvda"],"id":3.0,"kwargs":{"device":"mobile","json_keys":["eps"],"llm_query":"plot eps","upload_to_aws":true}}"#
+        );
+    }
+
+    #[test]
+    fn test_cache_key_keeps_llm_query_case() {
+        let rules = POSITIONAL_RULES;
+        assert_ne!(
+            cache_key(&request(3.0, &["stk_us_nvda"], Some("Plot EPS")), rules),
+            cache_key(&request(3.0, &["stk_us_nvda"], Some("plot eps")), rules)
+        );
+    }
+
+    #[test]
+    fn test_cache_key_golden() {
+        let message = request(1.0, &["etf_us_tqqq", "etf_us_qld"], None);
+        assert_eq!(
+            cache_key(&message, SET_RULES),
+            "v2-640a8911150a5ec24be037a4359f3f7eebbd18fd2859e7da87d53571e4d63e98"
+        );
+    }
+
+    #[test]
+    fn test_cache_key_ignores_arg_order_and_case_when_allowed() {
+        let rules = SET_RULES;
+        assert_eq!(
+            cache_key(&request(1.0, &["etf_us_tqqq", "etf_us_qld"], None), rules),
+            cache_key(&request(1.0, &["ETF_US_QLD", "etf_us_tqqq"], None), rules)
+        );
+    }
+
+    #[test]
+    fn test_cache_key_keeps_positional_args() {
+        let rules = POSITIONAL_RULES;
+        assert_eq!(
+            cache_key(&request(3.0, &["stk_us_nvda", "NVDA"], None), rules),
+            cache_key(&request(3.0, &["stk_us_nvda", "nvda"], None), rules)
+        );
+        assert_ne!(
+            cache_key(&request(3.0, &["stk_us_nvda", "NVDA"], None), rules),
+            cache_key(&request(3.0, &["NVDA", "stk_us_nvda"], None), rules)
+        );
+    }
+
+    #[test]
+    fn test_cache_key_ignores_fields_outside_the_request() {
+        let rules = ArgRules::default();
+        let mut other = request(2.0, &["fund_us_fbgrx.json"], Some("query"));
+        other.cid = "another client".to_string();
+        other.t_o = "another topic".to_string();
+        other.attempts = 2;
+        assert_eq!(
+            cache_key(&request(2.0, &["fund_us_fbgrx.json"], Some("query")), rules),
+            cache_key(&other, rules)
+        );
+        assert_ne!(
+            cache_key(&request(2.0, &["fund_us_fbgrx.json"], Some("query")), rules),
+            cache_key(&request(2.001, &["fund_us_fbgrx.json"], Some("query")), rules)
+        );
+    }
+
+    #[test]
+    fn test_cache_key_ignores_force_refresh() {
+        let rules = POSITIONAL_RULES;
+        let mut refresh = request(3.0, &["stk_us_nvda", "NVDA"], None);
+        refresh.p_i.kwargs.force_refresh = Some(true);
+        assert_eq!(
+            cache_key(&request(3.0, &["stk_us_nvda", "NVDA"], None), rules),
+            cache_key(&refresh, rules)
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::CacheConfig;
+use crate::models::Message;
+use chrono::{DateTime, Duration, Utc};
+
+/* Decides whether a result in nsq_message_cache can be sent instead of reprocessing */
+pub struct CachePolicy {
+    config: CacheConfig,
+}
+
+impl CachePolicy {
+    pub fn new(config: CacheConfig) -> Self {
+        CachePolicy { config }
+    }
+
+    /* [config.cache.ttl_secs] wins over the handler's own default, then default_ttl_secs applies */
+    pub fn ttl(&self, handler: &str, handler_default_secs: Option<u64>) -> Duration {
+        let ttl_secs = self
+            .config
+            .ttl_secs
+            .get(handler)
+            .copied()
+            .or(handler_default_secs)
+            .unwrap_or(self.config.default_ttl_secs);
+        Duration::seconds(ttl_secs.min(i64::MAX as u64) as i64)
+    }
+
+    /* The client opts out with `cache_message: false` or the `force_refresh` kwarg */
+    pub fn bypass_requested(&self, request: &Message) -> bool {
+        !request.cache_message || request.p_i.kwargs.force_refresh.unwrap_or(false)
+    }
+
+    pub fn can_reuse(&self, request: &Message, ttl: Duration, cached: &Message, now: DateTime<Utc>) -> bool {
+        if self.bypass_requested(request) {
+            return false;
+        }
+        ttl > Duration::zero() && cached.date + ttl > now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn cache_policy() -> CachePolicy {
+        CachePolicy::new(CacheConfig {
+            default_ttl_secs: 60,
+            ttl_secs: HashMap::from([
+                ("earnings".to_string(), 3 * 24 * 60 * 60),
+                ("langchain".to_string(), 0),
+            ]),
+        })
+    }
+
+    fn request() -> Message {
+        Message {
+            cache_message: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_can_reuse_honors_handler_ttl() {
+        let cache_policy = cache_policy();
+        let now = Utc::now();
+        let cached = Message {
+            date: now - Duration::days(2),
+            ..Default::default()
+        };
+        assert!(cache_policy.can_reuse(&request(), cache_policy.ttl("earnings", None), &cached, now));
+        assert!(!cache_policy.can_reuse(&request(), cache_policy.ttl("leveraged_funds", None), &cached, now));
+        let fresh = Message {
+            date: now,
+            ..Default::default()
+        };
+        assert!(cache_policy.can_reuse(&request(), cache_policy.ttl("leveraged_funds", None), &fresh, now));
+        // A TTL of 0 never reuses a result
+        assert!(!cache_policy.can_reuse(&request(), cache_policy.ttl("langchain", None), &fresh, now));
+    }
+
+    #[test]
+    fn test_can_reuse_honors_client_bypass() {
+        let cache_policy = cache_policy();
+        let now = Utc::now();
+        let cached = Message {
+            date: now,
+            ..Default::default()
+        };
+        let mut no_cache = request();
+        no_cache.cache_message = false;
+        assert!(!cache_policy.can_reuse(&no_cache, cache_policy.ttl("earnings", None), &cached, now));
+        let mut force_refresh = request();
+        force_refresh.p_i.kwargs.force_refresh = Some(true);
+        assert!(!cache_policy.can_reuse(&force_refresh, cache_policy.ttl("earnings", None), &cached, now));
+    }
+
+    #[test]
+    fn test_ttl_falls_back_to_handler_default() {
+        let cache_policy = cache_policy();
+        assert_eq!(cache_policy.ttl("langchain", Some(3600)), Duration::zero());
+        assert_eq!(cache_policy.ttl("new_handler", Some(0)), Duration::zero());
+        assert_eq!(cache_policy.ttl("new_handler", None), Duration::seconds(60));
+    }
+}
@@ -1,7 +1,9 @@
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::process::exit;
+use std::time::Duration;
 
 // Top level struct to hold the TOML data.
 #[derive(Deserialize)]
@@ -13,6 +15,582 @@ struct Data {
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub backend_rust_topic: String,
+    // Number of NSQ messages processed concurrently
+    #[serde(default = "default_worker_pool_size")]
+    pub worker_pool_size: usize,
+    // Seconds in-flight messages get to finish on SIGTERM before they are aborted
+    #[serde(default = "default_shutdown_grace_period_secs")]
+    pub shutdown_grace_period_secs: u64,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub producer: ProducerConfig,
+    #[serde(default)]
+    pub claim: ClaimConfig,
+    #[serde(default)]
+    pub lease: LeaseConfig,
+    // Transport for requests and replies, `memory` runs without nsqd for local development
+    #[serde(default)]
+    pub message_bus: MessageBusKind,
+    #[serde(default)]
+    pub http: HttpConfig,
+    // Where results and job status are kept, `memory` runs without MongoDB for local development
+    #[serde(default)]
+    pub message_store: MessageStoreKind,
+    #[serde(default)]
+    pub mongo: MongoConfig,
+    #[serde(default)]
+    pub store: StoreConfig,
+    #[serde(default)]
+    pub payloads: PayloadConfig,
+    // Where services read and write objects, `local` serves a directory for running without credentials
+    #[serde(default)]
+    pub object_store: ObjectStoreKind,
+    #[serde(default)]
+    pub local_store: LocalStoreConfig,
+    #[serde(default)]
+    pub object_cache: ObjectCacheConfig,
+    #[serde(default)]
+    pub s3: S3Config,
+    // Publish progress events on the reply topic while a job runs
+    #[serde(default = "default_progress_events")]
+    pub progress_events: bool,
+}
+
+// HttpConfig holds the `[config.http]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Loopback by default, set HTTP_API_TOKEN before binding to other interfaces
+    #[serde(default = "default_http_bind_address")]
+    pub bind_address: String,
+    // Serve POST /uploads, which presigns PUTs into [config.s3] data_bucket
+    #[serde(default)]
+    pub uploads_enabled: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: false,
+            bind_address: default_http_bind_address(),
+            uploads_enabled: false,
+        }
+    }
+}
+
+fn default_http_bind_address() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageStoreKind {
+    #[default]
+    Mongo,
+    // Kept in process memory, lost on restart
+    Memory,
+}
+
+// MongoConfig holds the `[config.mongo]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MongoConfig {
+    // Database of nsq_message_cache and messages_wip
+    #[serde(default = "default_mongo_database")]
+    pub database: String,
+    // Results older than this are deleted from nsq_message_cache by a TTL index,
+    // defaults to the longest cache TTL and at least a day so results can still be polled
+    #[serde(default)]
+    pub cache_retention_secs: Option<u64>,
+    // Job records older than this are deleted from messages_wip
+    #[serde(default = "default_wip_retention_secs")]
+    pub wip_retention_secs: u64,
+    // How long an operation waits for a reachable server before it fails
+    #[serde(default = "default_server_selection_timeout_ms")]
+    pub server_selection_timeout_ms: u64,
+}
+
+impl Default for MongoConfig {
+    fn default() -> Self {
+        MongoConfig {
+            database: default_mongo_database(),
+            cache_retention_secs: None,
+            wip_retention_secs: default_wip_retention_secs(),
+            server_selection_timeout_ms: default_server_selection_timeout_ms(),
+        }
+    }
+}
+
+impl MongoConfig {
+    pub fn cache_retention(&self, cache: &CacheConfig) -> Duration {
+        let secs = self.cache_retention_secs.unwrap_or_else(|| {
+            cache
+                .ttl_secs
+                .values()
+                .copied()
+                .chain([cache.default_ttl_secs, 24 * 60 * 60])
+                .max()
+                .unwrap_or_default()
+        });
+        Duration::from_secs(secs)
+    }
+
+    pub fn wip_retention(&self) -> Duration {
+        Duration::from_secs(self.wip_retention_secs)
+    }
+}
+
+fn default_mongo_database() -> String {
+    "sravz".to_string()
+}
+
+fn default_wip_retention_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_server_selection_timeout_ms() -> u64 {
+    5_000
+}
+
+// StoreConfig holds the `[config.store]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct StoreConfig {
+    // Retries of an operation that failed because the store was unreachable
+    #[serde(default = "default_store_retry_attempts")]
+    pub retry_attempts: u32,
+    // Doubled after every retry
+    #[serde(default = "default_store_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    // While degraded, one operation per interval is let through to check whether the store is back
+    #[serde(default = "default_store_probe_interval_secs")]
+    pub probe_interval_secs: u64,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            retry_attempts: default_store_retry_attempts(),
+            retry_backoff_ms: default_store_retry_backoff_ms(),
+            probe_interval_secs: default_store_probe_interval_secs(),
+        }
+    }
+}
+
+fn default_store_retry_attempts() -> u32 {
+    2
+}
+
+fn default_store_retry_backoff_ms() -> u64 {
+    100
+}
+
+fn default_store_probe_interval_secs() -> u64 {
+    10
+}
+
+// PayloadConfig holds the `[config.payloads]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PayloadConfig {
+    // `d_o.data` larger than this, as JSON, is stored out-of-line and referenced from the message
+    #[serde(default = "default_inline_limit_bytes")]
+    pub inline_limit_bytes: usize,
+}
+
+impl Default for PayloadConfig {
+    fn default() -> Self {
+        PayloadConfig {
+            inline_limit_bytes: default_inline_limit_bytes(),
+        }
+    }
+}
+
+fn default_inline_limit_bytes() -> usize {
+    1024 * 1024
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectStoreKind {
+    #[default]
+    S3,
+    // Buckets are sub directories of [config.local_store] root
+    Local,
+}
+
+// LocalStoreConfig holds the `[config.local_store]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LocalStoreConfig {
+    #[serde(default = "default_local_store_root")]
+    pub root: String,
+}
+
+impl Default for LocalStoreConfig {
+    fn default() -> Self {
+        LocalStoreConfig {
+            root: default_local_store_root(),
+        }
+    }
+}
+
+fn default_local_store_root() -> String {
+    "object_store".to_string()
+}
+
+// ObjectCacheConfig holds the `[config.object_cache]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ObjectCacheConfig {
+    // Keep downloaded objects on disk and revalidate them instead of downloading them again
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_object_cache_directory")]
+    pub directory: String,
+    // Least recently used objects are removed above this size
+    #[serde(default = "default_object_cache_max_size_bytes")]
+    pub max_size_bytes: u64,
+}
+
+impl Default for ObjectCacheConfig {
+    fn default() -> Self {
+        ObjectCacheConfig {
+            enabled: false,
+            directory: default_object_cache_directory(),
+            max_size_bytes: default_object_cache_max_size_bytes(),
+        }
+    }
+}
+
+fn default_object_cache_directory() -> String {
+    "/tmp/object_cache".to_string()
+}
+
+fn default_object_cache_max_size_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+// S3Config holds the `[config.s3]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct S3Config {
+    // Host, or a URL such as http://localhost:9000 for MinIO
+    #[serde(default = "default_s3_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    // Handler outputs such as plots, published under output_prefix
+    #[serde(default = "default_s3_output_bucket")]
+    pub output_bucket: String,
+    #[serde(default = "default_s3_output_prefix")]
+    pub output_prefix: String,
+    // Public URL of output_prefix, defaults to <endpoint>/<output_bucket>/<output_prefix>
+    #[serde(default)]
+    pub public_url_prefix: Option<String>,
+    // Historical quotes, earnings and cached data provider responses
+    #[serde(default = "default_s3_data_bucket")]
+    pub data_bucket: String,
+    #[serde(default = "default_s3_historical_prefix")]
+    pub historical_prefix: String,
+    #[serde(default = "default_s3_eod_prefix")]
+    pub eod_prefix: String,
+    // Client uploads presigned by POST /uploads, in data_bucket
+    #[serde(default = "default_s3_upload_prefix")]
+    pub upload_prefix: String,
+    // Larger uploads are sent as a multipart upload
+    #[serde(default = "default_multipart_threshold_bytes")]
+    pub multipart_threshold_bytes: u64,
+    // At least 5 MiB, the S3 minimum for every part but the last
+    #[serde(default = "default_multipart_part_size_bytes")]
+    pub multipart_part_size_bytes: u64,
+    // Parts uploaded at the same time
+    #[serde(default = "default_multipart_concurrency")]
+    pub multipart_concurrency: usize,
+    // Expiry of presigned URLs unless a caller sets one, at most 7 days
+    #[serde(default = "default_presign_expiry_secs")]
+    pub presign_expiry_secs: u64,
+    // Handlers return presigned instead of public_url_prefix URLs in DO.signed_url
+    #[serde(default)]
+    pub presign_outputs: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            endpoint: default_s3_endpoint(),
+            region: default_s3_region(),
+            output_bucket: default_s3_output_bucket(),
+            output_prefix: default_s3_output_prefix(),
+            public_url_prefix: None,
+            data_bucket: default_s3_data_bucket(),
+            historical_prefix: default_s3_historical_prefix(),
+            eod_prefix: default_s3_eod_prefix(),
+            upload_prefix: default_s3_upload_prefix(),
+            multipart_threshold_bytes: default_multipart_threshold_bytes(),
+            multipart_part_size_bytes: default_multipart_part_size_bytes(),
+            multipart_concurrency: default_multipart_concurrency(),
+            presign_expiry_secs: default_presign_expiry_secs(),
+            presign_outputs: false,
+        }
+    }
+}
+
+impl S3Config {
+    pub fn output_key(&self, file_name: &str) -> String {
+        format!("{}{}", self.output_prefix, file_name)
+    }
+
+    pub fn historical_key(&self, file_name: &str) -> String {
+        format!("{}{}", self.historical_prefix, file_name)
+    }
+
+    pub fn eod_key(&self, path: &str) -> String {
+        format!("{}{}", self.eod_prefix, path)
+    }
+
+    pub fn upload_key(&self, path: &str) -> String {
+        format!("{}{}", self.upload_prefix, path)
+    }
+
+    pub fn public_url_prefix(&self) -> String {
+        self.public_url_prefix.clone().unwrap_or_else(|| {
+            let endpoint = if self.endpoint.contains("://") {
+                self.endpoint.clone()
+            } else {
+                format!("https://{}", self.endpoint)
+            };
+            format!(
+                "{}/{}/{}",
+                endpoint.trim_end_matches('/'),
+                self.output_bucket,
+                self.output_prefix
+            )
+        })
+    }
+}
+
+fn default_s3_endpoint() -> String {
+    "usc1.contabostorage.com".to_string()
+}
+
+fn default_s3_region() -> String {
+    "custom".to_string()
+}
+
+fn default_s3_output_bucket() -> String {
+    "sravz".to_string()
+}
+
+fn default_s3_output_prefix() -> String {
+    "rust-backend/".to_string()
+}
+
+fn default_s3_data_bucket() -> String {
+    "sravz-data".to_string()
+}
+
+fn default_s3_historical_prefix() -> String {
+    "historical/".to_string()
+}
+
+fn default_s3_eod_prefix() -> String {
+    "eod/".to_string()
+}
+
+fn default_s3_upload_prefix() -> String {
+    "uploads/".to_string()
+}
+
+fn default_multipart_threshold_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_multipart_part_size_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_multipart_concurrency() -> usize {
+    4
+}
+
+fn default_presign_expiry_secs() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageBusKind {
+    #[default]
+    Nsq,
+    // Requests are read as JSON lines from stdin, replies are logged
+    Memory,
+}
+
+// ClaimConfig holds the `[config.claim]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClaimConfig {
+    // How long a message whose key another worker owns waits for that worker's result,
+    // keep it below the nsqd msg-timeout (60s by default)
+    #[serde(default = "default_claim_wait_secs")]
+    pub wait_secs: u64,
+    #[serde(default = "default_claim_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+impl Default for ClaimConfig {
+    fn default() -> Self {
+        ClaimConfig {
+            wait_secs: default_claim_wait_secs(),
+            poll_interval_ms: default_claim_poll_interval_ms(),
+        }
+    }
+}
+
+fn default_claim_wait_secs() -> u64 {
+    30
+}
+
+fn default_claim_poll_interval_ms() -> u64 {
+    500
+}
+
+// LeaseConfig holds the `[config.lease]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LeaseConfig {
+    // How long a claim on messages_wip lasts without a heartbeat
+    #[serde(default = "default_lease_ttl_secs")]
+    pub ttl_secs: u64,
+    // Should be well below ttl_secs so a slow heartbeat does not lose the lease
+    #[serde(default = "default_lease_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    // How often expired leases are looked for and their jobs marked FAILED
+    #[serde(default = "default_lease_sweep_interval_secs")]
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        LeaseConfig {
+            ttl_secs: default_lease_ttl_secs(),
+            heartbeat_interval_secs: default_lease_heartbeat_interval_secs(),
+            sweep_interval_secs: default_lease_sweep_interval_secs(),
+        }
+    }
+}
+
+fn default_lease_ttl_secs() -> u64 {
+    60
+}
+
+fn default_lease_heartbeat_interval_secs() -> u64 {
+    15
+}
+
+fn default_lease_sweep_interval_secs() -> u64 {
+    60
+}
+
+// ProducerConfig holds the `[config.producer]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProducerConfig {
+    // How long nsqd gets to acknowledge a publish before the next host is tried
+    #[serde(default = "default_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+    #[serde(default = "default_health_check_interval_secs")]
+    pub health_check_interval_secs: u64,
+}
+
+impl Default for ProducerConfig {
+    fn default() -> Self {
+        ProducerConfig {
+            ack_timeout_ms: default_ack_timeout_ms(),
+            health_check_interval_secs: default_health_check_interval_secs(),
+        }
+    }
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+// CacheConfig holds the `[config.cache]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheConfig {
+    // How long a result in nsq_message_cache is reused for handlers not listed in `ttl_secs`
+    #[serde(default = "default_cache_ttl_secs")]
+    pub default_ttl_secs: u64,
+    // TTL by handler name, 0 means results are never reused
+    #[serde(default)]
+    pub ttl_secs: HashMap<String, u64>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            default_ttl_secs: default_cache_ttl_secs(),
+            ttl_secs: HashMap::new(),
+        }
+    }
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+// RetryConfig holds the `[config.retry]` section.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetryConfig {
+    // Attempts for handlers not listed in `handlers`, including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    // Topic that receives messages which failed their last attempt
+    pub dead_letter_topic: Option<String>,
+    // Max attempts by handler name, e.g. `earnings = 3`
+    #[serde(default)]
+    pub handlers: HashMap<String, u32>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: default_max_attempts(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            dead_letter_topic: None,
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    5_000
+}
+
+fn default_max_backoff_ms() -> u64 {
+    300_000
+}
+
+fn default_progress_events() -> bool {
+    true
+}
+
+fn default_worker_pool_size() -> usize {
+    4
+}
+
+fn default_shutdown_grace_period_secs() -> u64 {
+    30
 }
 
 #[derive(Debug, Clone)]
@@ -21,9 +599,6 @@ pub struct AppConfig {
     pub nsq_host: String,
     pub nsq_lookupd_host: String,
     pub mongolab_uri: String,
-    pub contabo_bucket: String,
-    pub contabo_bucket_key: String,
-    pub contabo_object_url_prefix: String,
     pub eodhistoricaldata_api_key: String,
     pub eodhistoricaldata_api_key2: String,
     pub data_provider_url: String,
@@ -59,12 +634,6 @@ impl AppConfig {
         let nsq_lookupd_host = env_var("NSQ_LOOKUPD_HOST")?;
         let mongolab_uri = env_var("MONGOLAB_URI")?;
         let node_env = env_var("NODE_ENV")?;
-        let contabo_bucket = "sravz".to_string();
-        let contabo_bucket_key = "rust-backend".to_string();
-        let contabo_object_url_prefix = format!(
-            "https://usc1.contabostorage.com/adc59f4bb6a74373a1ebd286a7b11b60:{}/{}/",
-            contabo_bucket, contabo_bucket_key
-        );
         let eodhistoricaldata_api_key = env_var("EODHISTORICALDATA_API_KEY")?;
         let eodhistoricaldata_api_key2 = env_var("EODHISTORICALDATA_API_KEY2")?;
 
@@ -84,9 +653,6 @@ impl AppConfig {
             nsq_host,
             nsq_lookupd_host,
             mongolab_uri,
-            contabo_bucket,
-            contabo_bucket_key,
-            contabo_object_url_prefix,
             config: data.config,
             eodhistoricaldata_api_key,
             eodhistoricaldata_api_key2,
@@ -94,3 +660,20 @@ impl AppConfig {
         })
     }
 }
+
+#[cfg(test)]
+impl AppConfig {
+    /* Config without environment variables or a config file, `config` is the [config] table */
+    pub(crate) fn for_tests(config: &str) -> Self {
+        AppConfig {
+            node_env: "test".to_string(),
+            nsq_host: String::new(),
+            nsq_lookupd_host: String::new(),
+            mongolab_uri: String::new(),
+            eodhistoricaldata_api_key: String::new(),
+            eodhistoricaldata_api_key2: String::new(),
+            data_provider_url: String::new(),
+            config: toml::from_str(config).unwrap(),
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use crate::config::AppConfig;
+use crate::config::{AppConfig, S3Config};
+use crate::handler_registry::PermanentError;
+use crate::object_store::{PresignOptions, PutOptions, S3Error};
 use crate::rest_client::RestClient;
 use crate::s3_service::S3Module;
 use chrono::{Duration, Utc};
@@ -8,38 +10,30 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::error::{self, Error};
 use std::io::Cursor;
+use std::sync::Arc;
 use tempfile::NamedTempFile;
 
 pub struct DataFrameCache {
-    dataframe_map: HashMap<String, DataFrame>,
-    s3_module: S3Module,
+    s3_module: Arc<S3Module>,
+    s3_config: S3Config,
     rest_client: RestClient,
 }
 
 impl<'a> DataFrameCache {
-    pub fn new() -> Self {
-        let dataframe_map = HashMap::new();
-        let s3_module = S3Module::new();
-        let _config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let rest_client = RestClient::new();
+    pub fn new(config: Arc<AppConfig>, s3_module: Arc<S3Module>) -> Self {
+        let s3_config = config.config.s3.clone();
+        let rest_client = RestClient::new(config, s3_module.clone());
         DataFrameCache {
-            dataframe_map,
             s3_module,
+            s3_config,
             rest_client,
         }
     }
 
     pub async fn dataframe_to_json(
-        &mut self,
+        &self,
         df: &DataFrame,
-    ) -> Result<String, Box<dyn std::error::Error>> {
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
         // Create a vector to store the JSON representation of each row
         let mut json_rows = Vec::new();
         let num_rows = df.height(); // Number of rows in the DataFrame
@@ -76,9 +70,9 @@ impl<'a> DataFrameCache {
     }
 
     pub async fn dataframe_to_parquet(
-        &mut self,
+        &self,
         mut df: DataFrame,
-    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let tmp_file = NamedTempFile::new()?;
         let file_path = format!("{}.parquet", tmp_file.path().to_string_lossy().to_string());
 
@@ -102,116 +96,105 @@ impl<'a> DataFrameCache {
         }
     }
 
-    /* Get historical data dataframe */
+    /* Get historical data dataframe, parsed on every call so an updated object is never missed.
+    With [config.object_cache] enabled the download is a revalidation of the cached ETag. */
     pub async fn get_dataframe(
-        &mut self,
+        &self,
         sravz_id: String,
-    ) -> Result<Option<DataFrame>, Box<dyn Error>> {
-        let bucket_name = "sravz-data";
-        if self.dataframe_map.contains_key(&sravz_id) {
-            if let Some(value) = self.dataframe_map.get(&sravz_id) {
-                return Ok(Some(value.clone()));
-            }
-        } else {
-            match self
-                .s3_module
-                .download_object(bucket_name, &format!("historical/{}.json", sravz_id), false)
-                .await
-            {
-                Ok(downloaded_content) => {
-                    match self.s3_module.decompress_gzip(downloaded_content) {
-                        Ok(decompressed_data) => {
-                            let cursor: Cursor<Vec<u8>> = Cursor::new(decompressed_data);
-                            let df = JsonReader::new(cursor).finish().unwrap();
-                            let mut df = df.unnest(["Date"]).unwrap();
-                            let df = df.rename("_isoformat", "Date").unwrap();
-                            let mut df = df
-                                .clone()
-                                .lazy()
-                                .select([
-                                    col("Date")
-                                        .str()
-                                        .to_datetime(
-                                            Some(TimeUnit::Microseconds),
-                                            None,
-                                            StrptimeOptions::default(),
-                                            lit("raise"),
-                                        )
-                                        .alias("DateTime"),
-                                    col("*"),
-                                ])
-                                .drop_columns(["Date"])
-                                .collect()?;
-
-                            let old_cols: Vec<String> = df
-                                .get_column_names()
-                                .iter()
-                                .map(|s| s.to_owned().to_owned())
-                                .collect();
-
-                            old_cols.iter().for_each(|old| {
-                                if old != "DateTime" {
-                                    df.rename(old, &format!("{}_{}", sravz_id, old))
-                                        .expect(format!("cannot rename column {}", old).as_str());
-                                }
-                            });
-
-                            // Sort by date desc
-                            df = df.sort(["DateTime"], false, true)?;
-                            // let df_with_constant = df.apply(|name| format!("{}{}", constant_string, name));
-                            self.dataframe_map.insert(sravz_id, df.clone());
-                            info!("Dateframe Head {}", df.head(Some(10)));
-                            // info!("Dateframe Tail {}", df.tail(Some(10)));
-                            // dbg!(df);
-                            return Ok(Some(df.clone()));
-                        }
-                        Err(err) => {
-                            info!("Error during decompression: {:?}", err);
-                        }
-                    }
-                }
-                Err(error) => {
-                    return Err(Box::new(error));
+    ) -> Result<Option<DataFrame>, Box<dyn Error + Send + Sync>> {
+        let bucket_name = &self.s3_config.data_bucket;
+        match self
+            .s3_module
+            .download_object(
+                bucket_name,
+                &self.s3_config.historical_key(&format!("{}.json", sravz_id)),
+            )
+            .await
+        {
+            Ok(decompressed_data) => {
+                // Downloaded bytes that are not a historical quotes dataframe
+                let corrupt = |err: PolarsError| {
+                    S3Error::Corrupt(format!("Historical data of {}: {}", sravz_id, err))
+                };
+                let cursor: Cursor<Vec<u8>> = Cursor::new(decompressed_data);
+                let df = JsonReader::new(cursor).finish().map_err(corrupt)?;
+                let mut df = df.unnest(["Date"]).map_err(corrupt)?;
+                let df = df.rename("_isoformat", "Date").map_err(corrupt)?;
+                let mut df = df
+                    .clone()
+                    .lazy()
+                    .select([
+                        col("Date")
+                            .str()
+                            .to_datetime(
+                                Some(TimeUnit::Microseconds),
+                                None,
+                                StrptimeOptions::default(),
+                                lit("raise"),
+                            )
+                            .alias("DateTime"),
+                        col("*"),
+                    ])
+                    .drop_columns(["Date"])
+                    .collect()
+                    .map_err(corrupt)?;
+
+                let old_cols: Vec<String> = df
+                    .get_column_names()
+                    .iter()
+                    .map(|s| s.to_owned().to_owned())
+                    .collect();
+
+                for old in old_cols.iter().filter(|old| *old != "DateTime") {
+                    df.rename(old, &format!("{}_{}", sravz_id, old))
+                        .map_err(corrupt)?;
                 }
+
+                // Sort by date desc
+                df = df.sort(["DateTime"], false, true).map_err(corrupt)?;
+                // let df_with_constant = df.apply(|name| format!("{}{}", constant_string, name));
+                info!("Dateframe Head {}", df.head(Some(10)));
+                // info!("Dateframe Tail {}", df.tail(Some(10)));
+                // dbg!(df);
+                Ok(Some(df))
             }
+            // Reply with the id rather than the object key
+            Err(S3Error::NotFound(_)) => {
+                Err(Box::new(PermanentError(format!("Unknown sravz_id {}", sravz_id))))
+            }
+            Err(error) => Err(Box::new(error)),
         }
-        return Ok(None);
     }
 
     /* Save data dataframe to s3 */
     pub async fn save_dataframe_to_s3(
-        &mut self,
+        &self,
         df: &DataFrame,
         object_key: &str,
-    ) -> Result<Option<String>, Box<dyn Error>> {
-        let bucket_name = "sravz-data";
-        // Perform the GET request using the mock server URL
-        let result = self.dataframe_to_json(&df).await;
-
-        // Assert that the result is Ok and contains the expected response body
-        // assert!(result.is_ok());
-        match result.unwrap() {
-            json_string => {
-                self.s3_module
-                    .upload_object(&bucket_name, object_key, &json_string)
-                    .await
-                    .unwrap();
-                let presigned_url = self
-                    .s3_module
-                    .generate_presigned_url(bucket_name, object_key)
-                    .await;
-                match presigned_url.unwrap() {
-                    url => return Ok(Some(url)),
-                }
-            }
-        }
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let bucket_name = &self.s3_config.data_bucket;
+        let json_string = self.dataframe_to_json(df).await?;
+        self.s3_module
+            .upload_object(
+                bucket_name,
+                object_key,
+                json_string.as_bytes(),
+                &PutOptions::gzip("application/json"),
+            )
+            .await?;
+        let expires_in = std::time::Duration::from_secs(self.s3_config.presign_expiry_secs);
+        let presigned_url = self
+            .s3_module
+            .presign(bucket_name, object_key, &PresignOptions::get(expires_in))
+            .await?;
+        Ok(Some(presigned_url))
     }
 
     /* Get earning dataframe */
     pub async fn get_earnings_dataframe(
-        &mut self,
+        &self,
         code: &str,
-    ) -> Result<Option<DataFrame>, Box<dyn Error>> {
+    ) -> Result<Option<DataFrame>, Box<dyn Error + Send + Sync>> {
         let mut params = HashMap::new();
         let url_suffix = "api/calendar/earnings";
         params.insert("symbols", code);
@@ -307,31 +290,53 @@ impl<'a> DataFrameCache {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::s3_service::{configured_app_config, fixture_app_config, fixture_s3_module};
 
     #[tokio::test]
     async fn test_historical_dataframe() {
-        let mut data_frame_cache: DataFrameCache = DataFrameCache::new();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let data_frame_cache = DataFrameCache::new(fixture_app_config(), s3_module);
 
         // Perform the GET request using the mock server URL
         let result = data_frame_cache
             .get_dataframe("stk_us_nvda".to_string())
             .await;
 
-        // Assert that the result is Ok and contains the expected response body
-        // assert!(result.is_ok());
-        match result.unwrap() {
-            Some(df) => {
-                println!("Dateframe Head {}", df.head(Some(10)));
-            }
-            None => {
-                println!("No DataFrame found");
-            }
-        }
+        // tests/fixtures/sravz-data/historical/stk_us_nvda.json, newest first
+        let df = result.unwrap().unwrap();
+        assert_eq!(df.height(), 5);
+        assert_eq!(df.get_column_names()[0], "DateTime");
+        assert!(df.column("stk_us_nvda_AdjustedClose").is_ok());
+
+        let unknown = data_frame_cache.get_dataframe("stk_us_unknown".to_string()).await;
+        assert_eq!(unknown.unwrap_err().to_string(), "Unknown sravz_id stk_us_unknown");
+    }
+
+    #[tokio::test]
+    async fn test_updated_historical_data_is_not_served_stale() {
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let data_frame_cache = DataFrameCache::new(fixture_app_config(), s3_module.clone());
+        let df = data_frame_cache.get_dataframe("stk_us_nvda".to_string()).await;
+        assert_eq!(df.unwrap().unwrap().height(), 5);
+
+        let quote = r#"[{"Date": {"_isoformat": "2024-01-02T00:00:00"}, "AdjustedClose": 1.0}]"#;
+        s3_module
+            .upload_object(
+                "sravz-data",
+                "historical/stk_us_nvda.json",
+                quote.as_bytes(),
+                &PutOptions::default(),
+            )
+            .await
+            .unwrap();
+        let df = data_frame_cache.get_dataframe("stk_us_nvda".to_string()).await;
+        assert_eq!(df.unwrap().unwrap().height(), 1);
     }
 
     #[tokio::test]
     async fn test_get_earnings_dataframe() {
-        let mut data_frame_cache: DataFrameCache = DataFrameCache::new();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let data_frame_cache = DataFrameCache::new(configured_app_config(), s3_module);
 
         // Perform the GET request using the mock server URL
         let result = data_frame_cache.get_earnings_dataframe("NVDA").await;
@@ -350,7 +355,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_dataframe_to_json() {
-        let mut data_frame_cache: DataFrameCache = DataFrameCache::new();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let data_frame_cache = DataFrameCache::new(fixture_app_config(), s3_module);
 
         let df = df![
             "DateTime" => &["2023-01-01", "2023-01-02"],
@@ -371,7 +377,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_dataframe_to_parquet() {
-        let mut data_frame_cache: DataFrameCache = DataFrameCache::new();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let data_frame_cache = DataFrameCache::new(fixture_app_config(), s3_module);
 
         let df = df![
             "DateTime" => &["2023-01-01", "2023-01-02"],
@@ -395,7 +402,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_save_dataframe_to_s3() {
-        let mut data_frame_cache: DataFrameCache = DataFrameCache::new();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let data_frame_cache = DataFrameCache::new(fixture_app_config(), s3_module);
 
         let df = df![
             "DateTime" => &["2023-01-01", "2023-01-02"],
@@ -0,0 +1,213 @@
+use crate::cache_key::ArgRules;
+use crate::models::Message;
+use crate::progress::Progress;
+use async_trait::async_trait;
+use serde_derive::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+/* How results of a handler are cached in nsq_message_cache */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HandlerCachePolicy {
+    pub arg_rules: ArgRules,
+    // Used when [config.cache.ttl_secs] has no entry for the handler
+    pub default_ttl_secs: Option<u64>,
+}
+
+/* A service that processes one range of message ids */
+#[async_trait]
+pub trait MessageHandler: Send + Sync {
+    /* Stable name, also the key of per-handler settings in the config */
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /* Message ids served by this handler, e.g. 1.0..=1.009 */
+    fn message_ids(&self) -> RangeInclusive<f64>;
+
+    /* Rejects requests that can never succeed, before any work is done */
+    fn validate(&self, _message: &Message) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn cache_policy(&self) -> HandlerCachePolicy {
+        HandlerCachePolicy::default()
+    }
+
+    /* Stages of long jobs are reported through `progress` */
+    async fn handle(
+        &self,
+        message: Message,
+        progress: Progress,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>>;
+}
+
+/* A request that fails the same way on every attempt, e.g. an unknown sravz_id.
+Handlers return it so the client gets the error at once instead of after every retry. */
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl Error for PermanentError {}
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/* What a client needs to know to call a handler */
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct HandlerInfo {
+    pub name: String,
+    pub description: String,
+    pub min_id: f64,
+    pub max_id: f64,
+}
+
+/* Handlers by message id range. Ranges may not overlap. */
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: Vec<Arc<dyn MessageHandler>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        HandlerRegistry::default()
+    }
+
+    pub fn register(&mut self, handler: Arc<dyn MessageHandler>) -> Result<(), String> {
+        let ids = handler.message_ids();
+        if let Some(existing) = self.handlers.iter().find(|existing| {
+            let other = existing.message_ids();
+            ids.start() <= other.end() && other.start() <= ids.end()
+        }) {
+            return Err(format!(
+                "Handler {} ids {:?} overlap with handler {} ids {:?}",
+                handler.name(),
+                ids,
+                existing.name(),
+                existing.message_ids()
+            ));
+        }
+        if self.handlers.iter().any(|existing| existing.name() == handler.name()) {
+            return Err(format!("Handler {} is already registered", handler.name()));
+        }
+        self.handlers.push(handler);
+        Ok(())
+    }
+
+    pub fn find(&self, id: f64) -> Option<&Arc<dyn MessageHandler>> {
+        self.handlers
+            .iter()
+            .find(|handler| handler.message_ids().contains(&id))
+    }
+
+    /* Registered handlers ordered by message id, so clients can discover the supported ids */
+    pub fn handlers(&self) -> Vec<HandlerInfo> {
+        let mut handlers: Vec<HandlerInfo> = self
+            .handlers
+            .iter()
+            .map(|handler| {
+                let ids = handler.message_ids();
+                HandlerInfo {
+                    name: handler.name().to_string(),
+                    description: handler.description().to_string(),
+                    min_id: *ids.start(),
+                    max_id: *ids.end(),
+                }
+            })
+            .collect();
+        handlers.sort_by(|a, b| a.min_id.total_cmp(&b.min_id));
+        handlers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo {
+        name: &'static str,
+        ids: RangeInclusive<f64>,
+    }
+
+    #[async_trait]
+    impl MessageHandler for Echo {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn description(&self) -> &'static str {
+            "Returns the message unchanged"
+        }
+
+        fn message_ids(&self) -> RangeInclusive<f64> {
+            self.ids.clone()
+        }
+
+        fn validate(&self, message: &Message) -> Result<(), String> {
+            if message.p_i.args.is_empty() {
+                return Err("args are required".to_string());
+            }
+            Ok(())
+        }
+
+        async fn handle(
+            &self,
+            message: Message,
+            _progress: Progress,
+        ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+            Ok(message)
+        }
+    }
+
+    fn echo(name: &'static str, ids: RangeInclusive<f64>) -> Arc<dyn MessageHandler> {
+        Arc::new(Echo { name, ids })
+    }
+
+    #[tokio::test]
+    async fn test_find_dispatches_by_message_id() {
+        let mut registry = HandlerRegistry::new();
+        registry.register(echo("first", 1.0..=1.009)).unwrap();
+        registry.register(echo("second", 2.0..=2.009)).unwrap();
+
+        assert_eq!(registry.find(1.005).map(|h| h.name()), Some("first"));
+        assert_eq!(registry.find(2.0).map(|h| h.name()), Some("second"));
+        assert!(registry.find(1.5).is_none());
+
+        let message = Message {
+            id: 2.0,
+            key: "key".to_string(),
+            ..Default::default()
+        };
+        let handler = registry.find(message.id).unwrap();
+        assert_eq!(
+            handler.validate(&message),
+            Err("args are required".to_string())
+        );
+        assert_eq!(handler.handle(message, Progress::disabled()).await.unwrap().key, "key");
+    }
+
+    #[test]
+    fn test_register_rejects_overlapping_ids() {
+        let mut registry = HandlerRegistry::new();
+        registry.register(echo("first", 1.0..=1.009)).unwrap();
+        assert!(registry.register(echo("second", 1.005..=1.5)).is_err());
+        assert!(registry.register(echo("first", 5.0..=5.009)).is_err());
+    }
+
+    #[test]
+    fn test_handlers_lists_supported_ids() {
+        let mut registry = HandlerRegistry::new();
+        registry.register(echo("second", 2.0..=2.009)).unwrap();
+        registry.register(echo("first", 1.0..=1.009)).unwrap();
+        let handlers = registry.handlers();
+        assert_eq!(
+            handlers.iter().map(|h| h.name.as_str()).collect::<Vec<_>>(),
+            vec!["first", "second"]
+        );
+        assert_eq!((handlers[1].min_id, handlers[1].max_id), (2.0, 2.009));
+    }
+}
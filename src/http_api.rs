@@ -0,0 +1,511 @@
+use crate::{
+    config::S3Config,
+    models::Message,
+    object_store::guess_content_type,
+    pipeline::{JobResult, Pipeline, SubmitError},
+    s3_service::S3Module,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use log::{error, info, warn};
+use serde_derive::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// Window of GET /failures without window_secs
+const DEFAULT_FAILURE_WINDOW_SECS: u64 = 60 * 60;
+// Jobs returned by GET /jobs without limit, and at most
+const DEFAULT_JOBS_LIMIT: usize = 50;
+const MAX_JOBS_LIMIT: usize = 1000;
+
+/* HTTP ingress next to the NSQ consumer:
+POST /messages queues a Message and answers with its key,
+GET /results/{key} returns the cached Message or its messages_wip status,
+GET /results/{key}/attempts lists its processing attempts from job_attempts,
+GET /failures?window_secs= counts failed attempts by handler,
+GET /jobs?limit= lists the most recently updated jobs from messages_wip,
+GET /handlers lists the supported message ids,
+GET /health reports whether results and dedupe are available,
+POST /uploads presigns a PUT the client uploads an input file with, only with [config.http] uploads_enabled.
+With a token every route but /health needs an `Authorization: Bearer <token>` header. */
+pub fn routes(pipeline: Arc<Pipeline>, uploads: Option<Uploads>, token: Option<String>) -> Router {
+    let mut api = Router::new()
+        .route("/messages", post(submit_message))
+        .route("/results/:key", get(get_result))
+        .route("/results/:key/attempts", get(get_attempts))
+        .route("/failures", get(get_failures))
+        .route("/jobs", get(list_jobs))
+        .route("/handlers", get(list_handlers))
+        .with_state(pipeline.clone());
+    if let Some(uploads) = uploads {
+        api = api.merge(
+            Router::new()
+                .route("/uploads", post(presign_upload))
+                .with_state(uploads),
+        );
+    }
+    if let Some(token) = token {
+        api = api.route_layer(middleware::from_fn_with_state(Arc::new(token), require_token));
+    }
+    // Left open for load balancer and container health checks
+    api.merge(Router::new().route("/health", get(health)).with_state(pipeline))
+}
+
+/* Object store and [config.s3] behind POST /uploads */
+#[derive(Clone)]
+pub struct Uploads {
+    pub s3_module: Arc<S3Module>,
+    pub config: S3Config,
+}
+
+/* Serves until the shutdown receiver flips to true */
+pub async fn serve(
+    pipeline: Arc<Pipeline>,
+    uploads: Option<Uploads>,
+    token: Option<String>,
+    bind_address: &str,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let address: SocketAddr = bind_address.parse()?;
+    if token.is_none() && !address.ip().is_loopback() {
+        warn!(
+            "HTTP API on {} has no HTTP_API_TOKEN, anyone who can reach it can submit and read jobs",
+            address
+        );
+    }
+    info!("HTTP API listening on {}", address);
+    axum::Server::try_bind(&address)?
+        .serve(routes(pipeline, uploads, token).into_make_service())
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        })
+        .await?;
+    Ok(())
+}
+
+/* Rejects requests without the configured bearer token with 401 */
+async fn require_token<B>(
+    State(token): State<Arc<String>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => error_response(StatusCode::UNAUTHORIZED, &"Missing or invalid bearer token"),
+    }
+}
+
+// Compares without returning at the first differing byte, so timing does not leak the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn submit_message(
+    State(pipeline): State<Arc<Pipeline>>,
+    Json(message): Json<Message>,
+) -> Response {
+    match pipeline.submit(&message).await {
+        Ok(key) => (StatusCode::ACCEPTED, Json(json!({ "key": key }))).into_response(),
+        Err(err @ SubmitError::Invalid(_)) => error_response(StatusCode::BAD_REQUEST, &err),
+        Err(err @ SubmitError::Unavailable(_)) => {
+            error!("{}", err);
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &err)
+        }
+    }
+}
+
+async fn get_result(State(pipeline): State<Arc<Pipeline>>, Path(key): Path<String>) -> Response {
+    match pipeline.lookup(&key).await {
+        Ok(JobResult::Done(message)) => {
+            Json(json!({ "key": key, "status": "DONE", "message": message })).into_response()
+        }
+        Ok(JobResult::Pending(status)) => {
+            Json(json!({ "key": key, "status": status })).into_response()
+        }
+        Ok(JobResult::Unknown) => {
+            error_response(StatusCode::NOT_FOUND, &format!("Unknown key {}", key))
+        }
+        Err(err) => {
+            error!("Unable to look up key {}: {}", key, err);
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &err)
+        }
+    }
+}
+
+async fn get_attempts(State(pipeline): State<Arc<Pipeline>>, Path(key): Path<String>) -> Response {
+    match pipeline.attempts(&key).await {
+        Ok(attempts) => Json(json!({ "key": key, "attempts": attempts })).into_response(),
+        Err(err) => {
+            error!("Unable to look up attempts of key {}: {}", key, err);
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &err)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FailuresQuery {
+    window_secs: Option<u64>,
+}
+
+async fn get_failures(
+    State(pipeline): State<Arc<Pipeline>>,
+    Query(query): Query<FailuresQuery>,
+) -> Response {
+    let window_secs = query.window_secs.unwrap_or(DEFAULT_FAILURE_WINDOW_SECS);
+    match pipeline.failure_counts(Duration::from_secs(window_secs)).await {
+        Ok(failures) => {
+            Json(json!({ "window_secs": window_secs, "failures": failures })).into_response()
+        }
+        Err(err) => {
+            error!("Unable to count failures: {}", err);
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &err)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JobsQuery {
+    limit: Option<usize>,
+}
+
+async fn list_jobs(
+    State(pipeline): State<Arc<Pipeline>>,
+    Query(query): Query<JobsQuery>,
+) -> Response {
+    let limit = query.limit.unwrap_or(DEFAULT_JOBS_LIMIT).min(MAX_JOBS_LIMIT);
+    match pipeline.recent_jobs(limit).await {
+        Ok(jobs) => Json(json!({ "jobs": jobs })).into_response(),
+        Err(err) => {
+            error!("Unable to list jobs: {}", err);
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &err)
+        }
+    }
+}
+
+async fn list_handlers(State(pipeline): State<Arc<Pipeline>>) -> Response {
+    Json(pipeline.handlers()).into_response()
+}
+
+/* 200 while degraded too: messages are still processed, only without cache and dedupe */
+async fn health(State(pipeline): State<Arc<Pipeline>>) -> Response {
+    let store = pipeline.store_health();
+    let status = if store.degraded { "degraded" } else { "ok" };
+    Json(json!({ "status": status, "store": store })).into_response()
+}
+
+#[derive(Deserialize)]
+struct UploadRequest {
+    file_name: String,
+    // Guessed from the file name when missing
+    content_type: Option<String>,
+}
+
+async fn presign_upload(
+    State(uploads): State<Uploads>,
+    Json(request): Json<UploadRequest>,
+) -> Response {
+    let file_name = request.file_name;
+    if file_name.is_empty() || file_name == "." || file_name == ".." || file_name.contains('/') {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("Invalid file_name {:?}", file_name),
+        );
+    }
+    let content_type = request.content_type.unwrap_or_else(|| {
+        guess_content_type(&file_name)
+            .unwrap_or("application/octet-stream")
+            .to_string()
+    });
+    match uploads
+        .s3_module
+        .upload_url(&uploads.config, &file_name, &content_type)
+        .await
+    {
+        Ok((key, url)) => Json(json!({
+            "bucket": uploads.config.data_bucket,
+            "key": key,
+            "method": "PUT",
+            "content_type": content_type,
+            "url": url,
+        }))
+        .into_response(),
+        Err(err) => {
+            error!("Unable to presign upload of {}: {}", file_name, err);
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &err)
+        }
+    }
+}
+
+fn error_response(status: StatusCode, err: &dyn std::fmt::Display) -> Response {
+    (status, Json(json!({ "error": err.to_string() }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler_registry::{HandlerRegistry, MessageHandler};
+    use crate::message_bus::MemoryBus;
+    use crate::message_store::{MemoryStore, MessageStore, IN_PROGRESS};
+    use crate::object_store::LocalObjectStore;
+    use crate::pipeline::{test_pipeline, TEST_TOPIC};
+    use crate::progress::Progress;
+    use async_trait::async_trait;
+    use axum::body::Body;
+    use axum::http::Request;
+    use std::error::Error;
+    use std::ops::RangeInclusive;
+    use tower::ServiceExt;
+
+    struct Echo;
+
+    #[async_trait]
+    impl MessageHandler for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn description(&self) -> &'static str {
+            "Returns the message unchanged"
+        }
+
+        fn message_ids(&self) -> RangeInclusive<f64> {
+            9.0..=9.009
+        }
+
+        async fn handle(
+            &self,
+            message: Message,
+            _progress: Progress,
+        ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+            Ok(message)
+        }
+    }
+
+    async fn app(bus: Arc<MemoryBus>) -> Router {
+        app_with_store(bus, Arc::new(MemoryStore::new()), None).await
+    }
+
+    async fn app_with_store(
+        bus: Arc<MemoryBus>,
+        store: Arc<MemoryStore>,
+        token: Option<String>,
+    ) -> Router {
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Echo)).unwrap();
+        // Presigning on the local store never touches the directory
+        let uploads = Uploads {
+            s3_module: Arc::new(S3Module::new(Arc::new(LocalObjectStore::new(
+                std::env::temp_dir(),
+            )))),
+            config: S3Config::default(),
+        };
+        routes(Arc::new(test_pipeline(registry, bus, store)), Some(uploads), token)
+    }
+
+    fn post_message(message: &Message) -> Request<Body> {
+        Request::post("/messages")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(message).unwrap()))
+            .unwrap()
+    }
+
+    async fn json_body(response: Response) -> serde_json::Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_post_message_queues_it_and_returns_key() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let message = Message {
+            id: 9.0,
+            cache_message: true,
+            ..Default::default()
+        };
+        let response = app(bus.clone())
+            .await
+            .oneshot(post_message(&message))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let key = json_body(response).await["key"].as_str().unwrap().to_string();
+        assert!(key.starts_with("v2-"));
+        let queued: Message = serde_json::from_slice(&bus.published(TEST_TOPIC)[0]).unwrap();
+        assert_eq!(queued.id, 9.0);
+    }
+
+    #[tokio::test]
+    async fn test_post_unknown_message_id_is_rejected() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let message = Message {
+            id: 4.0,
+            ..Default::default()
+        };
+        let response = app(bus.clone())
+            .await
+            .oneshot(post_message(&message))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(response).await["error"],
+            "Invalid message: Message ID not implemented"
+        );
+        assert!(bus.published(TEST_TOPIC).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_handlers_lists_message_ids() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let response = app(bus)
+            .await
+            .oneshot(Request::get("/handlers").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let handlers = json_body(response).await;
+        assert_eq!(handlers[0]["name"], "echo");
+        assert_eq!(handlers[0]["min_id"], 9.0);
+    }
+
+    #[tokio::test]
+    async fn test_get_failures_defaults_to_last_hour() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let response = app(bus)
+            .await
+            .oneshot(Request::get("/failures").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["window_secs"], 3600);
+        assert_eq!(body["failures"], json!({}));
+    }
+
+    #[tokio::test]
+    async fn test_get_health_reports_store() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let response = app(bus)
+            .await
+            .oneshot(Request::get("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["store"]["degraded"], false);
+    }
+
+    #[tokio::test]
+    async fn test_post_upload_presigns_a_put_under_upload_prefix() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let upload = |body: serde_json::Value| {
+            Request::post("/uploads")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = app(bus.clone())
+            .await
+            .oneshot(upload(json!({ "file_name": "portfolio.csv" })))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["bucket"], "sravz-data");
+        assert_eq!(body["method"], "PUT");
+        assert_eq!(body["content_type"], "text/csv");
+        let key = body["key"].as_str().unwrap();
+        assert!(key.starts_with("uploads/") && key.ends_with("/portfolio.csv"));
+        assert!(body["url"].as_str().unwrap().ends_with(key));
+
+        let response = app(bus.clone())
+            .await
+            .oneshot(upload(json!({ "file_name": "../portfolio.csv" })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Not served unless [config.http] uploads_enabled
+        let pipeline = test_pipeline(HandlerRegistry::new(), bus, Arc::new(MemoryStore::new()));
+        let response = routes(Arc::new(pipeline), None, None)
+            .oneshot(upload(json!({ "file_name": "portfolio.csv" })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_lists_recent_statuses() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let store = Arc::new(MemoryStore::new());
+        for key in ["v2-a", "v2-b"] {
+            store
+                .claim(key, "host:1", "{}", Duration::from_secs(60))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let response = app_with_store(bus, store, None)
+            .await
+            .oneshot(Request::get("/jobs?limit=1").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let jobs = json_body(response).await["jobs"].clone();
+        assert_eq!(jobs.as_array().unwrap().len(), 1);
+        assert_eq!((jobs[0]["key"].as_str(), jobs[0]["status"].as_str()), (Some("v2-b"), Some(IN_PROGRESS)));
+        assert!(jobs[0].get("msg").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_token_is_required_except_for_health() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let store = Arc::new(MemoryStore::new());
+        let app = app_with_store(bus, store, Some("secret".to_string())).await;
+        let jobs = |authorization: Option<&str>| {
+            let request = Request::get("/jobs");
+            match authorization {
+                Some(authorization) => request.header("authorization", authorization),
+                None => request,
+            }
+            .body(Body::empty())
+            .unwrap()
+        };
+
+        let response = app.clone().oneshot(jobs(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(jobs(Some("Bearer wrong"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(jobs(Some("Bearer secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(Request::get("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
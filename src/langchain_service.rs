@@ -1,23 +1,30 @@
+use crate::cache_key::ArgRules;
 use crate::config::AppConfig;
+use crate::handler_registry::{HandlerCachePolicy, MessageHandler};
+use crate::progress::Progress;
 use crate::models::Message;
-use crate::py03_service::{run_py_module, PyMessage};
-use crate::s3_service::S3Module;
+use crate::py03_service::{spawn_py_module, PyMessage};
 use log::error;
 use std::error::Error;
 use std::io;
+use std::sync::Arc;
 
-pub struct LangChain<'a> {
-    s3_module: &'a S3Module,
-    config: &'a AppConfig,
+pub struct LangChain {
+    config: Arc<AppConfig>,
 }
 
-impl<'a> LangChain<'a> {
-    pub fn new(config: &'a AppConfig, s3_module: &'a S3Module) -> Self {
-        LangChain { s3_module, config }
+impl LangChain {
+    pub fn new(config: Arc<AppConfig>) -> Self {
+        LangChain { config }
     }
 
-    pub async fn query(&mut self, mut message: Message) -> Result<Message, Box<dyn Error>> {
-        match run_py_module(PyMessage::new(
+    pub async fn query(
+        &self,
+        mut message: Message,
+        progress: &Progress,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        progress.report("llm_query", 10);
+        match spawn_py_module(PyMessage::new(
             message.id.to_string(),
             message.key.to_string(),
             message.p_i.args.clone().join(","),
@@ -25,20 +32,23 @@ impl<'a> LangChain<'a> {
             "".to_string(),
             Some(message.p_i.kwargs.json_keys.clone().unwrap_or_default().join(",")),
             Some(message.p_i.kwargs.llm_query.clone().unwrap_or_default()),
-        )) {
+        ), progress.clone())
+        .await
+        {
             Ok(py_message) => {
                 log::info!("Python code executed successfully");
                 message.d_o = Some(crate::models::DO {
-                    bucket_name: self.config.contabo_bucket.clone(),
+                    bucket_name: self.config.config.s3.output_bucket.clone(),
                     key_name: format!("{}.png", message.key),
                     signed_url:  py_message.output.into(),
                     data: serde_json::Value::String(String::new()),
+                    data_ref: None,
                 });
             }
             Err(err) => {
                 error!("Error executing Python code: {:?}", err);
                 return Err(Box::new(io::Error::new(
-                    io::ErrorKind::Other,
+                    err.kind(),
                     format!("Service error: {:?}", err),
                 )));
             }
@@ -47,29 +57,62 @@ impl<'a> LangChain<'a> {
     }
 }
 
+#[async_trait::async_trait]
+impl MessageHandler for LangChain {
+    fn name(&self) -> &'static str {
+        "langchain"
+    }
+
+    fn description(&self) -> &'static str {
+        "LLM query over the documents in args, the question is the llm_query kwarg"
+    }
+
+    fn message_ids(&self) -> std::ops::RangeInclusive<f64> {
+        2.0..=2.009
+    }
+
+    fn validate(&self, message: &Message) -> Result<(), String> {
+        if message.p_i.args.is_empty() {
+            return Err("LangChain requires at least one document in args".to_string());
+        }
+        Ok(())
+    }
+
+    fn cache_policy(&self) -> HandlerCachePolicy {
+        HandlerCachePolicy {
+            arg_rules: ArgRules {
+                order_insensitive: true,
+                case_insensitive: true,
+                case_insensitive_kwargs: false,
+            },
+            // LLM answers are not reused unless the config says so
+            default_ttl_secs: Some(0),
+        }
+    }
+
+    async fn handle(
+        &self,
+        message: Message,
+        progress: Progress,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        self.query(message, &progress).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        config::AppConfig,
         langchain_service::LangChain,
         models::{Kwargs, Message},
-        s3_service::S3Module,
+        s3_service::configured_app_config,
     };
     use chrono::Utc;
     use log::{error, info};
 
     #[tokio::test]
     async fn test_agent_supervisor() {
-        let s3_module = S3Module::new();
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut lang_chain = LangChain::new(&config, &s3_module);
+        let config = configured_app_config();
+        let lang_chain = LangChain::new(config);
         let keys = vec!["Yield_1Year_YTD".to_string(), "Yield_3Year_YTD".to_string(), "Yield_5Year_YTD".to_string()];
         let llm_query = format!(
             "Check if yield has been deceasing or increasing over time and store in value Yield_Direction.\n\
@@ -91,6 +134,7 @@ mod tests {
                         upload_to_aws: true,
                         json_keys: Some(keys),
                         llm_query: Some(llm_query), // <-- wrapped in Some()
+                        force_refresh: None,
                     },
                 },
                 t_o: String::new(),
@@ -103,13 +147,16 @@ mod tests {
                 e: String::new(),
                 key: "1".to_string(),
                 exception_message: String::new(),
+                attempts: 0,
+                retry_errors: Vec::new(),
                 d_o: Some(crate::models::DO {
                     bucket_name: "Fake".to_string(),
                     key_name: "Fake".to_string(),
                     data: serde_json::Value::String("Fake".to_string()),
                     signed_url: "Fake".to_string(),
+                    data_ref: None,
                 }),
-            })
+            }, &crate::progress::Progress::disabled())
             .await;
 
         match lang_chain_result {
@@ -1,5 +1,8 @@
+use crate::cache_key::ArgRules;
 use crate::config::AppConfig;
-use crate::py03_service::run_py_module;
+use crate::handler_registry::{HandlerCachePolicy, MessageHandler};
+use crate::progress::Progress;
+use crate::py03_service::spawn_py_module;
 use crate::py03_service::PyMessage;
 use crate::s3_service::S3Module;
 use crate::{dataframe_service::DataFrameCache, models::Message};
@@ -7,17 +10,18 @@ use log::{error, info};
 use polars::prelude::*;
 use std::error::Error;
 use std::io;
+use std::sync::Arc;
 
-pub struct LeveragedFunds<'a> {
+pub struct LeveragedFunds {
     pub(crate) dataframe_cache: DataFrameCache,
-    pub(crate) s3_module: &'a S3Module,
-    pub(crate) config: &'a AppConfig,
+    pub(crate) s3_module: Arc<S3Module>,
+    pub(crate) config: Arc<AppConfig>,
 }
 
-impl<'a> LeveragedFunds<'a> {
-    pub fn new(config: &'a AppConfig, s3_module: &'a S3Module) -> Self {
+impl LeveragedFunds {
+    pub fn new(config: Arc<AppConfig>, s3_module: Arc<S3Module>) -> Self {
         // TODO: Check proper dependency injection
-        let dataframe_cache = DataFrameCache::new();
+        let dataframe_cache = DataFrameCache::new(config.clone(), s3_module.clone());
         LeveragedFunds {
             dataframe_cache,
             s3_module,
@@ -26,11 +30,13 @@ impl<'a> LeveragedFunds<'a> {
     }
 
     pub async fn leverage_funds(
-        &mut self,
+        &self,
         mut message: Message,
-    ) -> Result<Message, Box<dyn Error>> {
+        progress: &Progress,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
         let object_keys = message.p_i.args.clone();
         let mut dataframe_vector: Vec<DataFrame> = Vec::new();
+        progress.report("loading_prices", 5);
         for sravzid in object_keys {
             match self.dataframe_cache.get_dataframe(sravzid).await {
                 Ok(dataframe) => match dataframe {
@@ -45,6 +51,7 @@ impl<'a> LeveragedFunds<'a> {
             }
         }
 
+        progress.report("joining_prices", 20);
         // Join DataFrames in the vector on the "id" column
         let mut joined_df: Option<DataFrame> = None;
         for df in dataframe_vector {
@@ -94,7 +101,8 @@ impl<'a> LeveragedFunds<'a> {
             // println!("{:?}", joined);
 
             let json_keys = message.p_i.kwargs.json_keys.clone();
-            match run_py_module(PyMessage::new(
+            progress.report("analysis", 30);
+            match spawn_py_module(PyMessage::new(
                 message.id.to_string(),
                 message.key.to_string(),
                 "".to_string(),
@@ -102,26 +110,35 @@ impl<'a> LeveragedFunds<'a> {
                 "".to_string(),
                 Some(json_keys.unwrap_or_default().join(",")),
                 message.p_i.kwargs.llm_query.clone(),
-            )) {
+            ), progress.clone())
+            .await
+            {
                 Ok(_) => {
                     // println!("Python code executed successfully");
+                    progress.report("uploading", 90);
                     self.s3_module
                         .upload_file(
-                            "sravz",
-                            &format!("rust-backend/{}.png", message.key),
+                            &self.config.config.s3.output_bucket,
+                            &self.config.config.s3.output_key(&format!("{}.png", message.key)),
                             &format!("/tmp/data/{}.png", message.key),
                         )
                         .await?;
+                    let file_name = format!("{}.png", message.key);
+                    let signed_url = self
+                        .s3_module
+                        .output_url(&self.config.config.s3, &file_name, "image/png")
+                        .await?;
                     message.update_s3_location(
-                        self.config.contabo_bucket.clone(),
-                        self.config.contabo_object_url_prefix.clone(),
-                        format!("{}.png", message.key),
+                        self.config.config.s3.output_bucket.clone(),
+                        file_name,
+                        signed_url,
                     )
                 }
                 Err(err) => {
                     error!("Error executing Python code: {:?}", err);
+                    // Keeps InvalidInput of Python ValueErrors, which are not retried
                     return Err(Box::new(io::Error::new(
-                        io::ErrorKind::Other,
+                        err.kind(),
                         format!("Service error: {:?}", err),
                     )));
                 }
@@ -131,29 +148,63 @@ impl<'a> LeveragedFunds<'a> {
     }
 }
 
+#[async_trait::async_trait]
+impl MessageHandler for LeveragedFunds {
+    fn name(&self) -> &'static str {
+        "leveraged_funds"
+    }
+
+    fn description(&self) -> &'static str {
+        "Leveraged funds comparison chart, args are sravz ids"
+    }
+
+    fn message_ids(&self) -> std::ops::RangeInclusive<f64> {
+        1.0..=1.009
+    }
+
+    fn validate(&self, message: &Message) -> Result<(), String> {
+        if message.p_i.args.is_empty() {
+            return Err("Leveraged funds requires at least one sravz id in args".to_string());
+        }
+        Ok(())
+    }
+
+    fn cache_policy(&self) -> HandlerCachePolicy {
+        // Args are a set of sravz ids
+        HandlerCachePolicy {
+            arg_rules: ArgRules {
+                order_insensitive: true,
+                case_insensitive: true,
+                case_insensitive_kwargs: false,
+            },
+            default_ttl_secs: None,
+        }
+    }
+
+    async fn handle(
+        &self,
+        message: Message,
+        progress: Progress,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        self.leverage_funds(message, &progress).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        config::AppConfig,
         leveraged_funds_service::LeveragedFunds,
         models::{Kwargs, Message},
-        s3_service::S3Module,
+        s3_service::{configured_app_config, fixture_s3_module},
     };
     use chrono::Utc;
     use log::{error, info};
 
     #[tokio::test]
     async fn test_leverage_funds() {
-        let s3_module = S3Module::new();
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut leveraged_funds = LeveragedFunds::new(&config, &s3_module);
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let config = configured_app_config();
+        let leveraged_funds = LeveragedFunds::new(config, s3_module);
         let leveraged_fund_result = leveraged_funds
             .leverage_funds(Message {
                 id: 1.0,
@@ -168,6 +219,7 @@ mod tests {
                         upload_to_aws: true,
                         json_keys: None,
                         llm_query: Some(String::new()),
+                        force_refresh: None,
                     },
                 },
                 t_o: String::new(),
@@ -180,13 +232,16 @@ mod tests {
                 e: String::new(),
                 key: "1".to_string(),
                 exception_message: String::new(),
+                attempts: 0,
+                retry_errors: Vec::new(),
                 d_o: Some(crate::models::DO {
                     bucket_name: "Fake".to_string(),
                     key_name: "Fake".to_string(),
                     data: serde_json::Value::String("Fake".to_string()),
                     signed_url: "Fake".to_string(),
+                    data_ref: None,
                 }),
-            })
+            }, &crate::progress::Progress::disabled())
             .await;
 
         match leveraged_fund_result {
@@ -1,31 +1,59 @@
+mod cache_key;
+mod cache_policy;
 mod config;
 mod dataframe_service;
+mod handler_registry;
 mod helper;
+mod http_api;
 mod langchain_service;
 mod leveraged_funds_service;
+mod message_bus;
+mod message_store;
 mod models;
+mod object_cache;
+mod mongo_indexes;
 mod mongo_service;
+mod nsq_producer;
+mod object_store;
+mod payload_store;
+mod pipeline;
+mod progress;
 mod py03_service;
+mod resilient_store;
 mod rest_client;
+mod retry;
 mod router;
 mod s3_service;
-use crate::{config::AppConfig, helper::sha256_hash, models::Message, router::Router};
-use chrono::{Duration, Utc};
+mod worker_pool;
+use crate::{
+    config::{AppConfig, MessageBusKind, MessageStoreKind},
+    message_bus::{MemoryBus, MessageBus, NsqBus},
+    message_store::{MemoryStore, MessageStore},
+    mongo_indexes::expected_indexes,
+    resilient_store::ResilientStore,
+    nsq_producer::ProducerPool,
+    payload_store::{MemoryPayloadStore, PayloadStore},
+    pipeline::Pipeline,
+    router::Router,
+    worker_pool::WorkerPool,
+};
 use env_logger::Env;
-use langchain_service::LangChain;
-use leveraged_funds_service::LeveragedFunds;
-use log::{error, info};
+use log::{error, info, warn};
 use mongo_service::Mongo;
-use mongodb::Client;
-use rand::seq::SliceRandom;
+use mongodb::{options::ClientOptions, Client};
 use s3_service::S3Module;
 use std::collections::HashSet;
 use std::error::Error;
 use std::fs::{self};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tokio_nsq::{
     NSQChannel, NSQConsumerConfig, NSQConsumerConfigSources, NSQConsumerLookupConfig,
-    NSQProducerConfig, NSQTopic,
+    NSQTopic,
 };
 mod services {
     pub mod earnings;
@@ -56,15 +84,157 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    let bus: Arc<dyn MessageBus> = match config.config.message_bus {
+        MessageBusKind::Nsq => Arc::new(nsq_bus(&config)),
+        MessageBusKind::Memory => stdin_bus(&config),
+    };
+
+    /* Create router dependencies */
+    // TODO: Check proper dependency injection
+    let (store, payloads): (Arc<dyn MessageStore>, Arc<dyn PayloadStore>) = match config.config.message_store {
+        MessageStoreKind::Mongo => {
+            let mongo_config = &config.config.mongo;
+            let mut options = match ClientOptions::parse(&config.mongolab_uri).await {
+                Ok(options) => options,
+                Err(err) => {
+                    error!("Invalid MONGOLAB_URI: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            // Fail fast while MongoDB is down instead of the driver's 30s, see [config.store]
+            options.server_selection_timeout =
+                Some(Duration::from_millis(mongo_config.server_selection_timeout_ms));
+            let client = match Client::with_options(options) {
+                Ok(client) => client,
+                Err(err) => {
+                    error!("Unable to create MongoDB client: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            let mongo = Mongo::new(client, &mongo_config.database);
+            let expected = expected_indexes(
+                mongo_config.cache_retention(&config.config.cache),
+                mongo_config.wip_retention(),
+            );
+            // Claims are only exclusive with the unique messages_wip.key_1 index, never run without it
+            match mongo.bootstrap_indexes(&expected).await {
+                Ok(drift) => {
+                    for drift in &drift {
+                        warn!("MongoDB index drift: {}", drift);
+                    }
+                    if let Some(drift) = drift.iter().find(|drift| drift.breaks_claims()) {
+                        error!("Claims would not be exclusive, fix the index and restart: {}", drift);
+                        std::process::exit(1);
+                    }
+                }
+                Err(err) => {
+                    error!("Unable to bootstrap MongoDB indexes, claims would not be exclusive: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            let mongo = Arc::new(mongo);
+            (
+                Arc::new(ResilientStore::new(mongo.clone(), config.config.store.clone())),
+                mongo,
+            )
+        }
+        MessageStoreKind::Memory => (
+            Arc::new(MemoryStore::new()),
+            Arc::new(MemoryPayloadStore::new()),
+        ),
+    };
+    let config = Arc::new(config);
+    let s3_module = match S3Module::from_config(&config) {
+        Ok(s3_module) => Arc::new(s3_module),
+        Err(err) => {
+            error!("Unable to create the {:?} object store: {}", config.config.object_store, err);
+            std::process::exit(1);
+        }
+    };
+
+    let uploads = config.config.http.uploads_enabled.then(|| http_api::Uploads {
+        s3_module: s3_module.clone(),
+        config: config.config.s3.clone(),
+    });
+    let router = match Router::with_default_handlers(config.clone(), s3_module) {
+        Ok(router) => router,
+        Err(err) => {
+            error!("Unable to register message handlers: {}", err);
+            std::process::exit(1);
+        }
+    };
+    for handler in router.handlers() {
+        info!(
+            "Handler {} serves message ids {}..={}: {}",
+            handler.name, handler.min_id, handler.max_id, handler.description
+        );
+    }
+    let pipeline = Arc::new(Pipeline::new(
+        config.clone(),
+        router,
+        store,
+        payloads,
+        bus.clone(),
+    ));
+    let mut worker_pool = WorkerPool::new(config.config.worker_pool_size);
+    let mut shutdown = shutdown_listener();
+    {
+        let pipeline = pipeline.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move { pipeline.run_sweeper(shutdown).await });
+    }
+    if config.config.http.enabled {
+        let pipeline = pipeline.clone();
+        let bind_address = config.config.http.bind_address.clone();
+        // Bearer token every route but /health requires, unset leaves the API open
+        let token = std::env::var("HTTP_API_TOKEN").ok().filter(|token| !token.is_empty());
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(err) = http_api::serve(pipeline, uploads, token, &bind_address, shutdown).await {
+                error!("HTTP API stopped: {}", err);
+            }
+        });
+    }
+    loop {
+        let delivery = tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => break,
+            delivery = bus.consume() => match delivery {
+                Some(delivery) => delivery,
+                None => {
+                    info!("Message bus closed");
+                    break;
+                }
+            }
+        };
+        let pipeline = pipeline.clone();
+        // Waits here while all workers are busy. On shutdown the unstarted message is
+        // dropped, which requeues it on nsqd for another replica.
+        tokio::select! {
+            _ = worker_pool.spawn(async move { pipeline.process_message(delivery).await }) => {}
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        }
+    }
+
+    info!("Shutting down, no longer consuming NSQ messages");
+    let grace_period = Duration::from_secs(config.config.shutdown_grace_period_secs);
+    if !worker_pool.shutdown(grace_period).await {
+        pipeline.abort_in_flight().await;
+        // Aborted Python jobs keep running on blocking threads, which the runtime would wait for on drop
+        info!("Shutdown complete");
+        std::process::exit(0);
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/* Consumes backend_rust_topic from the nsqd hosts known to nsqlookupd and publishes
+through a producer pool over NSQ_HOST */
+fn nsq_bus(config: &AppConfig) -> NsqBus {
     let consumer_topic = NSQTopic::new(config.config.backend_rust_topic.clone())
         .expect("Failed to create consumer topic");
     let channel = NSQChannel::new(config.config.backend_rust_topic.clone())
         .expect("Failed to create NSQ channel");
 
-    let client = Client::with_uri_str(config.mongolab_uri.clone())
-        .await
-        .expect("Unable to connect to MongoDB");
-
     let mut addresses = HashSet::new();
     let nsqlookupd_addresses: Vec<String> = config
         .nsq_lookupd_host
@@ -83,212 +253,53 @@ async fn main() -> Result<(), Box<dyn Error>> {
         &config.config.backend_rust_topic, &config.config.backend_rust_topic
     );
 
-    let mut consumer = NSQConsumerConfig::new(consumer_topic, channel)
-        .set_max_in_flight(15)
+    let consumer = NSQConsumerConfig::new(consumer_topic, channel)
+        // Only take as many messages as there are workers to process them
+        .set_max_in_flight(config.config.worker_pool_size.max(1) as u32)
         .set_sources(NSQConsumerConfigSources::Lookup(
             NSQConsumerLookupConfig::new().set_addresses(addresses),
         ))
         .build();
 
-    let mut rng: rand::prelude::ThreadRng = rand::thread_rng();
     let nsqd_hosts: Vec<String> = config.nsq_host.split(',').map(String::from).collect(); // vec!["nsqd-1:4150", "nsqd-2:4150", "nsqd-3:4150"];
-    let mut nsqd_host = "";
-    if let Some(random_element) = nsqd_hosts.choose(&mut rng) {
-        nsqd_host = random_element;
-        info!("Connecting to nsqd host {}", nsqd_host)
-    } else {
-        println!("nsqd_hosts vector empty");
-    }
-
-    let mut producer = NSQProducerConfig::new(nsqd_host).build();
-
-    /* Create router dependencies */
-    // TODO: Check proper dependency injection
-    let mongo = Mongo {};
-    let s3_module = S3Module::new();
-    let mut leveraged_funds = LeveragedFunds::new(&config, &s3_module);
-    let mut langchain: LangChain = LangChain::new(&config, &s3_module);
-
-    let mut router = Router::new(mongo, &mut leveraged_funds, &mut langchain);
-    loop {
-        let message = consumer
-            .consume_filtered()
-            .await
-            .expect("Failed to consume NSQ message");
-        info!("Processing the message...");
-
-        let message_body = message.body.clone();     
-        let message_body_str =
-            std::str::from_utf8(&message_body).expect("Failed to get JSON string from NSQ Message");
-        info!("Message received {}", message_body_str);
-
-        let result: Result<Message, serde_json::Error> = serde_json::from_str(message_body_str);
-
-        // Handle the result using pattern matching
-        match result {
-            Ok(mut _message) => {
-                // Empty out error message if the client send an error already
-                _message.e = "".to_string();
-                _message.exception_message = "".to_string();
-                _message.date = Utc::now();
-
-                // Sort kwargs for consistent hashing
-                let mut sorted_kwargs = serde_json::to_value(&_message.p_i.kwargs)
-                    .expect("Failed to serialize kwargs");
-                if let serde_json::Value::Object(ref mut map) = &mut sorted_kwargs {
-                    // Convert keys and string values to lowercase
-                    let lowercase_entries: Vec<(String, serde_json::Value)> = map
-                        .iter()
-                        .map(|(k, v)| {
-                            let lowercase_value = match v {
-                                serde_json::Value::String(s) => serde_json::Value::String(s.to_lowercase()),
-                                _ => v.clone(),
-                            };
-                            (k.to_lowercase(), lowercase_value)
-                        })
-                        .collect();
-                    
-                    // Clear and repopulate the map with lowercase entries
-                    map.clear();
-                    for (k, v) in lowercase_entries {
-                        map.insert(k, v);
-                    }
-                    
-                    // Create a sorted map
-                    let mut sorted: Vec<_> = map.iter().collect();
-                    sorted.sort_by(|a, b| a.0.cmp(b.0));
-                }
-
-                // Get hash of the message
-                let hashed_string = &sha256_hash(&format!(
-                    "{:?}{:?}{}{:?}",
-                    _message.p_i, _message.id, _message.fun_n, sorted_kwargs
-                ));
-
-                info!(
-                    "Message received on NSQ = {} - SHA-256 = {}",
-                    message_body_str, hashed_string
-                );
-
-                _message.key = hashed_string.to_string();
-
-                let messages = router
-                    .mongo
-                    .find_by_key(hashed_string.to_string(), &client)
-                    .await
-                    .expect("Document not found");
-
-                /* If the message exists and inserted less than 24 hours back - resend the same message else reprocess the message */
-                if messages.len() > 0 && messages[0].date + Duration::days(1) > Utc::now() {
-                    let mut message_from_router = messages[0].clone();
-                    message_from_router.cid = _message.cid.clone();
-                    let message_from_router_json = &serde_json::to_string(&message_from_router)
-                        .expect("Failed to convert NSQ message to JSON string");
-
-                    info!(
-                        "Sending the existing message in mongodb {}",
-                        message_from_router_json
-                    );
-                    info!("Routing skipped as the message was processed in last 24 hours");
-                    message.finish().await;
-                    info!("Sending the processed message on NSQ {}", message_from_router_json);
-                    let producer_topic =
-                        NSQTopic::new(_message.t_o).expect("Failed to create producer topic");
-                    info!("Producer topic {:?}", producer_topic);
-                    producer
-                        .publish(&producer_topic, message_from_router_json.as_bytes().to_vec())
-                        .await
-                        .expect("Failed to publish NSQ message");
-                    info!("Published to NSQ topic {:?}", producer_topic);
-                    producer
-                        .consume()
-                        .await
-                        .expect("Failed to consume NSQ message");                    
-                    info!("Consumed from NSQ topic {:?}", producer_topic);
-                    continue;
-                } else {
-                    // Clone the message so we have a reference in this routine
-                    let var_name = _message.clone();
-                    let mut original_message = var_name;
-                    original_message.key = hashed_string.to_string();
-
-                    // Set message in progress before processing
-                    // Check if message is already in progress
-                    let status = router.mongo.is_message_in_progress(
-                        &original_message.key,
-                        &client,
-                        None
-                    ).await;
-                    if status.as_deref() != Some("IN_PROGRESS") {
-                        router.mongo.set_message_in_progress(
-                            &original_message.key,
-                            &serde_json::to_string(&original_message).unwrap_or_default(),
-                            "",
-                            "IN_PROGRESS",
-                            &client,
-                            None
-                        ).await;
-
-                        // Pass the original message to the router
-                        info!("Routing the message...");
-                        // Acknowledge the message from NSQ
-                        message.finish().await;                           
-                        let router_result = router.process_message(_message).await;
-                        match router_result {
-                            Ok(processed_message) => {
-                                // Reassign processed message to the original message
-                                original_message = processed_message;
-                            }
-                            Err(err) => {
-                                // Update original message
-                                original_message.e = "Error".to_string();
-                                original_message.exception_message = err.to_string();
-                                error!("Router message processing error: {}", err);
-                            }
-                        }
-
-                        // Save the message to mongodb cache   
-                        info!("Saving the message to MongoDB");                     
-                        router
-                            .mongo
-                            .update_one(original_message.clone(), &client)
-                            .await;
-                        let message_body_str = &serde_json::to_string(&original_message)
-                            .expect("Failed to convert message to JSON string");
-                        info!("Sending the processed message on NSQ {}", message_body_str);                    
-
-                    } else {
-                        original_message.e = format!(
-                            "Given message key: {} - ({}) is being processed. Please check after sometime",
-                            &original_message.key,
-                            serde_json::to_string(&original_message).unwrap_or_default()
-                        );
-                        info!("Message with key {} is already in progress. Skipping processing.", &original_message.key);
-                    }       
+    let producer_pool = Arc::new(ProducerPool::new(&nsqd_hosts, &config.config.producer));
+    tokio::spawn(producer_pool.clone().run_health_checks(Duration::from_secs(
+        config.config.producer.health_check_interval_secs,
+    )));
+    NsqBus::new(consumer, producer_pool)
+}
 
-                    // Send the message to NSQ
-                    info!("Sending the message on NSQ {}", message_body_str);
-                    let producer_topic = NSQTopic::new(original_message.t_o.clone())
-                        .expect("Failed to create producer topic");
-                    info!("Producer topic {:?}", producer_topic);
-                    producer
-                        .publish(&producer_topic, message_body_str.as_bytes().to_vec())
-                        .await
-                        .expect("Failed to publish NSQ message");
-                    info!("Published to NSQ topic {:?}", producer_topic);
-                    producer
-                        .consume()
-                        .await
-                        .expect("Failed to consume NSQ message");                                 
-                    continue;                                 
-                }
-            }
-            Err(err) => {
-                error!("Deserialization failed: {}", err);
-                message.finish().await;
+/* In-memory bus fed with one JSON message per stdin line, e.g.
+`cat tests/message.json | cargo run` */
+fn stdin_bus(config: &AppConfig) -> Arc<MemoryBus> {
+    let bus = Arc::new(MemoryBus::new(&config.config.backend_rust_topic));
+    let topic = config.config.backend_rust_topic.clone();
+    let stdin_bus = bus.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if !line.trim().is_empty() {
+                let _ = stdin_bus.publish(&topic, line.as_bytes()).await;
             }
         }
-    }
+    });
+    info!(
+        "Using the in-memory message bus, reading messages for {} from stdin",
+        config.config.backend_rust_topic
+    );
+    bus
+}
 
-    // Ok(())
+/* Flips to true on the first SIGTERM or SIGINT */
+fn shutdown_listener() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = sigterm.recv() => info!("SIGTERM received"),
+            _ = tokio::signal::ctrl_c() => info!("SIGINT received"),
+        }
+        let _ = sender.send(true);
+    });
+    receiver
 }
@@ -0,0 +1,227 @@
+use crate::nsq_producer::{ProducerPool, PublishError};
+use async_trait::async_trait;
+use log::info;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio_nsq::{NSQConsumer, NSQMessage, NSQRequeueDelay};
+
+/* A message taken from the bus. It must be handed back with ack or requeue;
+an NSQ delivery that is dropped instead is requeued by nsqd with its default delay. */
+pub struct Delivery {
+    pub body: Vec<u8>,
+    // 1 on first delivery, incremented every time the message is requeued
+    pub attempt: u16,
+    source: DeliverySource,
+}
+
+enum DeliverySource {
+    Nsq(NSQMessage),
+    Memory,
+}
+
+/* Transport the pipeline consumes requests from and publishes replies to */
+#[async_trait]
+pub trait MessageBus: Send + Sync {
+    /* Next message on the consumer topic, None once the bus is closed */
+    async fn consume(&self) -> Option<Delivery>;
+
+    async fn ack(&self, delivery: Delivery);
+
+    /* Hands the message back for redelivery, after the transport's default delay when None */
+    async fn requeue(&self, delivery: Delivery, delay: Option<Duration>);
+
+    async fn publish(&self, topic: &str, body: &[u8]) -> Result<(), PublishError>;
+
+    async fn publish_deferred(
+        &self,
+        topic: &str,
+        body: &[u8],
+        delay: Duration,
+    ) -> Result<(), PublishError>;
+}
+
+/* nsqlookupd-discovered consumer plus the nsqd producer pool */
+pub struct NsqBus {
+    consumer: Mutex<NSQConsumer>,
+    producer_pool: Arc<ProducerPool>,
+}
+
+impl NsqBus {
+    pub fn new(consumer: NSQConsumer, producer_pool: Arc<ProducerPool>) -> Self {
+        NsqBus {
+            consumer: Mutex::new(consumer),
+            producer_pool,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBus for NsqBus {
+    async fn consume(&self) -> Option<Delivery> {
+        let message = self.consumer.lock().await.consume_filtered().await?;
+        Some(Delivery {
+            body: message.body.clone(),
+            attempt: message.attempt,
+            source: DeliverySource::Nsq(message),
+        })
+    }
+
+    async fn ack(&self, delivery: Delivery) {
+        if let DeliverySource::Nsq(message) = delivery.source {
+            message.finish().await;
+        }
+    }
+
+    async fn requeue(&self, delivery: Delivery, delay: Option<Duration>) {
+        if let DeliverySource::Nsq(message) = delivery.source {
+            let strategy = match delay {
+                Some(delay) => NSQRequeueDelay::CustomDelay(delay),
+                None => NSQRequeueDelay::DefaultDelay,
+            };
+            message.requeue(strategy).await;
+        }
+    }
+
+    async fn publish(&self, topic: &str, body: &[u8]) -> Result<(), PublishError> {
+        self.producer_pool.publish(topic, body).await
+    }
+
+    async fn publish_deferred(
+        &self,
+        topic: &str,
+        body: &[u8],
+        delay: Duration,
+    ) -> Result<(), PublishError> {
+        let delay_milliseconds = delay.as_millis().min(u32::MAX as u128) as u32;
+        self.producer_pool
+            .publish_deferred(topic, body, delay_milliseconds)
+            .await
+    }
+}
+
+/* In-process bus for tests and local development. Messages published on `topic`
+are consumed again, everything published is kept by topic for inspection. */
+pub struct MemoryBus {
+    topic: String,
+    sender: mpsc::UnboundedSender<(Vec<u8>, u16)>,
+    receiver: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, u16)>>,
+    published: std::sync::Mutex<HashMap<String, Vec<Vec<u8>>>>,
+}
+
+impl MemoryBus {
+    pub fn new(topic: &str) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        MemoryBus {
+            topic: topic.to_string(),
+            sender,
+            receiver: Mutex::new(receiver),
+            published: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn published(&self, topic: &str) -> Vec<Vec<u8>> {
+        self.published
+            .lock()
+            .unwrap()
+            .get(topic)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn deliver_after(&self, body: Vec<u8>, attempt: u16, delay: Duration) {
+        let sender = self.sender.clone();
+        if delay.is_zero() {
+            let _ = sender.send((body, attempt));
+            return;
+        }
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = sender.send((body, attempt));
+        });
+    }
+}
+
+#[async_trait]
+impl MessageBus for MemoryBus {
+    async fn consume(&self) -> Option<Delivery> {
+        let (body, attempt) = self.receiver.lock().await.recv().await?;
+        Some(Delivery {
+            body,
+            attempt,
+            source: DeliverySource::Memory,
+        })
+    }
+
+    async fn ack(&self, _delivery: Delivery) {}
+
+    async fn requeue(&self, delivery: Delivery, delay: Option<Duration>) {
+        // Same linear default as nsqd: one second per attempt
+        let delay = delay.unwrap_or(Duration::from_secs(delivery.attempt as u64));
+        self.deliver_after(delivery.body, delivery.attempt.saturating_add(1), delay);
+    }
+
+    async fn publish(&self, topic: &str, body: &[u8]) -> Result<(), PublishError> {
+        self.publish_deferred(topic, body, Duration::ZERO).await
+    }
+
+    async fn publish_deferred(
+        &self,
+        topic: &str,
+        body: &[u8],
+        delay: Duration,
+    ) -> Result<(), PublishError> {
+        info!(
+            "Published on in-memory topic {}: {}",
+            topic,
+            String::from_utf8_lossy(body)
+        );
+        self.published
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default()
+            .push(body.to_vec());
+        if topic == self.topic {
+            self.deliver_after(body.to_vec(), 1, delay);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_bus_delivers_own_topic_and_records_the_rest() {
+        let bus = MemoryBus::new("backend-rust");
+        bus.publish("backend-rust", b"request").await.unwrap();
+        bus.publish("client", b"reply").await.unwrap();
+
+        let delivery = bus.consume().await.unwrap();
+        assert_eq!(delivery.body, b"request");
+        assert_eq!(delivery.attempt, 1);
+        bus.ack(delivery).await;
+
+        assert_eq!(bus.published("client"), vec![b"reply".to_vec()]);
+        assert!(bus.published("unknown").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_bus_requeue_redelivers_with_next_attempt() {
+        let bus = MemoryBus::new("backend-rust");
+        bus.publish("backend-rust", b"request").await.unwrap();
+        let delivery = bus.consume().await.unwrap();
+        bus.requeue(delivery, Some(Duration::from_millis(10))).await;
+
+        let redelivery = tokio::time::timeout(Duration::from_secs(1), bus.consume())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(redelivery.body, b"request");
+        assert_eq!(redelivery.attempt, 2);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::models::Message;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const IN_PROGRESS: &str = "IN_PROGRESS";
+pub const DONE: &str = "DONE";
+pub const FAILED: &str = "FAILED";
+// exception_message of jobs marked FAILED because their owner stopped heartbeating
+pub const LEASE_EXPIRED: &str = "Processing abandoned: the worker stopped renewing its lease";
+
+/* A job's record in messages_wip */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WipStatus {
+    pub key: String,
+    // IN_PROGRESS, DONE, FAILED, RETRY_SCHEDULED, ABORTED
+    pub status: String,
+    // Worker that claimed the job as hostname:pid, empty for records written before claims existed
+    pub owner: String,
+    // The claim lapses unless the owner heartbeats before this, None for records written before leases existed
+    pub lease_expires: Option<DateTime<Utc>>,
+    // The message as JSON when the status was set, left out of GET /jobs
+    #[serde(skip_serializing)]
+    pub msg: String,
+    pub exception_message: String,
+    pub date: DateTime<Utc>,
+}
+
+impl WipStatus {
+    /* IN_PROGRESS under a lease that has not expired, i.e. its owner is still working on it */
+    pub fn is_live(&self, now: DateTime<Utc>) -> bool {
+        self.status == IN_PROGRESS && self.lease_expires.is_some_and(|expires| expires > now)
+    }
+}
+
+/* End of a lease of length `lease` starting at `now` */
+pub fn lease_expiry(now: DateTime<Utc>, lease: Duration) -> DateTime<Utc> {
+    now + chrono::Duration::from_std(lease).unwrap_or(chrono::Duration::zero())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AttemptOutcome {
+    Succeeded,
+    // Also attempts that were retried afterwards
+    Failed,
+}
+
+impl AttemptOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttemptOutcome::Succeeded => "SUCCEEDED",
+            AttemptOutcome::Failed => "FAILED",
+        }
+    }
+}
+
+/* One processing attempt of a job, appended to job_attempts and never updated */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobAttempt {
+    pub key: String,
+    pub cid: String,
+    pub message_id: f64,
+    pub handler: String,
+    pub host: String,
+    // 1 for the first attempt, incremented by every retry
+    pub attempt: u32,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub outcome: AttemptOutcome,
+    pub error: String,
+    // URLs of the files the attempt produced
+    pub artifacts: Vec<String>,
+}
+
+/* Why a MessageStore operation failed */
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreError {
+    // The store could not be reached or timed out, worth retrying
+    Unavailable(String),
+    // The store answered but refused the operation
+    Rejected(String),
+    // A stored document could not be read or written in the expected shape
+    Corrupt(String),
+}
+
+impl StoreError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, StoreError::Unavailable(_))
+    }
+}
+
+impl Error for StoreError {}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Unavailable(reason) => write!(f, "Store unavailable: {}", reason),
+            StoreError::Rejected(reason) => write!(f, "Store rejected the operation: {}", reason),
+            StoreError::Corrupt(reason) => write!(f, "Store data corrupt: {}", reason),
+        }
+    }
+}
+
+/* Whether the store is reachable, see ResilientStore */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StoreHealth {
+    pub degraded: bool,
+    pub degraded_since: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+/* Outcome of MessageStore::claim */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Claim {
+    // The caller owns the job and must process it
+    Acquired,
+    // Another worker is processing the job, by owner
+    Owned(String),
+}
+
+/* Results in nsq_message_cache and job status in messages_wip */
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    /* Stores without failure tracking are always reported healthy */
+    fn health(&self) -> StoreHealth {
+        StoreHealth::default()
+    }
+
+    /* Cached result for the key */
+    async fn find_by_key(&self, key: &str) -> Result<Option<Message>, StoreError>;
+
+    /* Inserts or replaces the cached result with the message's key */
+    async fn upsert(&self, message: &Message) -> Result<(), StoreError>;
+
+    /* Final status of a job claimed by `owner`. False, and nothing written, when another worker
+    claimed the key since, so a worker that lost its lease can not overwrite the new claim. */
+    async fn release(
+        &self,
+        key: &str,
+        owner: &str,
+        msg: &str,
+        exception_message: &str,
+        status: &str,
+    ) -> Result<bool, StoreError>;
+
+    /* Marks the key IN_PROGRESS for `owner` with a lease of `lease`, unless another worker holds a live lease on it.
+    Check and update are a single operation, so only one of several racing workers acquires the job.
+    Owners are unique per job, so the same owner only claims again when a claim whose reply was
+    lost is retried, which succeeds while that claim is still in progress. */
+    async fn claim(
+        &self,
+        key: &str,
+        owner: &str,
+        msg: &str,
+        lease: Duration,
+    ) -> Result<Claim, StoreError>;
+
+    /* Extends the lease of `owner` on the key. False when the job is no longer in progress for that owner. */
+    async fn heartbeat(
+        &self,
+        key: &str,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<bool, StoreError>;
+
+    /* Marks IN_PROGRESS jobs whose lease expired as FAILED with `exception_message`.
+    Returns the jobs as they were before, at most `limit`. */
+    async fn fail_expired(
+        &self,
+        exception_message: &str,
+        limit: usize,
+    ) -> Result<Vec<WipStatus>, StoreError>;
+
+    /* Current status of the key */
+    async fn status(&self, key: &str) -> Result<Option<WipStatus>, StoreError>;
+
+    /* Most recently updated jobs, newest first */
+    async fn history(&self, limit: usize) -> Result<Vec<WipStatus>, StoreError>;
+
+    /* Appends to job_attempts */
+    async fn record_attempt(&self, attempt: &JobAttempt) -> Result<(), StoreError>;
+
+    /* Every attempt of the key, oldest first */
+    async fn attempts(&self, key: &str) -> Result<Vec<JobAttempt>, StoreError>;
+
+    /* Failed attempts by handler that started in [since, until) */
+    async fn failure_counts(
+        &self,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<BTreeMap<String, u64>, StoreError>;
+}
+
+/* Store kept in process memory, for tests and local development */
+#[derive(Default)]
+pub struct MemoryStore {
+    cache: Mutex<HashMap<String, Message>>,
+    wip: Mutex<HashMap<String, WipStatus>>,
+    attempts: Mutex<Vec<JobAttempt>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn find_by_key(&self, key: &str) -> Result<Option<Message>, StoreError> {
+        Ok(self.cache.lock().unwrap().get(key).cloned())
+    }
+
+    async fn upsert(&self, message: &Message) -> Result<(), StoreError> {
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(message.key.clone(), message.clone());
+        Ok(())
+    }
+
+    async fn release(
+        &self,
+        key: &str,
+        owner: &str,
+        msg: &str,
+        exception_message: &str,
+        status: &str,
+    ) -> Result<bool, StoreError> {
+        let mut wip = self.wip.lock().unwrap();
+        // Owner and lease stay with the record, as in the Mongo $set
+        match wip.get_mut(key) {
+            Some(current) if current.owner == owner => {
+                current.status = status.to_string();
+                current.msg = msg.to_string();
+                current.exception_message = exception_message.to_string();
+                current.date = Utc::now();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn claim(
+        &self,
+        key: &str,
+        owner: &str,
+        msg: &str,
+        lease: Duration,
+    ) -> Result<Claim, StoreError> {
+        let now = Utc::now();
+        let mut wip = self.wip.lock().unwrap();
+        if let Some(current) = wip
+            .get(key)
+            .filter(|current| current.is_live(now) && current.owner != owner)
+        {
+            return Ok(Claim::Owned(current.owner.clone()));
+        }
+        wip.insert(
+            key.to_string(),
+            WipStatus {
+                key: key.to_string(),
+                status: IN_PROGRESS.to_string(),
+                owner: owner.to_string(),
+                lease_expires: Some(lease_expiry(now, lease)),
+                msg: msg.to_string(),
+                exception_message: String::new(),
+                date: now,
+            },
+        );
+        Ok(Claim::Acquired)
+    }
+
+    async fn heartbeat(
+        &self,
+        key: &str,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<bool, StoreError> {
+        let mut wip = self.wip.lock().unwrap();
+        match wip
+            .get_mut(key)
+            .filter(|current| current.status == IN_PROGRESS && current.owner == owner)
+        {
+            Some(current) => {
+                current.lease_expires = Some(lease_expiry(Utc::now(), lease));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn fail_expired(
+        &self,
+        exception_message: &str,
+        limit: usize,
+    ) -> Result<Vec<WipStatus>, StoreError> {
+        let now = Utc::now();
+        let mut expired = Vec::new();
+        for current in self.wip.lock().unwrap().values_mut() {
+            if expired.len() == limit {
+                break;
+            }
+            let lapsed = current.lease_expires.is_some_and(|expires| expires <= now);
+            if current.status == IN_PROGRESS && lapsed {
+                expired.push(current.clone());
+                current.status = FAILED.to_string();
+                current.exception_message = exception_message.to_string();
+                current.date = now;
+            }
+        }
+        Ok(expired)
+    }
+
+    async fn status(&self, key: &str) -> Result<Option<WipStatus>, StoreError> {
+        Ok(self.wip.lock().unwrap().get(key).cloned())
+    }
+
+    async fn history(&self, limit: usize) -> Result<Vec<WipStatus>, StoreError> {
+        let mut history: Vec<WipStatus> = self.wip.lock().unwrap().values().cloned().collect();
+        history.sort_by_key(|status| std::cmp::Reverse(status.date));
+        history.truncate(limit);
+        Ok(history)
+    }
+
+    async fn record_attempt(&self, attempt: &JobAttempt) -> Result<(), StoreError> {
+        self.attempts.lock().unwrap().push(attempt.clone());
+        Ok(())
+    }
+
+    async fn attempts(&self, key: &str) -> Result<Vec<JobAttempt>, StoreError> {
+        let mut attempts: Vec<JobAttempt> = self
+            .attempts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|attempt| attempt.key == key)
+            .cloned()
+            .collect();
+        attempts.sort_by_key(|attempt| attempt.started_at);
+        Ok(attempts)
+    }
+
+    async fn failure_counts(
+        &self,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<BTreeMap<String, u64>, StoreError> {
+        let mut counts = BTreeMap::new();
+        for attempt in self.attempts.lock().unwrap().iter() {
+            if attempt.outcome == AttemptOutcome::Failed
+                && attempt.started_at >= since
+                && attempt.started_at < until
+            {
+                *counts.entry(attempt.handler.clone()).or_insert(0) += 1;
+            }
+        }
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    #[tokio::test]
+    async fn test_memory_store_upserts_by_key() {
+        let store = MemoryStore::new();
+        let mut message = Message {
+            key: "v1-key".to_string(),
+            e: "Error".to_string(),
+            ..Default::default()
+        };
+        store.upsert(&message).await.unwrap();
+        message.e = String::new();
+        store.upsert(&message).await.unwrap();
+
+        assert_eq!(store.find_by_key("v1-key").await.unwrap(), Some(message));
+        assert_eq!(store.find_by_key("v1-other").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_tracks_status_and_history() {
+        let store = MemoryStore::new();
+        store.claim("second", "owner", "{}", LEASE).await.unwrap();
+        store.claim("first", "owner", "{}", LEASE).await.unwrap();
+        store
+            .release("first", "owner", "{}", "S3 timeout", "RETRY_SCHEDULED")
+            .await
+            .unwrap();
+
+        let status = store.status("first").await.unwrap().unwrap();
+        assert_eq!(status.status, "RETRY_SCHEDULED");
+        assert_eq!(status.exception_message, "S3 timeout");
+        assert!(store.status("third").await.unwrap().is_none());
+
+        let history = store.history(10).await.unwrap();
+        assert_eq!(
+            history.iter().map(|s| s.key.as_str()).collect::<Vec<_>>(),
+            vec!["first", "second"]
+        );
+        assert_eq!(store.history(1).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_claim_is_exclusive_until_finished() {
+        let store = MemoryStore::new();
+        assert_eq!(store.claim("key", "replica-a", "{}", LEASE).await.unwrap(), Claim::Acquired);
+        assert_eq!(
+            store.claim("key", "replica-b", "{}", LEASE).await.unwrap(),
+            Claim::Owned("replica-a".to_string())
+        );
+
+        assert!(store.release("key", "replica-a", "{}", "", DONE).await.unwrap());
+        assert_eq!(store.claim("key", "replica-b", "{}", LEASE).await.unwrap(), Claim::Acquired);
+        assert_eq!(store.status("key").await.unwrap().unwrap().owner, "replica-b");
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_release_after_lost_lease_keeps_the_new_claim() {
+        let store = MemoryStore::new();
+        store.claim("key", "host-a:1", "{}", Duration::ZERO).await.unwrap();
+        assert_eq!(store.claim("key", "host-b:1", "{}", LEASE).await.unwrap(), Claim::Acquired);
+
+        assert!(!store.release("key", "host-a:1", "{}", "", DONE).await.unwrap());
+        let status = store.status("key").await.unwrap().unwrap();
+        assert_eq!((status.status.as_str(), status.owner.as_str()), (IN_PROGRESS, "host-b:1"));
+        assert!(!store.release("missing", "host-a:1", "{}", "", DONE).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_expired_lease_is_reclaimed_and_swept() {
+        let store = MemoryStore::new();
+        store.claim("key", "host-a:1", "{}", Duration::ZERO).await.unwrap();
+        assert!(!store.heartbeat("key", "host-b:1", LEASE).await.unwrap());
+        assert_eq!(store.claim("key", "host-b:1", "{}", LEASE).await.unwrap(), Claim::Acquired);
+        // The crashed owner lost the lease and can not extend it any more
+        assert!(!store.heartbeat("key", "host-a:1", LEASE).await.unwrap());
+        assert!(store.heartbeat("key", "host-b:1", LEASE).await.unwrap());
+        assert!(store.status("key").await.unwrap().unwrap().is_live(Utc::now()));
+
+        store.claim("abandoned", "host-c:1", "{}", Duration::ZERO).await.unwrap();
+        let expired = store.fail_expired("lease expired", 10).await.unwrap();
+        assert_eq!(
+            expired.iter().map(|s| s.key.as_str()).collect::<Vec<_>>(),
+            vec!["abandoned"]
+        );
+        let status = store.status("abandoned").await.unwrap().unwrap();
+        assert_eq!((status.status.as_str(), status.exception_message.as_str()), (FAILED, "lease expired"));
+        assert!(store.fail_expired("lease expired", 10).await.unwrap().is_empty());
+    }
+
+    fn attempt(handler: &str, attempt: u32, outcome: AttemptOutcome, started_at: DateTime<Utc>) -> JobAttempt {
+        JobAttempt {
+            key: format!("v1-{}", handler),
+            cid: "c1".to_string(),
+            message_id: 1.0,
+            handler: handler.to_string(),
+            host: "host".to_string(),
+            attempt,
+            started_at,
+            finished_at: started_at,
+            duration_ms: 0,
+            outcome,
+            error: String::new(),
+            artifacts: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_attempts_and_failure_counts() {
+        let store = MemoryStore::new();
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        store
+            .record_attempt(&attempt("earnings", 2, AttemptOutcome::Succeeded, now))
+            .await
+            .unwrap();
+        store
+            .record_attempt(&attempt("earnings", 1, AttemptOutcome::Failed, now - hour))
+            .await
+            .unwrap();
+        store
+            .record_attempt(&attempt("langchain", 1, AttemptOutcome::Failed, now))
+            .await
+            .unwrap();
+        store
+            .record_attempt(&attempt("langchain", 1, AttemptOutcome::Failed, now - hour * 3))
+            .await
+            .unwrap();
+
+        let attempts = store.attempts("v1-earnings").await.unwrap();
+        assert_eq!(attempts.iter().map(|a| a.attempt).collect::<Vec<_>>(), vec![1, 2]);
+
+        let counts = store.failure_counts(now - hour * 2, now + hour).await.unwrap();
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            vec![("earnings".to_string(), 1), ("langchain".to_string(), 1)]
+        );
+    }
+}
@@ -40,6 +40,15 @@ pub struct Message {
     pub key: String,
     #[serde(default)]
     pub exception_message: String,
+    // Failed attempts so far, carried on messages re-published for a retry
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub attempts: u32,
+    #[serde(rename = "retry_errors", default, skip_serializing_if = "Vec::is_empty")]
+    pub retry_errors: Vec<String>,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
 }
 
 impl Error for Message {}
@@ -51,17 +60,14 @@ impl fmt::Display for Message {
 }
 
 impl Message {
-    pub fn update_s3_location(
-        &mut self,
-        contabo_bucket: String,
-        contabo_object_url_prefix: String,
-        file_name: String,
-    ) {
+    /* signed_url is public or presigned, see S3Module::output_url */
+    pub fn update_s3_location(&mut self, contabo_bucket: String, file_name: String, signed_url: String) {
         self.d_o = Some(DO {
             bucket_name: contabo_bucket.clone(),
             key_name: format!("{}{}", contabo_bucket.clone(), file_name),
-            signed_url: format!("{}{}", contabo_object_url_prefix.clone(), file_name),
+            signed_url,
             data: serde_json::Value::String("".to_string()),
+            data_ref: None,
         });
     }
 }
@@ -83,6 +89,9 @@ pub struct Kwargs {
     pub json_keys: Option<Vec<String>>,
     #[serde(rename = "llm_query", skip_serializing_if = "Option::is_none")]
     pub llm_query: Option<String>,
+    // Skip the cached result and overwrite it, not part of the cache key
+    #[serde(rename = "force_refresh", default, skip_serializing_if = "Option::is_none")]
+    pub force_refresh: Option<bool>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Deserialize)]
@@ -132,4 +141,17 @@ pub struct DO {
     pub data: Value,
     #[serde(rename = "signed_url")]
     pub signed_url: String,
+    // Set when `data` was too large to keep in the message, see payload_store
+    #[serde(rename = "data_ref", default, skip_serializing_if = "Option::is_none")]
+    pub data_ref: Option<DataRef>,
+}
+
+/* Where an out-of-line `DO.data` is stored */
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataRef {
+    // Payload store backend, e.g. gridfs
+    pub store: String,
+    pub id: String,
+    // Size of the JSON payload in bytes
+    pub size: u64,
 }
@@ -0,0 +1,264 @@
+use crate::mongo_service::{ATTEMPTS_COLLECTION, CACHE_COLLECTION, WIP_COLLECTION};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::IndexOptions;
+use mongodb::IndexModel;
+use std::fmt;
+use std::time::Duration;
+
+/* An index the service relies on */
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexSpec {
+    pub collection: &'static str,
+    pub name: &'static str,
+    pub keys: Document,
+    pub unique: bool,
+    // TTL index: documents are deleted this long after their indexed date
+    pub expire_after: Option<Duration>,
+}
+
+impl IndexSpec {
+    pub fn model(&self) -> IndexModel {
+        let options = IndexOptions::builder()
+            .name(self.name.to_string())
+            .unique(self.unique.then_some(true))
+            .expire_after(self.expire_after)
+            .build();
+        IndexModel::builder()
+            .keys(self.keys.clone())
+            .options(options)
+            .build()
+    }
+}
+
+/* Difference between the expected and the existing indexes of a collection */
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexDrift {
+    Missing(IndexSpec),
+    // Same name, only the TTL differs, e.g. after the retention was reconfigured
+    TtlChanged {
+        spec: IndexSpec,
+        existing: Option<Duration>,
+    },
+    // Same name but different keys or uniqueness, left for an operator to fix
+    Changed { spec: IndexSpec, existing: String },
+    // Not created by the service
+    Unexpected { collection: String, name: String },
+}
+
+impl fmt::Display for IndexDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexDrift::Missing(spec) => {
+                write!(f, "{}.{} is missing", spec.collection, spec.name)
+            }
+            IndexDrift::TtlChanged { spec, existing } => write!(
+                f,
+                "{}.{} expires after {:?} instead of {:?}",
+                spec.collection, spec.name, existing, spec.expire_after
+            ),
+            IndexDrift::Changed { spec, existing } => write!(
+                f,
+                "{}.{} is {} instead of {} unique={}",
+                spec.collection, spec.name, existing, spec.keys, spec.unique
+            ),
+            IndexDrift::Unexpected { collection, name } => {
+                write!(f, "{}.{} is not expected", collection, name)
+            }
+        }
+    }
+}
+
+impl IndexDrift {
+    /* messages_wip.key_1 exists but is not the unique index MessageStore::claim relies on: the claim
+    upsert would insert a second document and two workers would both process the key */
+    pub fn breaks_claims(&self) -> bool {
+        matches!(
+            self,
+            IndexDrift::Changed { spec, .. } if spec.collection == WIP_COLLECTION && spec.name == "key_1"
+        )
+    }
+}
+
+/* Indexes of nsq_message_cache, messages_wip and job_attempts. The first two are looked up by key
+on every request, cache entries expire after `cache_retention` and job records after `wip_retention`. */
+pub fn expected_indexes(cache_retention: Duration, wip_retention: Duration) -> Vec<IndexSpec> {
+    vec![
+        IndexSpec {
+            collection: CACHE_COLLECTION,
+            name: "key_1",
+            keys: doc! { "key": 1 },
+            unique: true,
+            expire_after: None,
+        },
+        IndexSpec {
+            collection: CACHE_COLLECTION,
+            name: "date_1",
+            keys: doc! { "date": 1 },
+            unique: false,
+            expire_after: Some(cache_retention),
+        },
+        IndexSpec {
+            collection: WIP_COLLECTION,
+            name: "key_1",
+            keys: doc! { "key": 1 },
+            unique: true,
+            expire_after: None,
+        },
+        IndexSpec {
+            collection: WIP_COLLECTION,
+            name: "date_1",
+            keys: doc! { "date": 1 },
+            unique: false,
+            expire_after: Some(wip_retention),
+        },
+        // Expired lease sweep
+        IndexSpec {
+            collection: WIP_COLLECTION,
+            name: "status_1_lease_expires_1",
+            keys: doc! { "status": 1, "lease_expires": 1 },
+            unique: false,
+            expire_after: None,
+        },
+        // job_attempts is an audit log, it is never expired
+        IndexSpec {
+            collection: ATTEMPTS_COLLECTION,
+            name: "key_1_started_at_1",
+            keys: doc! { "key": 1, "started_at": 1 },
+            unique: false,
+            expire_after: None,
+        },
+        // Failure counts over a time window
+        IndexSpec {
+            collection: ATTEMPTS_COLLECTION,
+            name: "outcome_1_started_at_1",
+            keys: doc! { "outcome": 1, "started_at": 1 },
+            unique: false,
+            expire_after: None,
+        },
+    ]
+}
+
+/* Compares the expected indexes of `collection` with the ones it has, as returned by list_indexes */
+pub fn index_drift(
+    collection: &str,
+    expected: &[IndexSpec],
+    existing: &[IndexModel],
+) -> Vec<IndexDrift> {
+    let existing_name = |index: &IndexModel| {
+        index
+            .options
+            .as_ref()
+            .and_then(|options| options.name.clone())
+            .unwrap_or_default()
+    };
+    let mut drift = Vec::new();
+    for spec in expected.iter().filter(|spec| spec.collection == collection) {
+        let Some(index) = existing.iter().find(|index| existing_name(index) == spec.name) else {
+            drift.push(IndexDrift::Missing(spec.clone()));
+            continue;
+        };
+        let options = index.options.clone().unwrap_or_default();
+        let unique = options.unique.unwrap_or(false);
+        if !same_keys(&index.keys, &spec.keys) || unique != spec.unique {
+            drift.push(IndexDrift::Changed {
+                spec: spec.clone(),
+                existing: format!("{} unique={}", index.keys, unique),
+            });
+        } else if options.expire_after != spec.expire_after {
+            drift.push(IndexDrift::TtlChanged {
+                spec: spec.clone(),
+                existing: options.expire_after,
+            });
+        }
+    }
+    for index in existing {
+        let name = existing_name(index);
+        let known = name == "_id_"
+            || expected
+                .iter()
+                .any(|spec| spec.collection == collection && spec.name == name);
+        if !known {
+            drift.push(IndexDrift::Unexpected {
+                collection: collection.to_string(),
+                name,
+            });
+        }
+    }
+    drift
+}
+
+/* Same fields in the same order with the same direction, whatever numeric type the server returns */
+fn same_keys(existing: &Document, expected: &Document) -> bool {
+    let direction = |value: &Bson| match value {
+        Bson::Int32(value) => Some(*value as f64),
+        Bson::Int64(value) => Some(*value as f64),
+        Bson::Double(value) => Some(*value),
+        _ => None,
+    };
+    existing.len() == expected.len()
+        && existing
+            .iter()
+            .zip(expected.iter())
+            .all(|((a, a_value), (b, b_value))| a == b && direction(a_value) == direction(b_value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn existing(name: &str, keys: Document, unique: bool, expire_after: Option<Duration>) -> IndexModel {
+        IndexModel::builder()
+            .keys(keys)
+            .options(
+                IndexOptions::builder()
+                    .name(name.to_string())
+                    .unique(unique)
+                    .expire_after(expire_after)
+                    .build(),
+            )
+            .build()
+    }
+
+    #[test]
+    fn test_index_drift_reports_missing_changed_and_unexpected() {
+        let expected = expected_indexes(DAY, 7 * DAY);
+        let indexes = vec![
+            existing("_id_", doc! { "_id": 1 }, false, None),
+            // Servers return Int32 or Double directions
+            existing("key_1", doc! { "key": 1.0 }, true, None),
+            existing("date_1", doc! { "date": 1 }, false, Some(2 * DAY)),
+            existing("sravz_id_1", doc! { "sravz_id": 1 }, false, None),
+        ];
+
+        let drift = index_drift(CACHE_COLLECTION, &expected, &indexes);
+        assert_eq!(
+            drift,
+            vec![
+                IndexDrift::TtlChanged {
+                    spec: expected[1].clone(),
+                    existing: Some(2 * DAY),
+                },
+                IndexDrift::Unexpected {
+                    collection: CACHE_COLLECTION.to_string(),
+                    name: "sravz_id_1".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_index_drift_of_new_collection_is_all_missing() {
+        let expected = expected_indexes(DAY, 7 * DAY);
+        let drift = index_drift(WIP_COLLECTION, &expected, &[]);
+        assert_eq!(drift.len(), 3);
+        assert!(drift.iter().all(|drift| matches!(drift, IndexDrift::Missing(_))));
+
+        let not_unique = vec![existing("key_1", doc! { "key": 1 }, false, None)];
+        let drift = index_drift(WIP_COLLECTION, &expected, &not_unique);
+        assert!(matches!(&drift[0], IndexDrift::Changed { spec, .. } if spec.name == "key_1"));
+        assert!(drift[0].breaks_claims());
+        assert!(!drift[1..].iter().any(IndexDrift::breaks_claims));
+    }
+}
@@ -0,0 +1,287 @@
+use crate::config::ProducerConfig;
+use futures::FutureExt;
+use log::{error, info, warn};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_nsq::{NSQEvent, NSQProducer, NSQProducerConfig, NSQTopic};
+
+#[derive(Debug)]
+pub enum PublishError {
+    // NSQ_HOST did not contain any nsqd address
+    NoHosts,
+    InvalidTopic(String),
+    // Every nsqd rejected the message or did not acknowledge it, by host
+    AllHostsFailed(Vec<(String, String)>),
+}
+
+impl std::error::Error for PublishError {}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::NoHosts => write!(f, "No nsqd hosts configured"),
+            PublishError::InvalidTopic(topic) => write!(f, "Invalid NSQ topic {}", topic),
+            PublishError::AllHostsFailed(failures) => {
+                write!(f, "Publish failed on all nsqd hosts:")?;
+                for (host, err) in failures {
+                    write!(f, " {} ({})", host, err)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+struct PooledProducer {
+    host: String,
+    producer: Mutex<NSQProducer>,
+    healthy: AtomicBool,
+}
+
+impl PooledProducer {
+    fn connect(host: &str) -> Self {
+        info!("Connecting to nsqd host {}", host);
+        PooledProducer {
+            host: host.to_string(),
+            producer: Mutex::new(NSQProducerConfig::new(host).build()),
+            healthy: AtomicBool::new(false),
+        }
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::SeqCst) != healthy {
+            if healthy {
+                info!("nsqd host {} is healthy", self.host);
+            } else {
+                warn!("nsqd host {} is unhealthy", self.host);
+            }
+        }
+    }
+
+    /* Publishes and waits for nsqd to acknowledge the message */
+    async fn publish(
+        &self,
+        topic: &Arc<NSQTopic>,
+        body: Vec<u8>,
+        delay_milliseconds: u32,
+        ack_timeout: Duration,
+    ) -> Result<(), String> {
+        let mut producer = self.producer.lock().await;
+        // Drop stale events so the next Ok() belongs to this message
+        self.drain_events(&mut producer);
+        let queued = if delay_milliseconds == 0 {
+            producer.publish(topic, body).await
+        } else {
+            producer
+                .publish_deferred(topic, body, delay_milliseconds)
+                .await
+        };
+        if let Err(err) = queued {
+            self.set_healthy(false);
+            return Err(err.to_string());
+        }
+        loop {
+            match tokio::time::timeout(ack_timeout, producer.consume()).await {
+                Ok(Some(NSQEvent::Ok())) => {
+                    self.set_healthy(true);
+                    return Ok(());
+                }
+                Ok(Some(NSQEvent::Healthy())) => self.set_healthy(true),
+                Ok(Some(NSQEvent::Unhealthy())) => {
+                    self.set_healthy(false);
+                    return Err("connection lost before acknowledgement".to_string());
+                }
+                Ok(Some(NSQEvent::Message(_))) => {}
+                Ok(None) => {
+                    self.set_healthy(false);
+                    return Err("connection closed".to_string());
+                }
+                Err(_) => {
+                    self.set_healthy(false);
+                    return Err(format!("no acknowledgement within {:?}", ack_timeout));
+                }
+            }
+        }
+    }
+
+    /* Applies the connection events that are already queued without waiting.
+    Returns false when the connection task has gone away. */
+    fn drain_events(&self, producer: &mut NSQProducer) -> bool {
+        loop {
+            match producer.consume().now_or_never() {
+                Some(Some(NSQEvent::Healthy())) => self.set_healthy(true),
+                Some(Some(NSQEvent::Unhealthy())) => self.set_healthy(false),
+                Some(Some(_)) => {}
+                Some(None) => {
+                    self.set_healthy(false);
+                    return false;
+                }
+                None => return true,
+            }
+        }
+    }
+}
+
+/* One NSQ producer per nsqd host. Publishes round-robin across healthy hosts and
+fails over to the next host when one rejects or does not acknowledge a message. */
+pub struct ProducerPool {
+    producers: Vec<PooledProducer>,
+    next: AtomicUsize,
+    ack_timeout: Duration,
+}
+
+impl ProducerPool {
+    pub fn new(nsqd_hosts: &[String], config: &ProducerConfig) -> Self {
+        let producers = nsqd_hosts
+            .iter()
+            .map(|host| host.trim())
+            .filter(|host| !host.is_empty())
+            .map(PooledProducer::connect)
+            .collect();
+        ProducerPool {
+            producers,
+            next: AtomicUsize::new(0),
+            ack_timeout: Duration::from_millis(config.ack_timeout_ms),
+        }
+    }
+
+    pub async fn publish(&self, topic: &str, body: &[u8]) -> Result<(), PublishError> {
+        self.publish_deferred(topic, body, 0).await
+    }
+
+    pub async fn publish_deferred(
+        &self,
+        topic: &str,
+        body: &[u8],
+        delay_milliseconds: u32,
+    ) -> Result<(), PublishError> {
+        if self.producers.is_empty() {
+            return Err(PublishError::NoHosts);
+        }
+        let producer_topic = NSQTopic::new(topic.to_string())
+            .ok_or_else(|| PublishError::InvalidTopic(topic.to_string()))?;
+
+        let mut failures = Vec::new();
+        for index in self.publish_order() {
+            let pooled = &self.producers[index];
+            match pooled
+                .publish(&producer_topic, body.to_vec(), delay_milliseconds, self.ack_timeout)
+                .await
+            {
+                Ok(()) => {
+                    info!("Published to NSQ topic {} via {}", topic, pooled.host);
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!("Publish to {} via {} failed: {}", topic, pooled.host, err);
+                    failures.push((pooled.host.clone(), err));
+                }
+            }
+        }
+        Err(PublishError::AllHostsFailed(failures))
+    }
+
+    /* Round-robin start, healthy hosts first, unhealthy ones as a last resort */
+    fn publish_order(&self) -> Vec<usize> {
+        let count = self.producers.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        let rotated = (0..count).map(|offset| (start + offset) % count);
+        let (healthy, unhealthy): (Vec<usize>, Vec<usize>) =
+            rotated.partition(|&index| self.producers[index].healthy.load(Ordering::SeqCst));
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    pub fn healthy_hosts(&self) -> usize {
+        self.producers
+            .iter()
+            .filter(|pooled| pooled.healthy.load(Ordering::SeqCst))
+            .count()
+    }
+
+    /* Refreshes host health from connection events and reconnects producers whose
+    connection task has stopped. Producers busy publishing are checked next round. */
+    pub async fn check_health(&self) {
+        for pooled in &self.producers {
+            if let Ok(mut producer) = pooled.producer.try_lock() {
+                if !pooled.drain_events(&mut producer) {
+                    error!("nsqd host {} connection stopped, reconnecting", pooled.host);
+                    *producer = NSQProducerConfig::new(pooled.host.as_str()).build();
+                }
+            }
+        }
+    }
+
+    pub async fn run_health_checks(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.check_health().await;
+            if self.healthy_hosts() == 0 {
+                error!("No healthy nsqd hosts out of {}", self.producers.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn producer_config() -> ProducerConfig {
+        ProducerConfig {
+            ack_timeout_ms: 200,
+            health_check_interval_secs: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_without_hosts_fails() {
+        let producer_pool = ProducerPool::new(&["".to_string()], &producer_config());
+        let result = producer_pool.publish("topic", b"{}").await;
+        assert!(matches!(result, Err(PublishError::NoHosts)));
+    }
+
+    #[tokio::test]
+    async fn test_publish_fails_over_and_returns_error() {
+        // Nothing listens on these ports, so both hosts stay unhealthy
+        let hosts = vec!["127.0.0.1:1".to_string(), "127.0.0.1:2".to_string()];
+        let producer_pool = ProducerPool::new(&hosts, &producer_config());
+        match producer_pool.publish("topic", b"{}").await {
+            Err(PublishError::AllHostsFailed(failures)) => {
+                let failed_hosts: Vec<&str> =
+                    failures.iter().map(|(host, _)| host.as_str()).collect();
+                assert_eq!(failed_hosts.len(), 2);
+                assert!(failed_hosts.contains(&"127.0.0.1:1"));
+                assert!(failed_hosts.contains(&"127.0.0.1:2"));
+            }
+            other => panic!("Expected AllHostsFailed, got {:?}", other),
+        }
+        assert_eq!(producer_pool.healthy_hosts(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_publish_rejects_invalid_topic() {
+        let producer_pool = ProducerPool::new(&["127.0.0.1:1".to_string()], &producer_config());
+        let result = producer_pool.publish("not a topic!", b"{}").await;
+        assert!(matches!(result, Err(PublishError::InvalidTopic(_))));
+    }
+
+    #[tokio::test]
+    async fn test_publish_order_round_robins_healthy_hosts_first() {
+        let hosts = vec![
+            "127.0.0.1:1".to_string(),
+            "127.0.0.1:2".to_string(),
+            "127.0.0.1:3".to_string(),
+        ];
+        let producer_pool = ProducerPool::new(&hosts, &producer_config());
+        producer_pool.producers[0].set_healthy(true);
+        producer_pool.producers[2].set_healthy(true);
+        assert_eq!(producer_pool.publish_order(), vec![0, 2, 1]);
+        assert_eq!(producer_pool.publish_order(), vec![2, 0, 1]);
+        assert_eq!(producer_pool.publish_order(), vec![2, 0, 1]);
+        assert_eq!(producer_pool.publish_order(), vec![0, 2, 1]);
+    }
+}
@@ -0,0 +1,314 @@
+use crate::helper::sha256_hash;
+use crate::object_store::{Object, ObjectHead, S3Error};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/* Headers of a cached body, stored next to it as <name>.json */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedHead {
+    bucket: String,
+    key: String,
+    size: u64,
+    last_modified: Option<DateTime<Utc>>,
+    etag: Option<String>,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+}
+
+impl CachedHead {
+    fn head(self) -> ObjectHead {
+        ObjectHead {
+            size: self.size,
+            last_modified: self.last_modified,
+            etag: self.etag,
+            content_type: self.content_type,
+            content_encoding: self.content_encoding,
+        }
+    }
+}
+
+/* Downloaded objects on local disk, revalidated by S3Module against the store before use.
+Bodies are kept as stored, compressed objects stay compressed. The least recently used objects
+are removed once the bodies add up to more than max_size_bytes; the last use is the body's mtime
+so it survives restarts. */
+pub struct ObjectCache {
+    directory: PathBuf,
+    max_size_bytes: u64,
+    // Size and last use of the cached bodies by name
+    index: Mutex<HashMap<String, (u64, SystemTime)>>,
+}
+
+impl ObjectCache {
+    /* Creates the directory and indexes the objects cached by previous runs */
+    pub fn open(directory: impl Into<PathBuf>, max_size_bytes: u64) -> Result<Self, S3Error> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        let mut index = HashMap::new();
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "json") {
+                // Head of a body that was never moved in place or is gone
+                if !path.with_extension("").is_file() {
+                    let _ = fs::remove_file(&path);
+                }
+                continue;
+            }
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            match fs::metadata(&path) {
+                Ok(metadata) if directory.join(format!("{}.json", name)).is_file() => {
+                    let last_used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    index.insert(name.to_string(), (metadata.len(), last_used));
+                }
+                // Partly written entry, a .partial left by an interrupted put, or a stray file
+                _ => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        let cache = ObjectCache {
+            directory,
+            max_size_bytes,
+            index: Mutex::new(index),
+        };
+        cache.evict();
+        info!(
+            "Object cache {} holds {} of {} bytes",
+            cache.directory.display(),
+            cache.size_bytes(),
+            cache.max_size_bytes
+        );
+        Ok(cache)
+    }
+
+    fn name(bucket: &str, key: &str) -> String {
+        sha256_hash(&format!("{}/{}", bucket, key))
+    }
+
+    fn body_path(&self, name: &str) -> PathBuf {
+        self.directory.join(name)
+    }
+
+    fn head_path(&self, name: &str) -> PathBuf {
+        self.directory.join(format!("{}.json", name))
+    }
+
+    /* The cached copy, not revalidated. Unreadable entries are dropped and reported as a miss. */
+    pub async fn get(&self, bucket: &str, key: &str) -> Option<Object> {
+        let name = Self::name(bucket, key);
+        if !self.index.lock().unwrap().contains_key(&name) {
+            return None;
+        }
+        let read = async {
+            let head: CachedHead = serde_json::from_slice(&tokio::fs::read(self.head_path(&name)).await?)?;
+            let body = tokio::fs::read(self.body_path(&name)).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>((head, body))
+        };
+        match read.await {
+            Ok((head, body)) if head.bucket == bucket && head.key == key && head.size == body.len() as u64 => {
+                self.touch(&name);
+                Some(Object {
+                    body,
+                    head: head.head(),
+                })
+            }
+            Ok(_) => {
+                self.remove(bucket, key).await;
+                None
+            }
+            Err(err) => {
+                error!("Dropping cached {}/{}: {}", bucket, key, err);
+                self.remove(bucket, key).await;
+                None
+            }
+        }
+    }
+
+    /* Caches the object when it can be revalidated, i.e. has an ETag or Last-Modified */
+    pub async fn put(&self, bucket: &str, key: &str, object: &Object) -> Result<(), S3Error> {
+        let size = object.body.len() as u64;
+        if size > self.max_size_bytes
+            || (object.head.etag.is_none() && object.head.last_modified.is_none())
+        {
+            return Ok(());
+        }
+        let name = Self::name(bucket, key);
+        let head = CachedHead {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            size,
+            last_modified: object.head.last_modified,
+            etag: object.head.etag.clone(),
+            content_type: object.head.content_type.clone(),
+            content_encoding: object.head.content_encoding.clone(),
+        };
+        let head = serde_json::to_vec(&head).map_err(|err| S3Error::Corrupt(err.to_string()))?;
+        // Readers never see a partly written file. Both are written under names unique to this put,
+        // so concurrent puts of the key do not mix, then moved in place body first: a body next to
+        // the previous head fails get's size check or revalidation and is fetched again.
+        let write_id = uuid::Uuid::new_v4();
+        let partial_body = self.directory.join(format!("{}.{}.partial", name, write_id));
+        let partial_head = self.directory.join(format!("{}.json.{}.partial", name, write_id));
+        let written = async {
+            tokio::fs::write(&partial_body, &object.body).await?;
+            tokio::fs::write(&partial_head, head).await?;
+            tokio::fs::rename(&partial_body, self.body_path(&name)).await?;
+            tokio::fs::rename(&partial_head, self.head_path(&name)).await
+        };
+        if let Err(err) = written.await {
+            Self::remove_files(&partial_body, &partial_head);
+            return Err(err.into());
+        }
+        self.index
+            .lock()
+            .unwrap()
+            .insert(name, (size, SystemTime::now()));
+        self.evict();
+        Ok(())
+    }
+
+    /* Drops the cached copy, e.g. after the object was overwritten */
+    pub async fn remove(&self, bucket: &str, key: &str) {
+        let name = Self::name(bucket, key);
+        self.index.lock().unwrap().remove(&name);
+        Self::remove_files(&self.body_path(&name), &self.head_path(&name));
+    }
+
+    // Bytes of the cached bodies
+    pub fn size_bytes(&self) -> u64 {
+        self.index.lock().unwrap().values().map(|(size, _)| size).sum()
+    }
+
+    fn touch(&self, name: &str) {
+        let now = SystemTime::now();
+        if let Some(entry) = self.index.lock().unwrap().get_mut(name) {
+            entry.1 = now;
+        }
+        let _ = fs::File::options()
+            .write(true)
+            .open(self.body_path(name))
+            .and_then(|file| file.set_modified(now));
+    }
+
+    /* Removes the least recently used objects until the cache fits max_size_bytes */
+    fn evict(&self) {
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            let mut size: u64 = index.values().map(|(size, _)| size).sum();
+            if size <= self.max_size_bytes {
+                return;
+            }
+            let mut by_last_use: Vec<(String, u64, SystemTime)> = index
+                .iter()
+                .map(|(name, (size, last_used))| (name.clone(), *size, *last_used))
+                .collect();
+            by_last_use.sort_by_key(|(_, _, last_used)| *last_used);
+            let mut evicted = Vec::new();
+            for (name, entry_size, _) in by_last_use {
+                if size <= self.max_size_bytes {
+                    break;
+                }
+                index.remove(&name);
+                size -= entry_size;
+                evicted.push(name);
+            }
+            evicted
+        };
+        for name in evicted {
+            Self::remove_files(&self.body_path(&name), &self.head_path(&name));
+        }
+    }
+
+    fn remove_files(body: &Path, head: &Path) {
+        let _ = fs::remove_file(body);
+        let _ = fs::remove_file(head);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(body: &[u8], etag: &str) -> Object {
+        Object {
+            body: body.to_vec(),
+            head: ObjectHead {
+                size: body.len() as u64,
+                etag: Some(etag.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_get_and_reopen() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = ObjectCache::open(directory.path(), 1024).unwrap();
+        cache.put("sravz", "historical/a.json", &object(b"[1]", "\"a\"")).await.unwrap();
+
+        let cached = cache.get("sravz", "historical/a.json").await.unwrap();
+        assert_eq!(cached.body, b"[1]");
+        assert_eq!(cached.head.etag.as_deref(), Some("\"a\""));
+        assert!(cache.get("sravz", "historical/b.json").await.is_none());
+
+        // Objects cached by a previous run are kept
+        let cache = ObjectCache::open(directory.path(), 1024).unwrap();
+        assert_eq!(cache.size_bytes(), 3);
+        assert!(cache.get("sravz", "historical/a.json").await.is_some());
+        cache.remove("sravz", "historical/a.json").await;
+        assert!(cache.get("sravz", "historical/a.json").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_puts_are_cleaned_up_on_open() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = ObjectCache::open(directory.path(), 1024).unwrap();
+        cache.put("sravz", "a", &object(b"[1]", "\"a\"")).await.unwrap();
+        let name = ObjectCache::name("sravz", "a");
+        let mut files: Vec<String> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec![name.clone(), format!("{}.json", name)]);
+
+        let orphan = ObjectCache::name("sravz", "b");
+        for leftover in [
+            format!("{}.1234.partial", name),
+            format!("{}.json.1234.partial", name),
+            format!("{}.json", orphan),
+        ] {
+            fs::write(directory.path().join(leftover), b"{}").unwrap();
+        }
+        let cache = ObjectCache::open(directory.path(), 1024).unwrap();
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 2);
+        assert_eq!(cache.get("sravz", "a").await.unwrap().body, b"[1]");
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_are_evicted() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = ObjectCache::open(directory.path(), 10).unwrap();
+        cache.put("sravz", "a", &object(b"aaaa", "\"a\"")).await.unwrap();
+        cache.put("sravz", "b", &object(b"bbbb", "\"b\"")).await.unwrap();
+        // a is now used more recently than b
+        assert!(cache.get("sravz", "a").await.is_some());
+        cache.put("sravz", "c", &object(b"cccc", "\"c\"")).await.unwrap();
+
+        assert!(cache.get("sravz", "b").await.is_none());
+        assert!(cache.get("sravz", "a").await.is_some());
+        assert!(cache.get("sravz", "c").await.is_some());
+        assert_eq!(cache.size_bytes(), 8);
+
+        // Larger than the whole cache
+        cache.put("sravz", "d", &object(&[0; 11], "\"d\"")).await.unwrap();
+        assert!(cache.get("sravz", "d").await.is_none());
+    }
+}
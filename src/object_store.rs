@@ -0,0 +1,579 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+/* Why an object storage operation failed */
+#[derive(Debug, Clone, PartialEq)]
+pub enum S3Error {
+    // The bucket or object does not exist
+    NotFound(String),
+    // Missing or rejected credentials, or no permission for the bucket
+    AccessDenied(String),
+    // The store asked to slow down, worth retrying later
+    Throttled(String),
+    // The store could not be reached or the connection failed, worth retrying
+    Network(String),
+    // Stored bytes could not be read in the expected format
+    Corrupt(String),
+    // The request was rejected as malformed, e.g. an invalid key
+    Invalid(String),
+    // Any other failure reported by the store
+    Service(String),
+}
+
+impl Error for S3Error {}
+
+impl fmt::Display for S3Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            S3Error::NotFound(reason) => write!(f, "Not found: {}", reason),
+            S3Error::AccessDenied(reason) => write!(f, "Access denied: {}", reason),
+            S3Error::Throttled(reason) => write!(f, "Throttled: {}", reason),
+            S3Error::Network(reason) => write!(f, "Network error: {}", reason),
+            S3Error::Corrupt(reason) => write!(f, "Corrupt object: {}", reason),
+            S3Error::Invalid(reason) => write!(f, "Invalid request: {}", reason),
+            S3Error::Service(reason) => write!(f, "Service error: {}", reason),
+        }
+    }
+}
+
+/* Local files and streamed bodies fail with io::Error */
+impl From<io::Error> for S3Error {
+    fn from(error: io::Error) -> Self {
+        let reason = error.to_string();
+        match error.kind() {
+            io::ErrorKind::NotFound => S3Error::NotFound(reason),
+            io::ErrorKind::PermissionDenied => S3Error::AccessDenied(reason),
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => S3Error::Corrupt(reason),
+            io::ErrorKind::InvalidInput => S3Error::Invalid(reason),
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe => S3Error::Network(reason),
+            _ => S3Error::Service(reason),
+        }
+    }
+}
+
+/* Metadata of a stored object */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectHead {
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub etag: Option<String>,
+    pub content_type: Option<String>,
+    // "gzip" when the stored bytes are compressed
+    pub content_encoding: Option<String>,
+}
+
+/* A downloaded object, body as stored */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub body: Vec<u8>,
+    pub head: ObjectHead,
+}
+
+/* Headers stored with an object on put */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PutOptions {
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+}
+
+impl PutOptions {
+    // Body is gzip compressed content of content_type
+    pub fn gzip(content_type: &str) -> Self {
+        PutOptions {
+            content_type: Some(content_type.to_string()),
+            content_encoding: Some("gzip".to_string()),
+        }
+    }
+}
+
+/* MIME type for the extension of a key, None when unknown */
+pub fn guess_content_type(key: &str) -> Option<&'static str> {
+    let extension = Path::new(key).extension()?.to_str()?.to_ascii_lowercase();
+    Some(match extension.as_str() {
+        "json" => "application/json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+        "html" => "text/html",
+        "csv" => "text/csv",
+        "txt" => "text/plain",
+        "parquet" => "application/vnd.apache.parquet",
+        "gz" => "application/gzip",
+        _ => return None,
+    })
+}
+
+/* Whether the bytes start with the gzip magic number */
+pub fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.starts_with(&[0x1f, 0x8b])
+}
+
+/* Which keys a list call returns, S3 ListObjectsV2 semantics */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListOptions {
+    pub prefix: String,
+    // Keys with the delimiter after the prefix are rolled up into ListPage.common_prefixes,
+    // "/" lists one directory level
+    pub delimiter: Option<String>,
+    // ListPage.next_continuation_token of the previous page
+    pub continuation_token: Option<String>,
+    // Objects plus common prefixes per page, the store's limit (1000 for S3) when None
+    pub max_keys: Option<usize>,
+}
+
+/* An object in a ListPage */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectSummary {
+    pub key: String,
+    pub head: ObjectHead,
+}
+
+/* One page of a listing, objects and common prefixes in lexicographic order */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListPage {
+    pub objects: Vec<ObjectSummary>,
+    pub common_prefixes: Vec<String>,
+    // Set when there are more keys, pass it back in ListOptions.continuation_token
+    pub next_continuation_token: Option<String>,
+}
+
+// Page size of LocalObjectStore when ListOptions.max_keys is None, as S3
+const DEFAULT_MAX_KEYS: usize = 1000;
+// Directory under the LocalObjectStore root with the headers of the objects, outside every bucket
+const LOCAL_HEADERS_DIR: &str = ".headers";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PresignMethod {
+    #[default]
+    Get,
+    // Lets a client upload the object without credentials
+    Put,
+}
+
+/* What a presigned URL allows and for how long */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresignOptions {
+    pub expires_in: Duration,
+    pub method: PresignMethod,
+    // Get: Content-Type of the response, Put: Content-Type the upload has to be sent with
+    pub content_type: Option<String>,
+    // Get only: Content-Disposition of the response, e.g. attachment; filename="earnings.png"
+    pub content_disposition: Option<String>,
+}
+
+impl PresignOptions {
+    pub fn get(expires_in: Duration) -> Self {
+        PresignOptions {
+            expires_in,
+            method: PresignMethod::Get,
+            content_type: None,
+            content_disposition: None,
+        }
+    }
+
+    pub fn put(expires_in: Duration) -> Self {
+        PresignOptions {
+            method: PresignMethod::Put,
+            ..Self::get(expires_in)
+        }
+    }
+}
+
+/* Bucket storage behind S3Module. Missing objects are S3Error::NotFound, except for head which
+returns None. */
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn get(&self, bucket: &str, key: &str) -> Result<Object, S3Error>;
+    /* The object unless it still matches `cached`, for revalidating a local copy. By default a
+    HEAD compared on the ETag, or Last-Modified when there is no ETag. */
+    async fn get_if_modified(
+        &self,
+        bucket: &str,
+        key: &str,
+        cached: &ObjectHead,
+    ) -> Result<Option<Object>, S3Error> {
+        let Some(head) = self.head(bucket, key).await? else {
+            return Err(S3Error::NotFound(format!("Object {}/{} not found", bucket, key)));
+        };
+        let unchanged = match (&head.etag, &cached.etag) {
+            (Some(etag), Some(cached_etag)) => etag == cached_etag,
+            _ => head.last_modified.is_some() && head.last_modified == cached.last_modified,
+        };
+        if unchanged {
+            return Ok(None);
+        }
+        self.get(bucket, key).await.map(Some)
+    }
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<(), S3Error>;
+    async fn put_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        options: &PutOptions,
+    ) -> Result<(), S3Error>;
+    async fn head(&self, bucket: &str, key: &str) -> Result<Option<ObjectHead>, S3Error>;
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), S3Error>;
+    // One page of the bucket's objects, see ListOptions
+    async fn list(&self, bucket: &str, options: &ListOptions) -> Result<ListPage, S3Error>;
+    // URL the object can be downloaded, or uploaded with PresignMethod::Put, from without credentials
+    async fn presign(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PresignOptions,
+    ) -> Result<String, S3Error>;
+    async fn create_bucket(&self, bucket: &str) -> Result<(), S3Error>;
+}
+
+/* ObjectStore in a local directory, one sub directory per bucket, for running against fixtures
+on disk without object storage credentials. The headers given on put are kept as JSON under
+LOCAL_HEADERS_DIR, the content type of files without headers, e.g. fixtures, is guessed from the key. */
+pub struct LocalObjectStore {
+    root: PathBuf,
+}
+
+/* Sidecar of a LocalObjectStore object with the PutOptions it was stored with */
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LocalHeaders {
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+}
+
+impl LocalObjectStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalObjectStore { root: root.into() }
+    }
+
+    /* Path of the object, keys must stay inside the bucket directory */
+    fn path(&self, bucket: &str, key: &str) -> Result<PathBuf, S3Error> {
+        let relative = Path::new(bucket).join(key);
+        // S3 bucket names never start with a dot, this also keeps LOCAL_HEADERS_DIR out of reach
+        if bucket.is_empty()
+            || bucket.starts_with('.')
+            || key.is_empty()
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(S3Error::Invalid(format!("Invalid object {}/{}", bucket, key)));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn headers_path(&self, bucket: &str, key: &str) -> Result<PathBuf, S3Error> {
+        self.path(bucket, key)?;
+        Ok(self.root.join(LOCAL_HEADERS_DIR).join(bucket).join(key))
+    }
+
+    /* Headers stored with the object, none for files put in place by hand */
+    async fn read_headers(&self, bucket: &str, key: &str) -> Result<LocalHeaders, S3Error> {
+        match tokio::fs::read(self.headers_path(bucket, key)?).await {
+            Ok(json) => serde_json::from_slice(&json).map_err(|err| {
+                S3Error::Corrupt(format!("Headers of {}/{}: {}", bucket, key, err))
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(LocalHeaders::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn write_headers(&self, bucket: &str, key: &str, options: &PutOptions) -> Result<(), S3Error> {
+        let path = self.headers_path(bucket, key)?;
+        let headers = LocalHeaders {
+            content_type: options.content_type.clone(),
+            content_encoding: options.content_encoding.clone(),
+        };
+        Self::create_parent(&path).await?;
+        let json = serde_json::to_vec(&headers).map_err(|err| S3Error::Invalid(err.to_string()))?;
+        tokio::fs::write(path, json).await?;
+        Ok(())
+    }
+
+    fn object_head(key: &str, metadata: &std::fs::Metadata, headers: LocalHeaders) -> ObjectHead {
+        let last_modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+        ObjectHead {
+            content_type: headers
+                .content_type
+                .or_else(|| guess_content_type(key).map(str::to_string)),
+            content_encoding: headers.content_encoding,
+            size: metadata.len(),
+            // Size and mtime change whenever the file is rewritten
+            etag: last_modified.map(|modified| {
+                format!("\"{:x}-{:x}\"", metadata.len(), modified.timestamp_nanos_opt().unwrap_or_default())
+            }),
+            last_modified,
+        }
+    }
+
+    async fn create_parent(path: &Path) -> Result<(), io::Error> {
+        match path.parent() {
+            Some(parent) => tokio::fs::create_dir_all(parent).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalObjectStore {
+    async fn get(&self, bucket: &str, key: &str) -> Result<Object, S3Error> {
+        let path = self.path(bucket, key)?;
+        let body = tokio::fs::read(&path).await.map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => S3Error::NotFound(format!("Object {}/{} not found", bucket, key)),
+            _ => S3Error::from(err),
+        })?;
+        let metadata = tokio::fs::metadata(&path).await?;
+        Ok(Object {
+            body,
+            head: Self::object_head(key, &metadata, self.read_headers(bucket, key).await?),
+        })
+    }
+
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<(), S3Error> {
+        let path = self.path(bucket, key)?;
+        Self::create_parent(&path).await?;
+        tokio::fs::write(path, body).await?;
+        self.write_headers(bucket, key, options).await
+    }
+
+    async fn put_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        options: &PutOptions,
+    ) -> Result<(), S3Error> {
+        let destination = self.path(bucket, key)?;
+        Self::create_parent(&destination).await?;
+        tokio::fs::copy(path, destination).await?;
+        self.write_headers(bucket, key, options).await
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<Option<ObjectHead>, S3Error> {
+        let metadata = match tokio::fs::metadata(self.path(bucket, key)?).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(Self::object_head(key, &metadata, self.read_headers(bucket, key).await?)))
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), S3Error> {
+        // Like S3, deleting a missing object succeeds
+        for path in [self.path(bucket, key)?, self.headers_path(bucket, key)?] {
+            match tokio::fs::remove_file(path).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /* The continuation token is the last key or common prefix of the previous page */
+    async fn list(&self, bucket: &str, options: &ListOptions) -> Result<ListPage, S3Error> {
+        if bucket.is_empty() || bucket.starts_with('.') || bucket.contains(['/', '\\']) {
+            return Err(S3Error::Invalid(format!("Invalid bucket {}", bucket)));
+        }
+        let bucket_dir = self.root.join(bucket);
+        let mut objects = Vec::new();
+        let mut dirs = vec![bucket_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let Ok(relative) = path.strip_prefix(&bucket_dir) else {
+                    continue;
+                };
+                let key = relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if key.starts_with(&options.prefix) {
+                    let headers = self.read_headers(bucket, &key).await?;
+                    objects.push(ObjectSummary {
+                        head: Self::object_head(&key, &metadata, headers),
+                        key,
+                    });
+                }
+            }
+        }
+        // S3 lists keys in lexicographic order
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let mut page = ListPage::default();
+        let max_keys = options.max_keys.unwrap_or(DEFAULT_MAX_KEYS);
+        let mut last = options.continuation_token.clone();
+        for object in objects {
+            let common_prefix = options.delimiter.as_deref().and_then(|delimiter| {
+                let rest = &object.key[options.prefix.len()..];
+                rest.find(delimiter).map(|index| {
+                    object.key[..options.prefix.len() + index + delimiter.len()].to_string()
+                })
+            });
+            let name = common_prefix.as_deref().unwrap_or(&object.key);
+            if last.as_deref().is_some_and(|last| name <= last) {
+                continue;
+            }
+            if page.objects.len() + page.common_prefixes.len() == max_keys {
+                page.next_continuation_token = last;
+                break;
+            }
+            last = Some(name.to_string());
+            match common_prefix {
+                Some(common_prefix) => page.common_prefixes.push(common_prefix),
+                None => page.objects.push(object),
+            }
+        }
+        Ok(page)
+    }
+
+    /* The file itself, local files do not expire */
+    async fn presign(
+        &self,
+        bucket: &str,
+        key: &str,
+        _options: &PresignOptions,
+    ) -> Result<String, S3Error> {
+        let path = self.path(bucket, key)?;
+        let path = std::path::absolute(&path).unwrap_or(path);
+        Ok(format!("file://{}", path.display()))
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> Result<(), S3Error> {
+        tokio::fs::create_dir_all(self.root.join(bucket)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_put_get_head_list_delete() {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path());
+        let options = PutOptions::default();
+        store.put("sravz", "historical/b.json", b"{}".to_vec(), &options).await.unwrap();
+        store.put("sravz", "historical/a.json", b"[1]".to_vec(), &options).await.unwrap();
+        store.put("sravz", "earnings/a.json", b"[]".to_vec(), &options).await.unwrap();
+
+        let object = store.get("sravz", "historical/a.json").await.unwrap();
+        assert_eq!(object.body, b"[1]");
+        let head = store.head("sravz", "historical/a.json").await.unwrap().unwrap();
+        assert_eq!(head, object.head);
+        assert_eq!(head.size, 3);
+        assert_eq!(head.content_type.as_deref(), Some("application/json"));
+        assert!(head.last_modified.is_some());
+        let listing = ListOptions {
+            prefix: "historical/".to_string(),
+            ..Default::default()
+        };
+        let page = store.list("sravz", &listing).await.unwrap();
+        let keys: Vec<&str> = page.objects.iter().map(|object| object.key.as_str()).collect();
+        assert_eq!(keys, vec!["historical/a.json", "historical/b.json"]);
+        assert_eq!(page.objects[0].head, head);
+
+        store.delete("sravz", "historical/a.json").await.unwrap();
+        assert_eq!(store.head("sravz", "historical/a.json").await.unwrap(), None);
+        let err = store.get("sravz", "historical/a.json").await.unwrap_err();
+        assert!(matches!(err, S3Error::NotFound(_)));
+        assert!(store.delete("sravz", "historical/a.json").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_local_put_keeps_headers() {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path());
+        let gzip = PutOptions::gzip("application/json");
+        store.put("sravz", "earnings/a", b"\x1f\x8b".to_vec(), &gzip).await.unwrap();
+        store.put("sravz", "earnings/b.json", b"[]".to_vec(), &PutOptions::default()).await.unwrap();
+
+        let head = store.head("sravz", "earnings/a").await.unwrap().unwrap();
+        assert_eq!(head.content_type.as_deref(), Some("application/json"));
+        assert_eq!(head.content_encoding.as_deref(), Some("gzip"));
+        assert_eq!(store.get("sravz", "earnings/a").await.unwrap().head, head);
+        let page = store.list("sravz", &ListOptions::default()).await.unwrap();
+        let keys: Vec<&str> = page.objects.iter().map(|object| object.key.as_str()).collect();
+        assert_eq!(keys, vec!["earnings/a", "earnings/b.json"]);
+        assert_eq!(page.objects[0].head, head);
+        // Guessed without headers
+        assert_eq!(page.objects[1].head.content_type.as_deref(), Some("application/json"));
+        assert_eq!(page.objects[1].head.content_encoding, None);
+
+        // Overwriting replaces the headers, deleting removes them
+        store.put("sravz", "earnings/a", b"[]".to_vec(), &PutOptions::default()).await.unwrap();
+        assert_eq!(store.head("sravz", "earnings/a").await.unwrap().unwrap().content_encoding, None);
+        store.delete("sravz", "earnings/a").await.unwrap();
+        assert!(!root.path().join(LOCAL_HEADERS_DIR).join("sravz/earnings/a").exists());
+        assert!(store.list(LOCAL_HEADERS_DIR, &ListOptions::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_list_delimiter_and_pages() {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path());
+        let options = PutOptions::default();
+        for key in ["a.json", "eod/x.json", "eod/y.json", "historical/a.json", "z.json"] {
+            store.put("sravz", key, b"{}".to_vec(), &options).await.unwrap();
+        }
+
+        let mut listing = ListOptions {
+            delimiter: Some("/".to_string()),
+            max_keys: Some(2),
+            ..Default::default()
+        };
+        let page = store.list("sravz", &listing).await.unwrap();
+        assert_eq!(page.objects[0].key, "a.json");
+        assert_eq!(page.common_prefixes, vec!["eod/"]);
+
+        listing.continuation_token = page.next_continuation_token;
+        let page = store.list("sravz", &listing).await.unwrap();
+        assert_eq!(page.common_prefixes, vec!["historical/"]);
+        assert_eq!(page.objects[0].key, "z.json");
+        assert_eq!(page.next_continuation_token, None);
+    }
+
+    #[tokio::test]
+    async fn test_local_keys_stay_inside_the_bucket() {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path());
+
+        let err = store.get("sravz", "../secrets").await.unwrap_err();
+        assert!(matches!(err, S3Error::Invalid(_)));
+        assert!(store.head("sravz", "/etc/passwd").await.is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::message_store::StoreError;
+use crate::models::{DataRef, Message};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/* Out-of-line storage for result payloads that are too large for a message: Mongo caps
+documents at 16 MB and every NSQ reply would carry the whole dataset. */
+#[async_trait]
+pub trait PayloadStore: Send + Sync {
+    // Recorded as DataRef.store
+    fn backend(&self) -> &'static str;
+    // Stores the bytes and returns the id to read them back with
+    async fn put(&self, name: &str, bytes: Vec<u8>) -> Result<String, StoreError>;
+    async fn get(&self, id: &str) -> Result<Vec<u8>, StoreError>;
+    // Deletes up to `limit` payloads stored before `before`, returns how many were deleted
+    async fn prune(&self, before: DateTime<Utc>, limit: usize) -> Result<usize, StoreError>;
+}
+
+/* Moves `d_o.data` to the payload store when its JSON is larger than `inline_limit_bytes`,
+leaving a `d_o.data_ref` to it. Returns whether the data was moved. */
+pub async fn offload(
+    store: &dyn PayloadStore,
+    message: &mut Message,
+    inline_limit_bytes: usize,
+) -> Result<bool, StoreError> {
+    let Some(d_o) = message.d_o.as_mut() else {
+        return Ok(false);
+    };
+    if d_o.data_ref.is_some() {
+        return Ok(false);
+    }
+    let bytes = serde_json::to_vec(&d_o.data).map_err(|err| StoreError::Corrupt(err.to_string()))?;
+    if bytes.len() <= inline_limit_bytes {
+        return Ok(false);
+    }
+    let size = bytes.len() as u64;
+    let id = store.put(&message.key, bytes).await?;
+    d_o.data = Value::Null;
+    d_o.data_ref = Some(DataRef {
+        store: store.backend().to_string(),
+        id,
+        size,
+    });
+    Ok(true)
+}
+
+/* Puts an out-of-line `d_o.data` back into the message, a no-op for inline data */
+pub async fn rehydrate(store: &dyn PayloadStore, message: &mut Message) -> Result<(), StoreError> {
+    let Some(d_o) = message.d_o.as_mut() else {
+        return Ok(());
+    };
+    let Some(data_ref) = d_o.data_ref.as_ref() else {
+        return Ok(());
+    };
+    if data_ref.store != store.backend() {
+        return Err(StoreError::Rejected(format!(
+            "payload {} is in {}, not {}",
+            data_ref.id,
+            data_ref.store,
+            store.backend()
+        )));
+    }
+    let bytes = store.get(&data_ref.id).await?;
+    d_o.data = serde_json::from_slice(&bytes).map_err(|err| StoreError::Corrupt(err.to_string()))?;
+    d_o.data_ref = None;
+    Ok(())
+}
+
+// Payload bytes by id with the time they were stored
+type Payloads = HashMap<String, (DateTime<Utc>, Vec<u8>)>;
+
+/* PayloadStore kept in process, for local runs and tests */
+#[derive(Default)]
+pub struct MemoryPayloadStore {
+    payloads: Mutex<Payloads>,
+    next_id: AtomicU64,
+}
+
+impl MemoryPayloadStore {
+    pub fn new() -> Self {
+        MemoryPayloadStore::default()
+    }
+}
+
+#[async_trait]
+impl PayloadStore for MemoryPayloadStore {
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn put(&self, name: &str, bytes: Vec<u8>) -> Result<String, StoreError> {
+        let id = format!("{}-{}", name, self.next_id.fetch_add(1, Ordering::SeqCst));
+        self.payloads
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (Utc::now(), bytes));
+        Ok(id)
+    }
+
+    async fn get(&self, id: &str) -> Result<Vec<u8>, StoreError> {
+        self.payloads
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|(_, bytes)| bytes.clone())
+            .ok_or_else(|| StoreError::Rejected(format!("payload {} not found", id)))
+    }
+
+    async fn prune(&self, before: DateTime<Utc>, limit: usize) -> Result<usize, StoreError> {
+        let mut payloads = self.payloads.lock().unwrap();
+        let expired: Vec<String> = payloads
+            .iter()
+            .filter(|(_, (stored_at, _))| *stored_at < before)
+            .map(|(id, _)| id.clone())
+            .take(limit)
+            .collect();
+        for id in &expired {
+            payloads.remove(id);
+        }
+        Ok(expired.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::DO;
+    use serde_json::json;
+
+    fn message(data: Value) -> Message {
+        Message {
+            key: "v1-key".to_string(),
+            d_o: Some(DO {
+                data,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_data_is_offloaded_and_rehydrated() {
+        let store = MemoryPayloadStore::new();
+        let data = json!({ "rows": vec![1.5; 100] });
+        let mut message = message(data.clone());
+
+        assert!(offload(&store, &mut message, 64).await.unwrap());
+        let d_o = message.d_o.as_ref().unwrap();
+        assert_eq!(d_o.data, Value::Null);
+        let data_ref = d_o.data_ref.clone().unwrap();
+        assert_eq!(data_ref.store, "memory");
+        assert_eq!(data_ref.size, serde_json::to_vec(&data).unwrap().len() as u64);
+        // Only the reference is serialized
+        assert!(!serde_json::to_string(&message).unwrap().contains("1.5"));
+
+        rehydrate(&store, &mut message).await.unwrap();
+        let d_o = message.d_o.unwrap();
+        assert_eq!(d_o.data, data);
+        assert_eq!(d_o.data_ref, None);
+    }
+
+    #[tokio::test]
+    async fn test_small_data_stays_inline() {
+        let store = MemoryPayloadStore::new();
+        let mut message = message(json!("small"));
+
+        assert!(!offload(&store, &mut message, 64).await.unwrap());
+        assert_eq!(message.d_o.as_ref().unwrap().data, json!("small"));
+        rehydrate(&store, &mut message).await.unwrap();
+        assert_eq!(message.d_o.unwrap().data, json!("small"));
+    }
+
+    #[tokio::test]
+    async fn test_prune_deletes_payloads_stored_before() {
+        let store = MemoryPayloadStore::new();
+        let id = store.put("v1-key", b"[]".to_vec()).await.unwrap();
+
+        assert_eq!(store.prune(Utc::now() - chrono::Duration::hours(1), 10).await.unwrap(), 0);
+        assert_eq!(store.prune(Utc::now() + chrono::Duration::seconds(1), 10).await.unwrap(), 1);
+        assert!(store.get(&id).await.is_err());
+    }
+}
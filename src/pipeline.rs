@@ -0,0 +1,1367 @@
+use crate::{
+    cache_key::cache_key,
+    cache_policy::CachePolicy,
+    config::AppConfig,
+    handler_registry::HandlerInfo,
+    message_bus::{Delivery, MessageBus},
+    message_store::{
+        AttemptOutcome, Claim, JobAttempt, MessageStore, StoreHealth, WipStatus, DONE, FAILED,
+        LEASE_EXPIRED,
+    },
+    models::Message,
+    payload_store::{self, PayloadStore},
+    progress::{Progress, ProgressPublisher},
+    retry::{self, RetryPolicy},
+    router::Router,
+};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// How long the result waits for progress events that are still being published
+const PROGRESS_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+// Expired leases handled per sweep
+const SWEEP_BATCH_SIZE: usize = 100;
+// exception_message of the error reply to a message whose body is not UTF-8
+const INVALID_UTF8: &str = "Message body is not valid UTF-8";
+
+#[derive(Debug)]
+pub enum SubmitError {
+    // Unknown message id or args the handler rejects
+    Invalid(String),
+    // The request could not be queued on the bus
+    Unavailable(String),
+}
+
+impl std::error::Error for SubmitError {}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmitError::Invalid(reason) => write!(f, "Invalid message: {}", reason),
+            SubmitError::Unavailable(reason) => write!(f, "Unable to queue message: {}", reason),
+        }
+    }
+}
+
+/* Where a submitted request stands */
+#[derive(Debug)]
+pub enum JobResult {
+    // Result from nsq_message_cache
+    Done(Box<Message>),
+    // Status from messages_wip, e.g. IN_PROGRESS or RETRY_SCHEDULED
+    Pending(String),
+    Unknown,
+}
+
+/* Consume -> dedupe -> process -> publish flow for a single message from the bus.
+Shared by all workers of the WorkerPool. */
+pub struct Pipeline {
+    config: Arc<AppConfig>,
+    router: Router,
+    retry_policy: RetryPolicy,
+    cache_policy: CachePolicy,
+    store: Arc<dyn MessageStore>,
+    // Large d_o.data is kept here instead of in the message, see [config.payloads]
+    payloads: Arc<dyn PayloadStore>,
+    bus: Arc<dyn MessageBus>,
+    // Identifies this process in the claims on messages_wip, hostname:pid
+    owner_prefix: String,
+    host: String,
+    // Messages marked IN_PROGRESS whose result has not been saved yet and their claim owner, by key
+    in_flight: std::sync::Mutex<HashMap<String, (Message, String)>>,
+}
+
+impl Pipeline {
+    pub fn new(
+        config: Arc<AppConfig>,
+        router: Router,
+        store: Arc<dyn MessageStore>,
+        payloads: Arc<dyn PayloadStore>,
+        bus: Arc<dyn MessageBus>,
+    ) -> Self {
+        let retry_policy = RetryPolicy::new(
+            config.config.retry.clone(),
+            config.config.backend_rust_topic.clone(),
+        );
+        let cache_policy = CachePolicy::new(config.config.cache.clone());
+        let host = gethostname::gethostname().to_string_lossy().to_string();
+        Pipeline {
+            config,
+            router,
+            retry_policy,
+            cache_policy,
+            store,
+            payloads,
+            bus,
+            owner_prefix: format!("{}:{}", host, std::process::id()),
+            host,
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn process_message(&self, delivery: Delivery) {
+        info!("Processing the message...");
+
+        let message_body = delivery.body.clone();
+        // Invalid bytes become U+FFFD so the sender can still be told, the message is rejected below
+        let message_body_str = String::from_utf8_lossy(&message_body);
+        let valid_utf8 = std::str::from_utf8(&message_body).is_ok();
+        info!("Message received {}", message_body_str);
+
+        let result: Result<Message, serde_json::Error> = serde_json::from_str(&message_body_str);
+
+        // Handle the result using pattern matching
+        match result {
+            Ok(mut _message) => {
+                // Empty out error message if the client send an error already
+                _message.e = "".to_string();
+                _message.exception_message = "".to_string();
+                _message.date = Utc::now();
+
+                let message_handler = self.router.handler(_message.id);
+                let handler = message_handler.map_or("unknown", |h| h.name());
+                let handler_cache_policy = message_handler
+                    .map(|h| h.cache_policy())
+                    .unwrap_or_default();
+                let ttl = self
+                    .cache_policy
+                    .ttl(handler, handler_cache_policy.default_ttl_secs);
+
+                // Get the versioned hash of the canonical request
+                let hashed_string = &self.request_key(&_message);
+
+                info!(
+                    "Message received on NSQ = {} - SHA-256 = {}",
+                    message_body_str, hashed_string
+                );
+
+                _message.key = hashed_string.to_string();
+
+                let validation = if valid_utf8 {
+                    self.router.validate(&_message)
+                } else {
+                    Err(INVALID_UTF8.to_string())
+                };
+                if let Err(reason) = validation {
+                    info!("Rejecting message with key {}: {}", hashed_string, reason);
+                    _message.e = "Error".to_string();
+                    _message.exception_message = reason;
+                    self.bus.ack(delivery).await;
+                    let message_body_str = serde_json::to_string(&_message)
+                        .expect("Failed to convert message to JSON string");
+                    self.publish(_message.t_o.clone(), &message_body_str).await;
+                    return;
+                }
+
+                let cached = if self.cache_policy.bypass_requested(&_message) {
+                    info!("Client asked to bypass the cache for key {}", hashed_string);
+                    None
+                } else {
+                    match self.store.find_by_key(hashed_string).await {
+                        Ok(cached) => cached,
+                        Err(err) => {
+                            error!("Unable to read cached message {}: {}", hashed_string, err);
+                            None
+                        }
+                    }
+                };
+
+                /* If the message exists and is within the handler's cache TTL - resend the same message else reprocess the message */
+                if let Some(mut message_from_router) = cached.filter(|cached| {
+                    self.cache_policy
+                        .can_reuse(&_message, ttl, cached, Utc::now())
+                }) {
+                    message_from_router.cid = _message.cid.clone();
+                    let message_from_router_json = &serde_json::to_string(&message_from_router)
+                        .expect("Failed to convert NSQ message to JSON string");
+
+                    info!(
+                        "Sending the existing message in mongodb {}",
+                        message_from_router_json
+                    );
+                    info!(
+                        "Routing skipped as the message was processed in last {} seconds",
+                        ttl.num_seconds()
+                    );
+                    self.bus.ack(delivery).await;
+                    info!("Sending the processed message on NSQ {}", message_from_router_json);
+                    self.publish(_message.t_o, message_from_router_json).await;
+                } else {
+                    // Clone the message so we have a reference in this routine
+                    let var_name = _message.clone();
+                    let mut original_message = var_name;
+                    original_message.key = hashed_string.to_string();
+
+                    // Claim the message before processing, unless another worker already has it in progress
+                    let owner = self.claim_owner();
+                    let claim = match self
+                        .store
+                        .claim(
+                            &original_message.key,
+                            &owner,
+                            &serde_json::to_string(&original_message).unwrap_or_default(),
+                            self.lease(),
+                        )
+                        .await
+                    {
+                        Ok(claim) => claim,
+                        Err(err) => {
+                            error!("Unable to claim key {}: {}", original_message.key, err);
+                            Claim::Acquired
+                        }
+                    };
+                    if let Claim::Owned(owner) = claim {
+                        self.wait_for_owner(delivery, original_message, &owner).await;
+                        return;
+                    }
+                    self.in_flight
+                        .lock()
+                        .unwrap()
+                        .insert(
+                            original_message.key.clone(),
+                            (original_message.clone(), owner.clone()),
+                        );
+                    // Keeps the lease alive until the result is saved, stops when dropped
+                    let heartbeat = self.start_heartbeat(&original_message.key, &owner);
+
+                    // Pass the original message to the router
+                    info!("Routing the message...");
+                    // Acknowledge the message from NSQ, failures are re-published by schedule_retry
+                    self.bus.ack(delivery).await;
+                    let (progress, progress_publisher) = if self.config.config.progress_events {
+                        Progress::start(self.bus.clone(), &_message)
+                    } else {
+                        (Progress::disabled(), ProgressPublisher::default())
+                    };
+                    progress.report("started", 0);
+                    let started_at = Utc::now();
+                    let router_result = self.router.process_message(_message, progress).await;
+                    progress_publisher.finish(PROGRESS_FLUSH_TIMEOUT).await;
+                    let outcome = router_result.as_ref().map_err(|err| err.to_string());
+                    self.record_attempt(&original_message, handler, started_at, outcome)
+                        .await;
+                    match router_result {
+                        Ok(processed_message) => {
+                            // Reassign processed message to the original message
+                            original_message = processed_message;
+                            self.offload(&mut original_message).await;
+                        }
+                        Err(err) => {
+                            error!("Router message processing error: {}", err);
+                            self.retry_policy
+                                .record_failure(&mut original_message, &err.to_string());
+                            // Permanent errors are answered at once, they would fail every attempt
+                            if retry::is_permanent(err.as_ref()) {
+                                info!("Not retrying key {}: {}", original_message.key, err);
+                            } else if self
+                                .retry_policy
+                                .should_retry(handler, original_message.attempts)
+                            {
+                                if self.schedule_retry(&original_message, &owner).await {
+                                    drop(heartbeat);
+                                    return;
+                                }
+                                // The message was acked, without the retry nothing would answer it
+                                self.dead_letter(handler, &original_message).await;
+                            } else {
+                                self.dead_letter(handler, &original_message).await;
+                            }
+                            // Update original message
+                            original_message.e = "Error".to_string();
+                            original_message.exception_message = err.to_string();
+                        }
+                    }
+
+                    // Save the message to mongodb cache
+                    info!("Saving the message to MongoDB");
+                    if let Err(err) = self.store.upsert(&original_message).await {
+                        error!(
+                            "Unable to save message with key {}: {}",
+                            original_message.key, err
+                        );
+                    }
+                    // Releases the claim, workers waiting on the key pick up the saved result
+                    drop(heartbeat);
+                    let status = if original_message.e.is_empty() { DONE } else { FAILED };
+                    self.release(
+                        &original_message.key,
+                        &owner,
+                        &serde_json::to_string(&original_message).unwrap_or_default(),
+                        &original_message.exception_message,
+                        status,
+                    )
+                    .await;
+                    self.in_flight.lock().unwrap().remove(&original_message.key);
+
+                    // Send the message to NSQ
+                    let message_body_str = serde_json::to_string(&original_message)
+                        .expect("Failed to convert message to JSON string");
+                    info!("Sending the message on NSQ {}", message_body_str);
+                    self.publish(original_message.t_o.clone(), &message_body_str).await;
+                }
+            }
+            Err(err) => {
+                error!("Deserialization failed: {}", err);
+                self.bus.ack(delivery).await;
+            }
+        }
+    }
+
+    /* Another worker owns the key: reply with its result once it is saved instead of processing
+    the message twice. Requeued when the owner retries, aborts, loses its lease or is still busy after claim.wait_secs. */
+    async fn wait_for_owner(&self, delivery: Delivery, message: Message, owner: &str) {
+        info!(
+            "Message with key {} is being processed by {}, waiting for its result",
+            message.key, owner
+        );
+        let claim_config = &self.config.config.claim;
+        let deadline = Instant::now() + Duration::from_secs(claim_config.wait_secs);
+        let poll_interval = Duration::from_millis(claim_config.poll_interval_ms);
+        while Instant::now() < deadline {
+            tokio::time::sleep(poll_interval).await;
+            let status = match self.store.status(&message.key).await {
+                Ok(Some(status)) => status,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Unable to read status of key {}: {}", message.key, err);
+                    continue;
+                }
+            };
+            if status.is_live(Utc::now()) {
+                continue;
+            }
+            // A job swept after its owner crashed has no result, a cached one would be stale
+            let finished = status.status == DONE
+                || (status.status == FAILED && status.exception_message != LEASE_EXPIRED);
+            if !finished {
+                break;
+            }
+            match self.store.find_by_key(&message.key).await {
+                Ok(Some(mut result)) => {
+                    result.cid = message.cid.clone();
+                    let result_str = serde_json::to_string(&result)
+                        .expect("Failed to convert message to JSON string");
+                    info!("Sending the result of owner {} on NSQ {}", owner, result_str);
+                    self.bus.ack(delivery).await;
+                    self.publish(message.t_o, &result_str).await;
+                    return;
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Unable to read cached message {}: {}", message.key, err);
+                    break;
+                }
+            }
+        }
+        info!("No result from owner {} for key {}, requeueing", owner, message.key);
+        self.bus.requeue(delivery, None).await;
+    }
+
+    /* Appends the attempt that just finished to job_attempts, failures are only logged */
+    async fn record_attempt(
+        &self,
+        request: &Message,
+        handler: &str,
+        started_at: DateTime<Utc>,
+        outcome: Result<&Message, String>,
+    ) {
+        let finished_at = Utc::now();
+        let (outcome, error, artifacts) = match outcome {
+            Ok(processed) => (
+                AttemptOutcome::Succeeded,
+                String::new(),
+                processed
+                    .d_o
+                    .iter()
+                    .map(|d_o| d_o.signed_url.clone())
+                    .filter(|url| !url.is_empty())
+                    .collect(),
+            ),
+            Err(err) => (AttemptOutcome::Failed, err, Vec::new()),
+        };
+        let attempt = JobAttempt {
+            key: request.key.clone(),
+            cid: request.cid.clone(),
+            message_id: request.id,
+            handler: handler.to_string(),
+            host: self.host.clone(),
+            attempt: request.attempts + 1,
+            started_at,
+            finished_at,
+            duration_ms: (finished_at - started_at).num_milliseconds().max(0) as u64,
+            outcome,
+            error,
+            artifacts,
+        };
+        if let Err(err) = self.store.record_attempt(&attempt).await {
+            error!("Unable to record attempt of key {}: {}", attempt.key, err);
+        }
+    }
+
+    /* Attempts of the key from job_attempts, oldest first */
+    pub async fn attempts(&self, key: &str) -> Result<Vec<JobAttempt>, String> {
+        self.store.attempts(key).await.map_err(|err| err.to_string())
+    }
+
+    /* Most recently updated jobs from messages_wip, newest first */
+    pub async fn recent_jobs(&self, limit: usize) -> Result<Vec<WipStatus>, String> {
+        self.store.history(limit).await.map_err(|err| err.to_string())
+    }
+
+    /* Failed attempts by handler over the last `window` */
+    pub async fn failure_counts(&self, window: Duration) -> Result<BTreeMap<String, u64>, String> {
+        let until = Utc::now();
+        let since = until - chrono::Duration::from_std(window).map_err(|err| err.to_string())?;
+        self.store
+            .failure_counts(since, until)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    /* Owner of one claim, hostname:pid:uuid. Unique per job so workers of this process never share
+    a claim, e.g. when NSQ redelivers a message that is still being processed. */
+    fn claim_owner(&self) -> String {
+        format!("{}:{}", self.owner_prefix, uuid::Uuid::new_v4())
+    }
+
+    fn lease(&self) -> Duration {
+        Duration::from_secs(self.config.config.lease.ttl_secs)
+    }
+
+    /* Renews this worker's lease on the key every lease.heartbeat_interval_secs */
+    fn start_heartbeat(&self, key: &str, owner: &str) -> Heartbeat {
+        let store = self.store.clone();
+        let key = key.to_string();
+        let owner = owner.to_string();
+        let lease = self.lease();
+        let interval = Duration::from_secs(self.config.config.lease.heartbeat_interval_secs.max(1));
+        Heartbeat(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match store.heartbeat(&key, &owner, lease).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("Lost the lease on key {}, another worker may process it too", key);
+                        return;
+                    }
+                    Err(err) => error!("Unable to renew the lease on key {}: {}", key, err),
+                }
+            }
+        }))
+    }
+
+    /* Marks jobs whose owner stopped heartbeating as FAILED and sends an error reply to their client.
+    Their messages were acknowledged when claimed, so nothing else would answer them. */
+    pub async fn fail_abandoned(&self) {
+        let abandoned = match self.store.fail_expired(LEASE_EXPIRED, SWEEP_BATCH_SIZE).await {
+            Ok(abandoned) => abandoned,
+            Err(err) => {
+                error!("Unable to sweep expired leases: {}", err);
+                return;
+            }
+        };
+        for status in abandoned {
+            error!(
+                "Message with key {} abandoned by {}, lease expired at {:?}",
+                status.key, status.owner, status.lease_expires
+            );
+            let Ok(mut message) = serde_json::from_str::<Message>(&status.msg) else {
+                continue;
+            };
+            message.e = "Error".to_string();
+            message.exception_message = LEASE_EXPIRED.to_string();
+            message.date = Utc::now();
+            let message_body_str = serde_json::to_string(&message)
+                .expect("Failed to convert message to JSON string");
+            self.publish(message.t_o.clone(), &message_body_str).await;
+        }
+    }
+
+    /* Runs fail_abandoned and prune_payloads every lease.sweep_interval_secs until shutdown */
+    pub async fn run_sweeper(&self, mut shutdown: watch::Receiver<bool>) {
+        let interval = Duration::from_secs(self.config.config.lease.sweep_interval_secs.max(1));
+        loop {
+            tokio::select! {
+                _ = async { let _ = shutdown.wait_for(|stop| *stop).await; } => break,
+                _ = tokio::time::sleep(interval) => {
+                    self.fail_abandoned().await;
+                    self.prune_payloads().await;
+                }
+            }
+        }
+    }
+
+    /* Moves a large d_o.data out of the message before it is saved and published.
+    On failure the data stays inline, the client still gets it. */
+    async fn offload(&self, message: &mut Message) {
+        let inline_limit_bytes = self.config.config.payloads.inline_limit_bytes;
+        match payload_store::offload(self.payloads.as_ref(), message, inline_limit_bytes).await {
+            Ok(true) => info!(
+                "Stored d_o.data of key {} out-of-line: {:?}",
+                message.key,
+                message.d_o.as_ref().and_then(|d_o| d_o.data_ref.as_ref())
+            ),
+            Ok(false) => {}
+            Err(err) => error!(
+                "Unable to store d_o.data of key {} out-of-line, sending it inline: {}",
+                message.key, err
+            ),
+        }
+    }
+
+    /* Loads an out-of-line d_o.data back into the message */
+    pub async fn rehydrate(&self, message: &mut Message) -> Result<(), String> {
+        payload_store::rehydrate(self.payloads.as_ref(), message)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    /* Deletes out-of-line payloads older than the cached results referencing them */
+    pub async fn prune_payloads(&self) {
+        let retention = self.config.config.mongo.cache_retention(&self.config.config.cache);
+        let before = Utc::now()
+            - chrono::Duration::from_std(retention).unwrap_or_else(|_| chrono::Duration::days(1));
+        match self.payloads.prune(before, SWEEP_BATCH_SIZE).await {
+            Ok(0) => {}
+            Ok(pruned) => info!("Pruned {} out-of-line payloads stored before {}", pruned, before),
+            Err(err) => error!("Unable to prune out-of-line payloads: {}", err),
+        }
+    }
+
+    /* Key the result of a request is cached under */
+    pub fn request_key(&self, message: &Message) -> String {
+        let arg_rules = self
+            .router
+            .handler(message.id)
+            .map(|h| h.cache_policy().arg_rules)
+            .unwrap_or_default();
+        cache_key(message, arg_rules)
+    }
+
+    /* Queues a request on the consumer topic, so it is processed like one published on NSQ.
+    Returns the key to poll the result with. */
+    pub async fn submit(&self, message: &Message) -> Result<String, SubmitError> {
+        self.router.validate(message).map_err(SubmitError::Invalid)?;
+        let key = self.request_key(message);
+        let message_body_str = serde_json::to_string(message)
+            .map_err(|err| SubmitError::Invalid(err.to_string()))?;
+        self.bus
+            .publish(&self.config.config.backend_rust_topic, message_body_str.as_bytes())
+            .await
+            .map_err(|err| SubmitError::Unavailable(err.to_string()))?;
+        info!("Submitted message with key {}", key);
+        Ok(key)
+    }
+
+    /* messages_wip status while the key is being processed under a live lease, otherwise the cached result with its d_o.data rehydrated */
+    pub async fn lookup(&self, key: &str) -> Result<JobResult, String> {
+        let status = self.store.status(key).await.map_err(|err| err.to_string())?;
+        // A job re-running under a live lease makes an older cached result stale
+        if let Some(status) = status.as_ref().filter(|status| status.is_live(Utc::now())) {
+            return Ok(JobResult::Pending(status.status.clone()));
+        }
+        if let Some(mut message) = self.store.find_by_key(key).await.map_err(|err| err.to_string())? {
+            self.rehydrate(&mut message).await?;
+            return Ok(JobResult::Done(Box::new(message)));
+        }
+        Ok(match status {
+            Some(status) => JobResult::Pending(status.status),
+            None => JobResult::Unknown,
+        })
+    }
+
+    pub fn store_health(&self) -> StoreHealth {
+        self.store.health()
+    }
+
+    pub fn handlers(&self) -> Vec<HandlerInfo> {
+        self.router.handlers()
+    }
+
+    /* Marks every message that did not finish before shutdown as ABORTED
+    and sends an error reply to its client */
+    pub async fn abort_in_flight(&self) {
+        let aborted: Vec<(Message, String)> = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, in_flight)| in_flight)
+            .collect();
+        for (mut message, owner) in aborted {
+            message.e = "Error".to_string();
+            message.exception_message =
+                "Processing aborted: the service shut down before the message finished".to_string();
+            message.date = Utc::now();
+            error!("Aborting in-flight message with key {}", message.key);
+            self.release(
+                &message.key,
+                &owner,
+                &serde_json::to_string(&message).unwrap_or_default(),
+                &message.exception_message,
+                "ABORTED",
+            )
+            .await;
+            let message_body_str = serde_json::to_string(&message)
+                .expect("Failed to convert message to JSON string");
+            self.publish(message.t_o.clone(), &message_body_str).await;
+        }
+    }
+
+    /* Re-publishes a failed message on the consumer topic after the backoff delay.
+    False when it could not be published, the caller then answers with the error instead. */
+    async fn schedule_retry(&self, message: &Message, owner: &str) -> bool {
+        let backoff = self.retry_policy.backoff(message.attempts);
+        info!(
+            "Retrying message with key {} in {:?} - attempt {} failed",
+            message.key, backoff, message.attempts
+        );
+        let message_body_str = serde_json::to_string(message)
+            .expect("Failed to convert message to JSON string");
+        self.release(
+            &message.key,
+            owner,
+            &message_body_str,
+            message.retry_errors.last().map(String::as_str).unwrap_or_default(),
+            "RETRY_SCHEDULED",
+        )
+        .await;
+        let scheduled = self
+            .publish_deferred(
+                self.config.config.backend_rust_topic.clone(),
+                &message_body_str,
+                backoff,
+            )
+            .await;
+        if scheduled {
+            self.in_flight.lock().unwrap().remove(&message.key);
+        } else {
+            error!("Unable to schedule a retry of key {}, replying with the error", message.key);
+        }
+        scheduled
+    }
+
+    /* Hands a message that used up its attempts to the dead-letter topic */
+    async fn dead_letter(&self, handler: &str, message: &Message) {
+        let dead_letter = self.retry_policy.dead_letter(handler, message);
+        let dead_letter_topic = self.retry_policy.dead_letter_topic();
+        error!(
+            "Message with key {} failed after {} attempts, sending to {}",
+            message.key, dead_letter.attempts, dead_letter_topic
+        );
+        let dead_letter_str = serde_json::to_string(&dead_letter)
+            .expect("Failed to convert dead letter to JSON string");
+        self.publish(dead_letter_topic, &dead_letter_str).await;
+    }
+
+    /* Final status of a job this worker claimed as `owner`. Status failures are logged,
+    processing carries on without messages_wip */
+    async fn release(
+        &self,
+        key: &str,
+        owner: &str,
+        msg: &str,
+        exception_message: &str,
+        status: &str,
+    ) {
+        match self
+            .store
+            .release(key, owner, msg, exception_message, status)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => info!(
+                "Key {} was claimed by another worker, leaving its status instead of {}",
+                key, status
+            ),
+            Err(err) => error!("Unable to set status {} of key {}: {}", status, key, err),
+        }
+    }
+
+    /* Publish failures are logged, the NSQ bus has already tried every nsqd host */
+    async fn publish(&self, topic: String, message_body_str: &str) {
+        // Requests submitted over HTTP may have no reply topic, their result is polled
+        if topic.is_empty() {
+            info!("No reply topic, result is only stored in MongoDB");
+            return;
+        }
+        if let Err(err) = self
+            .bus
+            .publish(&topic, message_body_str.as_bytes())
+            .await
+        {
+            error!("Unable to publish message on NSQ topic {}: {}", topic, err);
+        }
+    }
+
+    /* True once published, failures are logged */
+    async fn publish_deferred(
+        &self,
+        topic: String,
+        message_body_str: &str,
+        delay: Duration,
+    ) -> bool {
+        match self
+            .bus
+            .publish_deferred(&topic, message_body_str.as_bytes(), delay)
+            .await
+        {
+            Ok(()) => true,
+            Err(err) => {
+                error!("Unable to publish message on NSQ topic {}: {}", topic, err);
+                false
+            }
+        }
+    }
+}
+
+/* Lease renewal task of one job, aborted on drop */
+struct Heartbeat(JoinHandle<()>);
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+pub(crate) const TEST_TOPIC: &str = "test_backend-rust";
+
+/* Pipeline on an in-memory bus and store */
+#[cfg(test)]
+pub(crate) fn test_pipeline(
+    registry: crate::handler_registry::HandlerRegistry,
+    bus: Arc<dyn MessageBus>,
+    store: Arc<crate::message_store::MemoryStore>,
+) -> Pipeline {
+    let config = AppConfig::for_tests(&format!(
+        "backend_rust_topic = \"{}\"\n[claim]\nwait_secs = 1\npoll_interval_ms = 10\n[payloads]\ninline_limit_bytes = 1024\n[retry]\ninitial_backoff_ms = 10\nmax_backoff_ms = 20",
+        TEST_TOPIC
+    ));
+    Pipeline::new(
+        Arc::new(config),
+        Router::new(registry),
+        store,
+        Arc::new(crate::payload_store::MemoryPayloadStore::new()),
+        bus,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler_registry::{HandlerRegistry, MessageHandler};
+    use crate::message_bus::MemoryBus;
+    use crate::nsq_producer::PublishError;
+    use crate::message_store::MemoryStore;
+    use async_trait::async_trait;
+    use std::error::Error;
+    use std::ops::RangeInclusive;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    /* Counts calls and returns the message unchanged */
+    #[derive(Default)]
+    struct Echo {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MessageHandler for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn description(&self) -> &'static str {
+            "Returns the message unchanged"
+        }
+
+        fn message_ids(&self) -> RangeInclusive<f64> {
+            9.0..=9.009
+        }
+
+        async fn handle(
+            &self,
+            message: Message,
+            _progress: Progress,
+        ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(message)
+        }
+    }
+
+    struct Failing;
+
+    #[async_trait]
+    impl MessageHandler for Failing {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn description(&self) -> &'static str {
+            "Always fails"
+        }
+
+        fn message_ids(&self) -> RangeInclusive<f64> {
+            8.0..=8.009
+        }
+
+        async fn handle(
+            &self,
+            _message: Message,
+            _progress: Progress,
+        ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+            Err("S3 timeout".into())
+        }
+    }
+
+    /* MemoryBus that can not publish with a delay, as when every nsqd rejects the retry */
+    struct NoDeferredBus(Arc<MemoryBus>);
+
+    #[async_trait]
+    impl MessageBus for NoDeferredBus {
+        async fn consume(&self) -> Option<Delivery> {
+            self.0.consume().await
+        }
+
+        async fn ack(&self, delivery: Delivery) {
+            self.0.ack(delivery).await
+        }
+
+        async fn requeue(&self, delivery: Delivery, delay: Option<Duration>) {
+            self.0.requeue(delivery, delay).await
+        }
+
+        async fn publish(&self, topic: &str, body: &[u8]) -> Result<(), PublishError> {
+            self.0.publish(topic, body).await
+        }
+
+        async fn publish_deferred(
+            &self,
+            topic: &str,
+            body: &[u8],
+            delay: Duration,
+        ) -> Result<(), PublishError> {
+            if !delay.is_zero() {
+                return Err(PublishError::NoHosts);
+            }
+            self.0.publish_deferred(topic, body, delay).await
+        }
+    }
+
+    /* Fails with an error that no retry can fix */
+    struct Rejecting;
+
+    #[async_trait]
+    impl MessageHandler for Rejecting {
+        fn name(&self) -> &'static str {
+            "rejecting"
+        }
+
+        fn description(&self) -> &'static str {
+            "Always fails permanently"
+        }
+
+        fn message_ids(&self) -> RangeInclusive<f64> {
+            6.0..=6.009
+        }
+
+        async fn handle(
+            &self,
+            _message: Message,
+            _progress: Progress,
+        ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+            Err(Box::new(crate::handler_registry::PermanentError(
+                "Unknown sravz_id stk_us_x".to_string(),
+            )))
+        }
+    }
+
+    /* Counts calls and takes a while, so concurrent deliveries overlap */
+    #[derive(Default)]
+    struct Slow {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MessageHandler for Slow {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn description(&self) -> &'static str {
+            "Returns the message unchanged after 100 ms"
+        }
+
+        fn message_ids(&self) -> RangeInclusive<f64> {
+            7.0..=7.009
+        }
+
+        async fn handle(
+            &self,
+            message: Message,
+            _progress: Progress,
+        ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(message)
+        }
+    }
+
+    async fn pipeline(bus: Arc<MemoryBus>) -> Pipeline {
+        test_pipeline(HandlerRegistry::new(), bus, Arc::new(MemoryStore::new()))
+    }
+
+    #[tokio::test]
+    async fn test_unknown_message_id_gets_error_reply() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let pipeline = pipeline(bus.clone()).await;
+        let request = Message {
+            id: 42.0,
+            t_o: "client".to_string(),
+            cid: "c1".to_string(),
+            cache_message: true,
+            ..Default::default()
+        };
+        bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+            .await
+            .unwrap();
+
+        pipeline.process_message(bus.consume().await.unwrap()).await;
+
+        let replies = bus.published("client");
+        assert_eq!(replies.len(), 1);
+        let reply: Message = serde_json::from_slice(&replies[0]).unwrap();
+        assert_eq!(reply.e, "Error");
+        assert_eq!(reply.exception_message, "Message ID not implemented");
+        assert_eq!(reply.cid, "c1");
+        assert!(reply.key.starts_with("v2-"));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_message_is_dropped() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let pipeline = pipeline(bus.clone()).await;
+        bus.publish(TEST_TOPIC, b"not json").await.unwrap();
+
+        pipeline.process_message(bus.consume().await.unwrap()).await;
+
+        assert_eq!(bus.published(TEST_TOPIC).len(), 1);
+        let redelivered = tokio::time::timeout(Duration::from_millis(50), bus.consume()).await;
+        assert!(redelivered.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_message_gets_error_reply() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Echo::default())).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), Arc::new(MemoryStore::new()));
+        let request = Message {
+            id: 9.0,
+            t_o: "client".to_string(),
+            cid: "c1".to_string(),
+            ..Default::default()
+        };
+        // Valid JSON with a stray 0xff byte in the cid
+        let json = serde_json::to_string(&request).unwrap();
+        let (head, tail) = json.split_once("\"c1\"").unwrap();
+        let body = [head.as_bytes(), b"\"c1\xff\"", tail.as_bytes()].concat();
+        bus.publish(TEST_TOPIC, &body).await.unwrap();
+        bus.publish(TEST_TOPIC, b"\xff\xfe").await.unwrap();
+
+        pipeline.process_message(bus.consume().await.unwrap()).await;
+        pipeline.process_message(bus.consume().await.unwrap()).await;
+
+        let replies = bus.published("client");
+        assert_eq!(replies.len(), 1);
+        let reply: Message = serde_json::from_slice(&replies[0]).unwrap();
+        assert_eq!((reply.e.as_str(), reply.exception_message.as_str()), ("Error", INVALID_UTF8));
+        assert_eq!(reply.cid, "c1\u{fffd}");
+        let redelivered = tokio::time::timeout(Duration::from_millis(50), bus.consume()).await;
+        assert!(redelivered.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_result_is_stored_and_reused() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let store = Arc::new(MemoryStore::new());
+        let echo = Arc::new(Echo::default());
+        let mut registry = HandlerRegistry::new();
+        registry.register(echo.clone()).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), store.clone());
+        let request = Message {
+            id: 9.0,
+            t_o: "client".to_string(),
+            cache_message: true,
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+                .await
+                .unwrap();
+            pipeline.process_message(bus.consume().await.unwrap()).await;
+        }
+
+        assert_eq!(echo.calls.load(Ordering::SeqCst), 1);
+        let key = pipeline.request_key(&request);
+        assert_eq!(store.find_by_key(&key).await.unwrap().unwrap().key, key);
+        let attempts = pipeline.attempts(&key).await.unwrap();
+        assert_eq!(attempts.len(), 1);
+        assert_eq!((attempts[0].handler.as_str(), attempts[0].attempt), ("echo", 1));
+        assert_eq!(attempts[0].outcome, AttemptOutcome::Succeeded);
+        let replies: Vec<Message> = bus
+            .published("client")
+            .iter()
+            .filter(|body| serde_json::from_slice::<crate::progress::ProgressEvent>(body).is_err())
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 2);
+        assert!(replies.iter().all(|reply| reply.key == key && reply.e.is_empty()));
+        assert!(matches!(pipeline.lookup(&key).await, Ok(JobResult::Done(_))));
+
+        // Re-run by another replica, e.g. after the cached result expired
+        store.claim(&key, "other-replica", "{}", LEASE).await.unwrap();
+        assert!(matches!(
+            pipeline.lookup(&key).await,
+            Ok(JobResult::Pending(status)) if status == crate::message_store::IN_PROGRESS
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_message_owned_elsewhere_gets_the_owners_result() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let store = Arc::new(MemoryStore::new());
+        let echo = Arc::new(Echo::default());
+        let mut registry = HandlerRegistry::new();
+        registry.register(echo.clone()).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), store.clone());
+        let request = Message {
+            id: 9.0,
+            t_o: "client".to_string(),
+            cid: "c2".to_string(),
+            cache_message: true,
+            ..Default::default()
+        };
+        let key = pipeline.request_key(&request);
+        store.claim(&key, "other-replica", "{}", LEASE).await.unwrap();
+        bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+            .await
+            .unwrap();
+        let delivery = bus.consume().await.unwrap();
+
+        let owner = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let result = Message {
+                key: key.clone(),
+                cid: "c1".to_string(),
+                stopic: "owner result".to_string(),
+                ..Default::default()
+            };
+            store.upsert(&result).await.unwrap();
+            store.release(&key, "other-replica", "{}", "", DONE).await.unwrap();
+        };
+        tokio::join!(pipeline.process_message(delivery), owner);
+
+        assert_eq!(echo.calls.load(Ordering::SeqCst), 0);
+        let replies = bus.published("client");
+        assert_eq!(replies.len(), 1);
+        let reply: Message = serde_json::from_slice(&replies[0]).unwrap();
+        assert_eq!(reply.stopic, "owner result");
+        assert_eq!(reply.cid, "c2");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_deliveries_in_one_process_run_the_handler_once() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let slow = Arc::new(Slow::default());
+        let mut registry = HandlerRegistry::new();
+        registry.register(slow.clone()).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), Arc::new(MemoryStore::new()));
+        let request = Message {
+            id: 7.0,
+            t_o: "client".to_string(),
+            cache_message: true,
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+                .await
+                .unwrap();
+        }
+        let (first, second) = (bus.consume().await.unwrap(), bus.consume().await.unwrap());
+
+        tokio::join!(pipeline.process_message(first), pipeline.process_message(second));
+
+        assert_eq!(slow.calls.load(Ordering::SeqCst), 1);
+        let replies: Vec<Message> = bus
+            .published("client")
+            .iter()
+            .filter(|body| serde_json::from_slice::<crate::progress::ProgressEvent>(body).is_err())
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 2);
+        assert!(replies.iter().all(|reply| reply.e.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_message_owned_elsewhere_is_requeued_when_owner_is_busy() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let store = Arc::new(MemoryStore::new());
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Echo::default())).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), store.clone());
+        let request = Message {
+            id: 9.0,
+            t_o: "client".to_string(),
+            cache_message: true,
+            ..Default::default()
+        };
+        store
+            .claim(&pipeline.request_key(&request), "other-replica", "{}", LEASE)
+            .await
+            .unwrap();
+        bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+            .await
+            .unwrap();
+
+        pipeline.process_message(bus.consume().await.unwrap()).await;
+
+        assert!(bus.published("client").is_empty());
+        let redelivery = tokio::time::timeout(Duration::from_secs(3), bus.consume())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(redelivery.attempt, 2);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_job_is_failed_and_its_client_answered() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let store = Arc::new(MemoryStore::new());
+        let pipeline = test_pipeline(HandlerRegistry::new(), bus.clone(), store.clone());
+        let request = Message {
+            id: 9.0,
+            t_o: "client".to_string(),
+            cid: "c1".to_string(),
+            key: "v1-abandoned".to_string(),
+            ..Default::default()
+        };
+        store
+            .claim(
+                &request.key,
+                "crashed-host:1",
+                &serde_json::to_string(&request).unwrap(),
+                Duration::ZERO,
+            )
+            .await
+            .unwrap();
+
+        pipeline.fail_abandoned().await;
+
+        let status = store.status(&request.key).await.unwrap().unwrap();
+        assert_eq!(status.status, FAILED);
+        let replies = bus.published("client");
+        assert_eq!(replies.len(), 1);
+        let reply: Message = serde_json::from_slice(&replies[0]).unwrap();
+        assert_eq!((reply.e.as_str(), reply.cid.as_str()), ("Error", "c1"));
+        assert_eq!(reply.exception_message, LEASE_EXPIRED);
+        // The expired claim is reclaimed by the next request
+        assert_eq!(
+            store.claim(&request.key, "host:2", "{}", LEASE).await.unwrap(),
+            Claim::Acquired
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_attempt_is_recorded_and_counted() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Failing)).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), Arc::new(MemoryStore::new()));
+        let request = Message {
+            id: 8.0,
+            t_o: "client".to_string(),
+            cid: "c1".to_string(),
+            ..Default::default()
+        };
+        bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+            .await
+            .unwrap();
+
+        pipeline.process_message(bus.consume().await.unwrap()).await;
+
+        let attempts = pipeline.attempts(&pipeline.request_key(&request)).await.unwrap();
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(attempts[0].outcome, AttemptOutcome::Failed);
+        assert_eq!((attempts[0].error.as_str(), attempts[0].cid.as_str()), ("S3 timeout", "c1"));
+        let counts = pipeline.failure_counts(Duration::from_secs(60)).await.unwrap();
+        assert_eq!(counts.get("failing"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_failing_message_is_retried_then_dead_lettered() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Failing)).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), Arc::new(MemoryStore::new()));
+        let request = Message {
+            id: 8.0,
+            t_o: "client".to_string(),
+            cid: "c1".to_string(),
+            ..Default::default()
+        };
+        bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+            .await
+            .unwrap();
+
+        // Default of three attempts, each one re-published after the backoff
+        for _ in 0..3 {
+            let delivery = tokio::time::timeout(Duration::from_secs(1), bus.consume())
+                .await
+                .unwrap()
+                .unwrap();
+            pipeline.process_message(delivery).await;
+        }
+
+        let retries: Vec<Message> = bus.published(TEST_TOPIC)[1..]
+            .iter()
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(
+            retries.iter().map(|retry| retry.attempts).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(retries[1].retry_errors.len(), 2);
+        let dead_letters = bus.published(&format!("{}-dead-letter", TEST_TOPIC));
+        assert_eq!(dead_letters.len(), 1);
+        let dead_letter: serde_json::Value = serde_json::from_slice(&dead_letters[0]).unwrap();
+        assert_eq!(dead_letter["handler"], "failing");
+        assert_eq!(dead_letter["attempts"], 3);
+        assert_eq!(dead_letter["errors"][2], "attempt 3: S3 timeout");
+        let payload: Message = serde_json::from_value(dead_letter["payload"].clone()).unwrap();
+        assert_eq!((payload.cid.as_str(), payload.attempts), ("c1", 0));
+        // Apart from progress events the client only hears about the last attempt
+        let replies: Vec<Message> = bus
+            .published("client")
+            .iter()
+            .filter(|body| serde_json::from_slice::<crate::progress::ProgressEvent>(body).is_err())
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 1);
+        let reply = &replies[0];
+        assert_eq!((reply.e.as_str(), reply.exception_message.as_str()), ("Error", "S3 timeout"));
+    }
+
+    #[tokio::test]
+    async fn test_retry_that_can_not_be_published_is_dead_lettered_and_answered() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let store = Arc::new(MemoryStore::new());
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Failing)).unwrap();
+        let pipeline =
+            test_pipeline(registry, Arc::new(NoDeferredBus(bus.clone())), store.clone());
+        let request = Message {
+            id: 8.0,
+            t_o: "client".to_string(),
+            ..Default::default()
+        };
+        let key = pipeline.request_key(&request);
+        bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+            .await
+            .unwrap();
+        let delivery = bus.consume().await.unwrap();
+        pipeline.process_message(delivery).await;
+
+        assert_eq!(bus.published(TEST_TOPIC).len(), 1);
+        let dead_letters = bus.published(&format!("{}-dead-letter", TEST_TOPIC));
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(store.status(&key).await.unwrap().unwrap().status, FAILED);
+        let replies: Vec<Message> = bus
+            .published("client")
+            .iter()
+            .filter(|body| serde_json::from_slice::<crate::progress::ProgressEvent>(body).is_err())
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 1);
+        assert_eq!((replies[0].e.as_str(), replies[0].attempts), ("Error", 1));
+    }
+
+    #[tokio::test]
+    async fn test_permanent_error_is_answered_without_retrying() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let store = Arc::new(MemoryStore::new());
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Rejecting)).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), store.clone());
+        let request = Message {
+            id: 6.0,
+            t_o: "client".to_string(),
+            ..Default::default()
+        };
+        let key = pipeline.request_key(&request);
+        bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+            .await
+            .unwrap();
+        let delivery = bus.consume().await.unwrap();
+        pipeline.process_message(delivery).await;
+
+        // Neither re-published nor dead-lettered
+        assert_eq!(bus.published(TEST_TOPIC).len(), 1);
+        assert!(bus.published(&format!("{}-dead-letter", TEST_TOPIC)).is_empty());
+        assert_eq!(store.status(&key).await.unwrap().unwrap().status, FAILED);
+        let replies: Vec<Message> = bus
+            .published("client")
+            .iter()
+            .filter(|body| serde_json::from_slice::<crate::progress::ProgressEvent>(body).is_err())
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].exception_message, "Unknown sravz_id stk_us_x");
+    }
+
+    #[tokio::test]
+    async fn test_large_result_is_sent_by_reference_and_rehydrated_on_lookup() {
+        let bus = Arc::new(MemoryBus::new(TEST_TOPIC));
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Echo::default())).unwrap();
+        let pipeline = test_pipeline(registry, bus.clone(), Arc::new(MemoryStore::new()));
+        let data = serde_json::json!({ "rows": vec![1.5; 1000] });
+        let request = Message {
+            id: 9.0,
+            t_o: "client".to_string(),
+            d_o: Some(crate::models::DO {
+                data: data.clone(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        bus.publish(TEST_TOPIC, serde_json::to_string(&request).unwrap().as_bytes())
+            .await
+            .unwrap();
+
+        pipeline.process_message(bus.consume().await.unwrap()).await;
+
+        let reply: Message = bus
+            .published("client")
+            .iter()
+            .filter(|body| serde_json::from_slice::<crate::progress::ProgressEvent>(body).is_err())
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .next()
+            .unwrap();
+        let d_o = reply.d_o.unwrap();
+        assert_eq!(d_o.data, serde_json::Value::Null);
+        assert_eq!(d_o.data_ref.unwrap().store, "memory");
+        let Ok(JobResult::Done(result)) = pipeline.lookup(&reply.key).await else {
+            panic!("result not found");
+        };
+        assert_eq!(result.d_o.unwrap().data, data);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::message_bus::MessageBus;
+use crate::models::Message;
+use chrono::Utc;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub const PROGRESS_EVENT_TYPE: &str = "progress";
+
+/* Published on the message's t_o topic while a job runs. `type` tells it apart
+from the result Message that is published on the same topic at the end. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProgressEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub id: f64,
+    pub key: String,
+    pub cid: String,
+    pub stage: String,
+    // 0 to 100
+    pub percent: u8,
+    pub date: String,
+}
+
+/* Handle a handler reports its stages through. Cheap to clone and usable from
+blocking threads such as the Python bridge, events are published in order by a
+background task. */
+#[derive(Clone)]
+pub struct Progress {
+    sender: Option<mpsc::UnboundedSender<ProgressEvent>>,
+    id: f64,
+    key: String,
+    cid: String,
+}
+
+/* Publishes the events of one job, see Progress::start */
+#[derive(Default)]
+pub struct ProgressPublisher {
+    task: Option<JoinHandle<()>>,
+}
+
+impl Progress {
+    /* Reports go nowhere, for callers without a reply topic */
+    pub fn disabled() -> Self {
+        Progress {
+            sender: None,
+            id: 0.0,
+            key: String::new(),
+            cid: String::new(),
+        }
+    }
+
+    pub fn start(bus: Arc<dyn MessageBus>, message: &Message) -> (Progress, ProgressPublisher) {
+        if message.t_o.is_empty() {
+            return (Progress::disabled(), ProgressPublisher::default());
+        }
+        let (sender, mut receiver) = mpsc::unbounded_channel::<ProgressEvent>();
+        let topic = message.t_o.clone();
+        let task = tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let body = match serde_json::to_string(&event) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        warn!("Unable to serialize progress event: {}", err);
+                        continue;
+                    }
+                };
+                if let Err(err) = bus.publish(&topic, body.as_bytes()).await {
+                    warn!("Unable to publish progress on NSQ topic {}: {}", topic, err);
+                }
+            }
+        });
+        let progress = Progress {
+            sender: Some(sender),
+            id: message.id,
+            key: message.key.clone(),
+            cid: message.cid.clone(),
+        };
+        (progress, ProgressPublisher { task: Some(task) })
+    }
+
+    pub fn report(&self, stage: &str, percent: u8) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let percent = percent.min(100);
+        info!("Progress of key {}: {} {}%", self.key, stage, percent);
+        let _ = sender.send(ProgressEvent {
+            event_type: PROGRESS_EVENT_TYPE.to_string(),
+            id: self.id,
+            key: self.key.clone(),
+            cid: self.cid.clone(),
+            stage: stage.to_string(),
+            percent,
+            date: Utc::now().to_rfc3339(),
+        });
+    }
+}
+
+impl ProgressPublisher {
+    /* Waits until every event is published, so none arrives after the result.
+    All Progress clones must be dropped first; a leaked clone is given up on after `timeout`. */
+    pub async fn finish(self, timeout: Duration) {
+        if let Some(task) = self.task {
+            if tokio::time::timeout(timeout, task).await.is_err() {
+                warn!("Progress events still pending after {:?}", timeout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_bus::MemoryBus;
+
+    #[tokio::test]
+    async fn test_progress_is_published_on_reply_topic_in_order() {
+        let bus = Arc::new(MemoryBus::new("backend-rust"));
+        let message = Message {
+            id: 1.0,
+            t_o: "client".to_string(),
+            cid: "c1".to_string(),
+            key: "v1-key".to_string(),
+            ..Default::default()
+        };
+        let (progress, publisher) = Progress::start(bus.clone(), &message);
+        let python = progress.clone();
+        std::thread::spawn(move || python.report("garch_fit", 50))
+            .join()
+            .unwrap();
+        progress.report("uploading", 120);
+        drop(progress);
+        publisher.finish(Duration::from_secs(1)).await;
+
+        let events: Vec<ProgressEvent> = bus
+            .published("client")
+            .iter()
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event_type, "progress");
+        assert_eq!((events[0].stage.as_str(), events[0].percent), ("garch_fit", 50));
+        assert_eq!((events[1].stage.as_str(), events[1].percent), ("uploading", 100));
+        assert_eq!((events[1].key.as_str(), events[1].cid.as_str()), ("v1-key", "c1"));
+    }
+
+    #[tokio::test]
+    async fn test_progress_without_reply_topic_is_dropped() {
+        let bus = Arc::new(MemoryBus::new("backend-rust"));
+        let (progress, publisher) = Progress::start(bus.clone(), &Message::default());
+        progress.report("started", 0);
+        drop(progress);
+        publisher.finish(Duration::from_secs(1)).await;
+        assert!(bus.published("").is_empty());
+    }
+}
@@ -1,9 +1,11 @@
 pub(crate) use log::{error, info};
 use polars::io::json;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
 use pyo3::types::PyTuple;
 use std::io;
+use crate::progress::Progress;
 
 #[pyclass]
 #[derive(Debug, Default, FromPyObject)]
@@ -51,7 +53,37 @@ impl PyMessage {
     }
 }
 
-pub(crate) fn run_py_module(py_message: PyMessage) -> Result<PyMessage, Box<std::io::Error>> {
+/* Passed to the Python module as `progress`, called as progress(stage, percent) */
+#[pyclass]
+pub(crate) struct PyProgress {
+    progress: Progress,
+}
+
+#[pymethods]
+impl PyProgress {
+    fn __call__(&self, stage: &str, percent: u8) {
+        self.progress.report(stage, percent);
+    }
+}
+
+/* Runs the Python module on the blocking thread pool so a long Python job does not stall other workers */
+pub(crate) async fn spawn_py_module(
+    py_message: PyMessage,
+    progress: Progress,
+) -> Result<PyMessage, Box<std::io::Error>> {
+    match tokio::task::spawn_blocking(move || run_py_module(py_message, progress)).await {
+        Ok(result) => result,
+        Err(err) => Err(Box::new(io::Error::other(format!(
+            "Python task failed: {}",
+            err
+        )))),
+    }
+}
+
+pub(crate) fn run_py_module(
+    py_message: PyMessage,
+    progress: Progress,
+) -> Result<PyMessage, Box<std::io::Error>> {
     Python::with_gil(|py| {
         let activators = PyModule::from_code(
             py,
@@ -63,10 +95,10 @@ sys.path.append("/workspace/backend-rust/src/sravz_rust_py")
 sys.path.append("/app/src/")     
 sys.path.append("/app/src/sravz_rust_py")     
 os.environ['MPLCONFIGDIR'] = "/tmp/matplotlib/"
-def run(py_message, slope=0.01):
+def run(py_message, progress=None, slope=0.01):
     try:
         from main import run
-        return run(py_message)    
+        return run(py_message, progress)
     except Exception as e:  # pylint: disable=broad-except
         print("Error occurred: %s", e)
         raise e
@@ -88,6 +120,18 @@ def run(py_message, slope=0.01):
                             ],
                         );
                         let kwargs = [("slope", 0.2)].into_py_dict(py);
+                        let py_progress = match Py::new(py, PyProgress { progress }) {
+                            Ok(py_progress) => py_progress,
+                            Err(err) => {
+                                return Err(Box::new(io::Error::other(format!(
+                                    "Unable to create progress callback: {}",
+                                    err
+                                ))));
+                            }
+                        };
+                        if let Err(err) = kwargs.set_item("progress", py_progress) {
+                            error!("Unable to pass progress callback to Python: {}", err);
+                        }
                         let rust_result = py_result.call(args, Some(kwargs));
                         match rust_result {
                             Ok(result) => {
@@ -107,6 +151,13 @@ def run(py_message, slope=0.01):
                             Err(err) => {
                                 // Handle the Python error
                                 error!("Python Error: {}", err);
+                                // The request itself is invalid, another attempt fails the same way
+                                if err.is_instance_of::<PyValueError>(py) {
+                                    return Err(Box::new(io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        format!("Service error: {}", err),
+                                    )));
+                                }
 
                                 // Access the traceback if available
                                 if let Some(traceback) = PyErr::fetch(py).traceback(py) {
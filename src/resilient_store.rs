@@ -0,0 +1,350 @@
+use crate::config::StoreConfig;
+use crate::message_store::{Claim, JobAttempt, MessageStore, StoreError, StoreHealth, WipStatus};
+use crate::models::Message;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/* Wraps a store with bounded retries of transient errors. Once retries are used up the store
+is degraded: calls fail fast, so messages are processed without the cache and dedupe, and one
+call every probe_interval_secs goes through to find out whether the store is back. */
+pub struct ResilientStore {
+    inner: Arc<dyn MessageStore>,
+    config: StoreConfig,
+    state: Mutex<DegradedState>,
+}
+
+#[derive(Default)]
+struct DegradedState {
+    since: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    last_probe: Option<Instant>,
+}
+
+impl ResilientStore {
+    pub fn new(inner: Arc<dyn MessageStore>, config: StoreConfig) -> Self {
+        ResilientStore {
+            inner,
+            config,
+            state: Mutex::new(DegradedState::default()),
+        }
+    }
+
+    /* Tries of an operation that is safe to repeat */
+    fn tries(&self) -> u32 {
+        self.config.retry_attempts + 1
+    }
+
+    /* False while degraded, except for one probe per probe interval */
+    fn admit(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.since.is_none() {
+            return true;
+        }
+        let probe_interval = Duration::from_secs(self.config.probe_interval_secs);
+        if state
+            .last_probe
+            .is_some_and(|last_probe| last_probe.elapsed() < probe_interval)
+        {
+            return false;
+        }
+        state.last_probe = Some(Instant::now());
+        true
+    }
+
+    fn mark_healthy(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(since) = state.since.take() {
+            info!(
+                "Store recovered after {}s, cache and dedupe are back",
+                (Utc::now() - since).num_seconds()
+            );
+        }
+        state.last_error = None;
+        state.last_probe = None;
+    }
+
+    fn mark_degraded(&self, err: &StoreError) {
+        let mut state = self.state.lock().unwrap();
+        if state.since.is_none() {
+            warn!(
+                "Store degraded, processing messages without cache and dedupe: {}",
+                err
+            );
+            state.since = Some(Utc::now());
+            state.last_probe = Some(Instant::now());
+        }
+        state.last_error = Some(err.to_string());
+    }
+
+    async fn call<T, F, Fut>(&self, operation: &str, tries: u32, f: F) -> Result<T, StoreError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, StoreError>>,
+    {
+        if !self.admit() {
+            return Err(StoreError::Unavailable(format!(
+                "{} skipped while the store is degraded",
+                operation
+            )));
+        }
+        // A probe is tried once, the store just failed
+        let tries = if self.health().degraded { 1 } else { tries.max(1) };
+        let mut backoff = Duration::from_millis(self.config.retry_backoff_ms);
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(value) => {
+                    self.mark_healthy();
+                    return Ok(value);
+                }
+                Err(err) if err.is_transient() && attempt < tries => {
+                    warn!(
+                        "Store {} failed, try {} of {} in {:?}: {}",
+                        operation, attempt, tries, backoff, err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(err) if err.is_transient() => {
+                    self.mark_degraded(&err);
+                    return Err(err);
+                }
+                // The store answered, so it is reachable
+                Err(err) => {
+                    self.mark_healthy();
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MessageStore for ResilientStore {
+    fn health(&self) -> StoreHealth {
+        let state = self.state.lock().unwrap();
+        StoreHealth {
+            degraded: state.since.is_some(),
+            degraded_since: state.since,
+            last_error: state.last_error.clone(),
+        }
+    }
+
+    async fn find_by_key(&self, key: &str) -> Result<Option<Message>, StoreError> {
+        self.call("find_by_key", self.tries(), || self.inner.find_by_key(key))
+            .await
+    }
+
+    async fn upsert(&self, message: &Message) -> Result<(), StoreError> {
+        self.call("upsert", self.tries(), || self.inner.upsert(message))
+            .await
+    }
+
+    async fn release(
+        &self,
+        key: &str,
+        owner: &str,
+        msg: &str,
+        exception_message: &str,
+        status: &str,
+    ) -> Result<bool, StoreError> {
+        self.call("release", self.tries(), || {
+            self.inner.release(key, owner, msg, exception_message, status)
+        })
+        .await
+    }
+
+    async fn claim(
+        &self,
+        key: &str,
+        owner: &str,
+        msg: &str,
+        lease: Duration,
+    ) -> Result<Claim, StoreError> {
+        self.call("claim", self.tries(), || {
+            self.inner.claim(key, owner, msg, lease)
+        })
+        .await
+    }
+
+    async fn heartbeat(&self, key: &str, owner: &str, lease: Duration) -> Result<bool, StoreError> {
+        self.call("heartbeat", self.tries(), || {
+            self.inner.heartbeat(key, owner, lease)
+        })
+        .await
+    }
+
+    async fn fail_expired(
+        &self,
+        exception_message: &str,
+        limit: usize,
+    ) -> Result<Vec<WipStatus>, StoreError> {
+        self.call("fail_expired", self.tries(), || {
+            self.inner.fail_expired(exception_message, limit)
+        })
+        .await
+    }
+
+    async fn status(&self, key: &str) -> Result<Option<WipStatus>, StoreError> {
+        self.call("status", self.tries(), || self.inner.status(key))
+            .await
+    }
+
+    async fn history(&self, limit: usize) -> Result<Vec<WipStatus>, StoreError> {
+        self.call("history", self.tries(), || self.inner.history(limit))
+            .await
+    }
+
+    async fn record_attempt(&self, attempt: &JobAttempt) -> Result<(), StoreError> {
+        // Not retried, a retry after a lost reply would append the attempt twice
+        self.call("record_attempt", 1, || self.inner.record_attempt(attempt))
+            .await
+    }
+
+    async fn attempts(&self, key: &str) -> Result<Vec<JobAttempt>, StoreError> {
+        self.call("attempts", self.tries(), || self.inner.attempts(key))
+            .await
+    }
+
+    async fn failure_counts(
+        &self,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<BTreeMap<String, u64>, StoreError> {
+        self.call("failure_counts", self.tries(), || {
+            self.inner.failure_counts(since, until)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_store::MemoryStore;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /* MemoryStore whose find_by_key fails while `down` is set */
+    #[derive(Default)]
+    struct Flaky {
+        store: MemoryStore,
+        down: std::sync::atomic::AtomicBool,
+        calls: AtomicU32,
+    }
+
+    #[async_trait]
+    impl MessageStore for Flaky {
+        async fn find_by_key(&self, key: &str) -> Result<Option<Message>, StoreError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.down.load(Ordering::SeqCst) {
+                return Err(StoreError::Unavailable("server selection timeout".to_string()));
+            }
+            self.store.find_by_key(key).await
+        }
+
+        async fn upsert(&self, message: &Message) -> Result<(), StoreError> {
+            self.store.upsert(message).await
+        }
+
+        async fn release(
+            &self,
+            key: &str,
+            owner: &str,
+            msg: &str,
+            exception_message: &str,
+            status: &str,
+        ) -> Result<bool, StoreError> {
+            self.store.release(key, owner, msg, exception_message, status).await
+        }
+
+        async fn claim(
+            &self,
+            key: &str,
+            owner: &str,
+            msg: &str,
+            lease: Duration,
+        ) -> Result<Claim, StoreError> {
+            self.store.claim(key, owner, msg, lease).await
+        }
+
+        async fn heartbeat(&self, key: &str, owner: &str, lease: Duration) -> Result<bool, StoreError> {
+            self.store.heartbeat(key, owner, lease).await
+        }
+
+        async fn fail_expired(
+            &self,
+            exception_message: &str,
+            limit: usize,
+        ) -> Result<Vec<WipStatus>, StoreError> {
+            self.store.fail_expired(exception_message, limit).await
+        }
+
+        async fn status(&self, key: &str) -> Result<Option<WipStatus>, StoreError> {
+            self.store.status(key).await
+        }
+
+        async fn history(&self, limit: usize) -> Result<Vec<WipStatus>, StoreError> {
+            self.store.history(limit).await
+        }
+
+        async fn record_attempt(&self, attempt: &JobAttempt) -> Result<(), StoreError> {
+            self.store.record_attempt(attempt).await
+        }
+
+        async fn attempts(&self, key: &str) -> Result<Vec<JobAttempt>, StoreError> {
+            self.store.attempts(key).await
+        }
+
+        async fn failure_counts(
+            &self,
+            since: DateTime<Utc>,
+            until: DateTime<Utc>,
+        ) -> Result<BTreeMap<String, u64>, StoreError> {
+            self.store.failure_counts(since, until).await
+        }
+    }
+
+    fn config(probe_interval_secs: u64) -> StoreConfig {
+        StoreConfig {
+            retry_attempts: 2,
+            retry_backoff_ms: 1,
+            probe_interval_secs,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried_then_degrade() {
+        let flaky = Arc::new(Flaky::default());
+        flaky.down.store(true, Ordering::SeqCst);
+        let store = ResilientStore::new(flaky.clone(), config(60));
+
+        assert!(store.find_by_key("key").await.unwrap_err().is_transient());
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 3);
+        let health = store.health();
+        assert!(health.degraded);
+        assert!(health.last_error.unwrap().contains("server selection timeout"));
+
+        // Degraded: fails fast without reaching the store until the next probe
+        assert!(store.find_by_key("key").await.is_err());
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_probe_recovers_degraded_store() {
+        let flaky = Arc::new(Flaky::default());
+        flaky.down.store(true, Ordering::SeqCst);
+        let store = ResilientStore::new(flaky.clone(), config(0));
+        assert!(store.find_by_key("key").await.is_err());
+        assert!(store.health().degraded);
+
+        flaky.down.store(false, Ordering::SeqCst);
+        assert_eq!(store.find_by_key("key").await.unwrap(), None);
+        assert_eq!(store.health(), StoreHealth::default());
+    }
+}
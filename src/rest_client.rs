@@ -2,25 +2,18 @@ use std::collections::HashMap;
 use std::io;
 
 use crate::config::AppConfig;
+use crate::object_store::PutOptions;
 use crate::s3_service::S3Module;
+use std::sync::Arc;
 
 pub struct RestClient {
-    s3_module: S3Module,
-    config: AppConfig,
+    s3_module: Arc<S3Module>,
+    config: Arc<AppConfig>,
     client: reqwest::Client,
 }
 
 impl<'a> RestClient {
-    pub fn new() -> Self {
-        let s3_module = S3Module::new();
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
+    pub fn new(config: Arc<AppConfig>, s3_module: Arc<S3Module>) -> Self {
         RestClient {
             s3_module,
             config,
@@ -34,18 +27,19 @@ impl<'a> RestClient {
     ) -> Result<String, io::Error> {
         // The URL you want to send the GET request to
         // api/calendar/earnings
-        let key = format!("eod/{}", url_suffix);
+        let s3_config = &self.config.config.s3;
+        let key = s3_config.eod_key(url_suffix);
 
         match self
             .s3_module
-            .is_blob_older_than_mins("sravz-data", &key, 3 * 30 * 24 * 60)
+            .is_blob_older_than_mins(&s3_config.data_bucket, &key, 3 * 30 * 24 * 60)
             .await
         {
             Ok(is_older) => {
                 if is_older {
                     match self
                         .s3_module
-                        .download_object("sravz-data", &key, true)
+                        .download_object(&s3_config.data_bucket, &key)
                         .await
                     {
                         Ok(data) => {
@@ -76,9 +70,15 @@ impl<'a> RestClient {
                                 // Upload the data to S3
                                 match params.get("symbols") {
                                     Some(symbol) => {
-                                        let s3_key = format!("eod/{}/{}.json", url_suffix, symbol);
+                                        let s3_key =
+                                            s3_config.eod_key(&format!("{}/{}.json", url_suffix, symbol));
                                         self.s3_module
-                                            .upload_object("sravz-data", &s3_key, &body)
+                                            .upload_object(
+                                                &s3_config.data_bucket,
+                                                &s3_key,
+                                                body.as_bytes(),
+                                                &PutOptions::gzip("application/json"),
+                                            )
                                             .await
                                             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
                                     }
@@ -104,6 +104,7 @@ impl<'a> RestClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::s3_service::{configured_app_config, fixture_s3_module};
     use chrono::{Duration, Utc};
     use mockito::{mock, Matcher};
     use std::collections::HashMap;
@@ -121,7 +122,8 @@ mod tests {
             .with_body(r#"{"result":"success"}"#)
             .create();
 
-        let rest_client = RestClient::new();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let rest_client = RestClient::new(configured_app_config(), s3_module);
 
         // Prepare the query parameters
         let mut params = HashMap::new();
@@ -151,7 +153,8 @@ mod tests {
             .with_body("Internal Server Error")
             .create();
 
-        let rest_client = RestClient::new();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let rest_client = RestClient::new(configured_app_config(), s3_module);
 
         // Prepare the query parameters
         let mut params = HashMap::new();
@@ -169,7 +172,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_earning_nvidia() {
-        let rest_client = RestClient::new();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let rest_client = RestClient::new(configured_app_config(), s3_module);
         let mut params = HashMap::new();
         let url_suffix = "api/calendar/earnings";
         params.insert("symbols", "NVDA");
@@ -0,0 +1,201 @@
+use crate::config::RetryConfig;
+use crate::handler_registry::PermanentError;
+use crate::models::Message;
+use crate::object_store::S3Error;
+use chrono::Utc;
+use serde_derive::Serialize;
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+
+/* Decides whether a failed message is retried, and after how long */
+pub struct RetryPolicy {
+    config: RetryConfig,
+    backend_rust_topic: String,
+}
+
+/* Published on the dead-letter topic once a message has used up its attempts */
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+    pub key: String,
+    pub handler: String,
+    pub attempts: u32,
+    pub errors: Vec<String>,
+    // The message as the client sent it, without the retry bookkeeping
+    pub payload: Message,
+    pub date: String,
+}
+
+impl RetryPolicy {
+    pub fn new(config: RetryConfig, backend_rust_topic: String) -> Self {
+        RetryPolicy {
+            config,
+            backend_rust_topic,
+        }
+    }
+
+    pub fn max_attempts(&self, handler: &str) -> u32 {
+        self.config
+            .handlers
+            .get(handler)
+            .copied()
+            .unwrap_or(self.config.max_attempts)
+            .max(1)
+    }
+
+    /* `attempt` is the 1-based number of the attempt that just failed */
+    pub fn should_retry(&self, handler: &str, attempt: u32) -> bool {
+        attempt < self.max_attempts(handler)
+    }
+
+    /* Exponential backoff before `attempt` + 1 runs, capped at max_backoff_ms */
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let backoff_ms = self
+            .config
+            .initial_backoff_ms
+            .saturating_mul(1u64 << exponent)
+            .min(self.config.max_backoff_ms);
+        Duration::from_millis(backoff_ms)
+    }
+
+    pub fn dead_letter_topic(&self) -> String {
+        self.config
+            .dead_letter_topic
+            .clone()
+            .unwrap_or_else(|| format!("{}-dead-letter", self.backend_rust_topic))
+    }
+
+    /* Records the failed attempt on the message so the next delivery knows its history */
+    pub fn record_failure(&self, message: &mut Message, error: &str) {
+        message.attempts += 1;
+        message
+            .retry_errors
+            .push(format!("attempt {}: {}", message.attempts, error));
+    }
+
+    pub fn dead_letter(&self, handler: &str, message: &Message) -> DeadLetter {
+        let mut payload = message.clone();
+        payload.attempts = 0;
+        payload.retry_errors = Vec::new();
+        DeadLetter {
+            key: message.key.clone(),
+            handler: handler.to_string(),
+            attempts: message.attempts,
+            errors: message.retry_errors.clone(),
+            payload,
+            date: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/* Handler errors that another attempt can not fix: rejected requests, missing or unreadable
+objects and invalid input to the Python modules. Anything else, e.g. timeouts, is retried. */
+pub fn is_permanent(err: &(dyn Error + Send + Sync + 'static)) -> bool {
+    if err.is::<PermanentError>() || err.is::<Message>() {
+        return true;
+    }
+    if let Some(err) = err.downcast_ref::<S3Error>() {
+        return matches!(
+            err,
+            S3Error::NotFound(_)
+                | S3Error::AccessDenied(_)
+                | S3Error::Corrupt(_)
+                | S3Error::Invalid(_)
+        );
+    }
+    if let Some(err) = err.downcast_ref::<io::Error>() {
+        // Wrapped errors, e.g. an S3Error of the data provider cache, are classified by their cause
+        return matches!(err.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData)
+            || err.get_ref().is_some_and(is_permanent);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy::new(
+            RetryConfig {
+                max_attempts: 3,
+                initial_backoff_ms: 1_000,
+                max_backoff_ms: 5_000,
+                dead_letter_topic: None,
+                handlers: HashMap::from([("langchain".to_string(), 1)]),
+            },
+            "vagrant_backend-rust".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_max_attempts_per_handler() {
+        let retry_policy = retry_policy();
+        assert_eq!(retry_policy.max_attempts("langchain"), 1);
+        assert_eq!(retry_policy.max_attempts("earnings"), 3);
+        assert!(!retry_policy.should_retry("langchain", 1));
+        assert!(retry_policy.should_retry("earnings", 2));
+        assert!(!retry_policy.should_retry("earnings", 3));
+    }
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let retry_policy = retry_policy();
+        assert_eq!(retry_policy.backoff(1), Duration::from_millis(1_000));
+        assert_eq!(retry_policy.backoff(2), Duration::from_millis(2_000));
+        assert_eq!(retry_policy.backoff(3), Duration::from_millis(4_000));
+        assert_eq!(retry_policy.backoff(4), Duration::from_millis(5_000));
+        assert_eq!(retry_policy.backoff(100), Duration::from_millis(5_000));
+    }
+
+    #[test]
+    fn test_permanent_errors_are_not_retried() {
+        let permanent: Vec<Box<dyn Error + Send + Sync>> = vec![
+            Box::new(PermanentError("Unknown sravz_id stk_us_x".to_string())),
+            Box::new(S3Error::NotFound("sravz-data/historical/x.json".to_string())),
+            Box::new(S3Error::AccessDenied("sravz-data".to_string())),
+            Box::new(S3Error::Corrupt("not gzip".to_string())),
+            Box::new(S3Error::Invalid("bucket".to_string())),
+            Box::new(io::Error::new(io::ErrorKind::InvalidInput, "ValueError: bad code")),
+            Box::new(io::Error::other(S3Error::NotFound("eod/x.json".to_string()))),
+        ];
+        for err in permanent {
+            assert!(is_permanent(err.as_ref()), "{}", err);
+        }
+        let transient: Vec<Box<dyn Error + Send + Sync>> = vec![
+            "S3 timeout".into(),
+            Box::new(S3Error::Throttled("slow down".to_string())),
+            Box::new(S3Error::Network("connection reset".to_string())),
+            Box::new(io::Error::other("Service error")),
+        ];
+        for err in transient {
+            assert!(!is_permanent(err.as_ref()), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_dead_letter_keeps_error_history() {
+        let retry_policy = retry_policy();
+        let mut message = Message {
+            id: 3.0,
+            key: "key".to_string(),
+            ..Default::default()
+        };
+        retry_policy.record_failure(&mut message, "S3 timeout");
+        retry_policy.record_failure(&mut message, "Mongo down");
+        let dead_letter = retry_policy.dead_letter("earnings", &message);
+        assert_eq!(dead_letter.attempts, 2);
+        assert_eq!(
+            dead_letter.errors,
+            vec!["attempt 1: S3 timeout", "attempt 2: Mongo down"]
+        );
+        assert_eq!(dead_letter.payload.attempts, 0);
+        assert!(dead_letter.payload.retry_errors.is_empty());
+        assert_eq!(
+            retry_policy.dead_letter_topic(),
+            "vagrant_backend-rust-dead-letter"
+        );
+    }
+}
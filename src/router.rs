@@ -1,49 +1,62 @@
 use crate::{
-    config::AppConfig, langchain_service::LangChain, leveraged_funds_service::LeveragedFunds,
-    models::Message, mongo_service::Mongo, services::earnings::Earnings,
+    config::AppConfig,
+    handler_registry::{HandlerInfo, HandlerRegistry, MessageHandler},
+    langchain_service::LangChain,
+    leveraged_funds_service::LeveragedFunds,
+    models::Message,
+    progress::Progress,
+    services::earnings::Earnings,
 };
+use crate::s3_service::S3Module;
 use std::error::Error;
+use std::sync::Arc;
 
-pub struct Router<'a> {
-    leveraged_funds: &'a mut LeveragedFunds<'a>,
-    langchain: &'a mut LangChain<'a>,
-    earnings: Earnings,
-    pub(crate) mongo: Mongo,
+/* Router is shared by all workers, so every handler it calls takes &self */
+pub struct Router {
+    registry: HandlerRegistry,
 }
 
-impl<'a> Router<'a> {
-    pub fn new(
-        mongo: Mongo,
-        leveraged_funds: &'a mut LeveragedFunds<'a>,
-        langchain: &'a mut LangChain<'a>,
-    ) -> Self {
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        // Use shaku
-        let earnings: Earnings = Earnings::new(config);
-        Router {
-            leveraged_funds,
-            langchain,
-            earnings,
-            mongo,
+impl Router {
+    pub fn new(registry: HandlerRegistry) -> Self {
+        Router { registry }
+    }
+
+    /* Router with the leveraged funds, LangChain and earnings handlers registered */
+    pub fn with_default_handlers(
+        config: Arc<AppConfig>,
+        s3_module: Arc<S3Module>,
+    ) -> Result<Self, String> {
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(LeveragedFunds::new(config.clone(), s3_module.clone())))?;
+        registry.register(Arc::new(LangChain::new(config.clone())))?;
+        registry.register(Arc::new(Earnings::new(config, s3_module)))?;
+        Ok(Router::new(registry))
+    }
+
+    pub fn handler(&self, id: f64) -> Option<&Arc<dyn MessageHandler>> {
+        self.registry.find(id)
+    }
+
+    pub fn handlers(&self) -> Vec<HandlerInfo> {
+        self.registry.handlers()
+    }
+
+    /* Unknown ids and requests the handler rejects are not worth processing or retrying */
+    pub fn validate(&self, message: &Message) -> Result<(), String> {
+        match self.handler(message.id) {
+            Some(handler) => handler.validate(message),
+            None => Err("Message ID not implemented".to_string()),
         }
     }
 
     pub async fn process_message(
-        &mut self,
+        &self,
         mut message: Message,
-    ) -> Result<Message, Box<dyn Error>> {
-        match message.id {
-            n if (1.0..=1.009).contains(&n) => self.leveraged_funds.leverage_funds(message).await,
-            n if (2.0..=2.009).contains(&n) => self.langchain.query(message).await,
-            n if (3.0..=3.009).contains(&n) => self.earnings.get_earnings_plot(message).await,
-            _ => {
+        progress: Progress,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        match self.handler(message.id) {
+            Some(handler) => handler.handle(message, progress).await,
+            None => {
                 message.exception_message = "Message ID not implemented".to_owned();
                 Err(Box::new(message))
             }
@@ -56,23 +69,15 @@ mod tests {
     use chrono::Utc;
 
     use super::*;
-    use crate::{models::Kwargs, s3_service::S3Module};
+    use crate::models::Kwargs;
+    use crate::s3_service::{configured_app_config, fixture_app_config, fixture_s3_module};
 
     #[tokio::test]
     async fn test_process_message_leveraged_funds() {
-        let mongo = Mongo {};
-        let s3_module = S3Module::new();
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut leveraged_funds = LeveragedFunds::new(&config, &s3_module);
-        let mut langchain: LangChain = LangChain::new(&config, &s3_module);
-        let mut router = Router::new(mongo, &mut leveraged_funds, &mut langchain);
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let config = configured_app_config();
+        let router =
+            Router::with_default_handlers(config, s3_module).unwrap();
 
         let message = Message {
             id: 1.0,
@@ -87,6 +92,7 @@ mod tests {
                     upload_to_aws: true,
                     json_keys: Some(vec![]),
                     llm_query: None,
+                    force_refresh: None,
                 },
             },
             t_o: String::new(),
@@ -99,16 +105,19 @@ mod tests {
             e: String::new(),
             key: String::new(),
             exception_message: String::new(),
+            attempts: 0,
+            retry_errors: Vec::new(),
             d_o: Some(crate::models::DO {
                 bucket_name: "Fake".to_string(),
                 key_name: "Fake".to_string(),
                 data: serde_json::Value::String("Fake".to_string()),
                 signed_url: "Fake".to_string(),
+                data_ref: None,
             }),
         };
 
         // Act
-        let result = router.process_message(message).await;
+        let result = router.process_message(message, Progress::disabled()).await;
 
         // Assert
         assert!(result.is_ok());
@@ -119,19 +128,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_process_message_langchain() {
-        let mongo = Mongo {};
-        let s3_module = S3Module::new();
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut leveraged_funds = LeveragedFunds::new(&config, &s3_module);
-        let mut langchain: LangChain = LangChain::new(&config, &s3_module);
-        let mut router = Router::new(mongo, &mut leveraged_funds, &mut langchain);
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let config = configured_app_config();
+        let router =
+            Router::with_default_handlers(config, s3_module).unwrap();
         let message = Message {
             id: 2.0,
             p_i: crate::models::PI {
@@ -145,6 +145,7 @@ mod tests {
                     upload_to_aws: true,
                     json_keys: Some(Vec::new()),
                     llm_query: None,
+                    force_refresh: None,
                 },
             },
             t_o: String::new(),
@@ -157,16 +158,19 @@ mod tests {
             e: String::new(),
             key: String::new(),
             exception_message: String::new(),
+            attempts: 0,
+            retry_errors: Vec::new(),
             d_o: Some(crate::models::DO {
                 bucket_name: "Fake".to_string(),
                 key_name: "Fake".to_string(),
                 data: serde_json::Value::String("Fake".to_string()),
                 signed_url: "Fake".to_string(),
+                data_ref: None,
             }),
         };
 
         // Act
-        let result = router.process_message(message).await;
+        let result = router.process_message(message, Progress::disabled()).await;
 
         // Assert
         assert!(result.is_ok());
@@ -177,19 +181,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_process_message_earnings() {
-        let mongo = Mongo {};
-        let s3_module = S3Module::new();
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut leveraged_funds = LeveragedFunds::new(&config, &s3_module);
-        let mut langchain: LangChain = LangChain::new(&config, &s3_module);
-        let mut router = Router::new(mongo, &mut leveraged_funds, &mut langchain);
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let config = configured_app_config();
+        let router =
+            Router::with_default_handlers(config, s3_module).unwrap();
         let message = Message {
             id: 3.0,
             p_i: crate::models::PI {
@@ -199,6 +194,7 @@ mod tests {
                     upload_to_aws: true,
                     json_keys: Some(Vec::new()),
                     llm_query: None,
+                    force_refresh: None,
                 },
             },
             t_o: String::new(),
@@ -211,16 +207,19 @@ mod tests {
             e: String::new(),
             key: "3".to_string(),
             exception_message: String::new(),
+            attempts: 0,
+            retry_errors: Vec::new(),
             d_o: Some(crate::models::DO {
                 bucket_name: "Fake".to_string(),
                 key_name: "Fake".to_string(),
                 data: serde_json::Value::String("Fake".to_string()),
                 signed_url: "Fake".to_string(),
+                data_ref: None,
             }),
         };
 
         // Act
-        let result = router.process_message(message).await;
+        let result = router.process_message(message, Progress::disabled()).await;
 
         // Assert
         assert!(result.is_ok());
@@ -231,19 +230,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_process_message_invalid_id() {
-        let mongo = Mongo {};
-        let s3_module = S3Module::new();
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut leveraged_funds = LeveragedFunds::new(&config, &s3_module);
-        let mut langchain: LangChain = LangChain::new(&config, &s3_module);
-        let mut router = Router::new(mongo, &mut leveraged_funds, &mut langchain);
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let config = configured_app_config();
+        let router =
+            Router::with_default_handlers(config, s3_module).unwrap();
         let message = Message {
             id: 4.0,
             p_i: crate::models::PI {
@@ -257,6 +247,7 @@ mod tests {
                     upload_to_aws: true,
                     json_keys: Some(Vec::new()),
                     llm_query: None,
+                    force_refresh: None,
                 },
             },
             t_o: String::new(),
@@ -269,20 +260,49 @@ mod tests {
             e: String::new(),
             key: String::new(),
             exception_message: String::new(),
+            attempts: 0,
+            retry_errors: Vec::new(),
             d_o: Some(crate::models::DO {
                 bucket_name: "Fake".to_string(),
                 key_name: "Fake".to_string(),
                 data: serde_json::Value::String("Fake".to_string()),
                 signed_url: "Fake".to_string(),
+                data_ref: None,
             }),
         };
 
         // Act
-        let result = router.process_message(message).await;
+        let result = router.process_message(message, Progress::disabled()).await;
 
         // Assert
         assert!(result.is_err());
         let err_message = result.err().unwrap();
         assert_eq!(format!("{}", err_message), "Message ID not implemented");
     }
+
+    #[test]
+    fn test_default_handlers_are_listed_and_validated() {
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let router = Router::with_default_handlers(fixture_app_config(), s3_module).unwrap();
+        let names: Vec<String> = router.handlers().into_iter().map(|h| h.name).collect();
+        assert_eq!(names, vec!["leveraged_funds", "langchain", "earnings"]);
+
+        let earnings = Message {
+            id: 3.0,
+            p_i: crate::models::PI {
+                args: vec!["stk_us_nvda".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(router.validate(&earnings).is_err());
+        let unknown = Message {
+            id: 4.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            router.validate(&unknown),
+            Err("Message ID not implemented".to_string())
+        );
+    }
 }
@@ -1,152 +1,742 @@
 use chrono::{DateTime, Duration, Utc};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 
+use crate::config::{AppConfig, ObjectStoreKind, S3Config};
+use crate::object_cache::ObjectCache;
+use crate::object_store::{
+    guess_content_type, is_gzip, ListOptions, ListPage, LocalObjectStore, Object, ObjectHead,
+    ObjectStore, ObjectSummary, PresignMethod, PresignOptions, PutOptions, S3Error,
+};
+use async_trait::async_trait;
 use rusoto_core::credential::AwsCredentials;
 use rusoto_core::{Region, RusotoError};
 use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
 use rusoto_s3::{
-    CreateBucketRequest, DeleteObjectRequest, GetObjectRequest, HeadObjectError, HeadObjectRequest,
-    ListObjectsV2Request, PutObjectRequest, S3Client, StreamingBody, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateBucketRequest, CreateMultipartUploadRequest, DeleteObjectRequest,
+    GetObjectError, GetObjectRequest, HeadObjectError, HeadObjectRequest, ListObjectsV2Error,
+    ListObjectsV2Request, PutObjectRequest, S3Client, StreamingBody, UploadPartRequest, S3,
 };
-use std::fs::File;
-use std::io::Write;
+use futures::{stream, StreamExt, TryStreamExt};
+use log::{error, info};
+use std::future::Future;
+use std::io::{SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
 use std::{
     env,
     io::{self, Read},
 };
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
 
-pub struct S3Module {
+// S3 rejects parts other than the last one below 5 MiB
+const MIN_PART_SIZE_BYTES: u64 = 5 * 1024 * 1024;
+// Longest expiry SigV4 presigned URLs accept
+const MAX_PRESIGN_EXPIRY: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/* ObjectStore on an S3 compatible endpoint via rusoto, Contabo by default */
+pub struct S3ObjectStore {
     client: S3Client,
     region: Region,
     access_key: String,
     secret_key: String,
+    config: S3Config,
 }
 
-impl S3Module {
-    pub fn new() -> Self {
-        let custom_endpoint = "usc1.contabostorage.com";
-        let access_key =
-            env::var("CONTABO_KEY").expect("CONTABO_KEY not found in environment variables");
-        let secret_key =
-            env::var("CONTABO_SECRET").expect("CONTABO_SECRET not found in environment variables");
+impl S3ObjectStore {
+    pub fn new(config: &S3Config) -> Result<Self, S3Error> {
+        let credential = |name: &str| {
+            env::var(name).map_err(|_| {
+                S3Error::AccessDenied(format!("{} not found in environment variables", name))
+            })
+        };
+        let access_key = credential("CONTABO_KEY")?;
+        let secret_key = credential("CONTABO_SECRET")?;
         let region = Region::Custom {
-            name: "custom".to_owned(),
-            endpoint: custom_endpoint.to_owned(),
+            name: config.region.clone(),
+            endpoint: config.endpoint.clone(),
         };
         let client = S3Client::new_with(
-            rusoto_core::request::HttpClient::new().expect("Failed to create HTTP client"),
+            rusoto_core::request::HttpClient::new()
+                .map_err(|error| S3Error::Service(error.to_string()))?,
             rusoto_core::credential::StaticProvider::new_minimal(
                 access_key.clone(),
                 secret_key.clone(),
             ),
             region.clone(),
         );
-        Self {
+        Ok(Self {
             client,
             region,
             access_key,
             secret_key,
+            config: config.clone(),
+        })
+    }
+
+    fn is_multipart(&self, size: u64) -> bool {
+        size > self.config.multipart_threshold_bytes
+    }
+
+    /* Uploads `size` bytes as a multipart upload, `multipart_concurrency` parts at a time.
+    `part(offset, length)` returns the body of a part. The upload is aborted when any part
+    fails, so the bucket does not keep paying for parts that will never be completed. */
+    async fn put_multipart<F, Fut>(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions,
+        size: u64,
+        part: F,
+    ) -> Result<(), S3Error>
+    where
+        F: Fn(u64, u64) -> Fut,
+        Fut: Future<Output = Result<StreamingBody, io::Error>>,
+    {
+        let create_request = CreateMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_encoding: options.content_encoding.clone(),
+            content_type: options.content_type.clone(),
+            ..Default::default()
+        };
+        let upload_id = self
+            .client
+            .create_multipart_upload(create_request)
+            .await
+            .map_err(s3_error)?
+            .upload_id
+            .ok_or_else(|| S3Error::Service("Multipart upload created without an upload id".to_string()))?;
+        let ranges = part_ranges(size, self.config.multipart_part_size_bytes);
+        info!(
+            "Uploading {}/{} in {} parts, upload id {}",
+            bucket,
+            key,
+            ranges.len(),
+            upload_id
+        );
+
+        let uploaded: Result<Vec<CompletedPart>, S3Error> = stream::iter(ranges)
+            .map(|(part_number, offset, length)| {
+                let part = &part;
+                let upload_id = &upload_id;
+                async move {
+                    let upload_part_request = UploadPartRequest {
+                        bucket: bucket.to_string(),
+                        key: key.to_string(),
+                        upload_id: upload_id.clone(),
+                        part_number,
+                        content_length: Some(length as i64),
+                        body: Some(part(offset, length).await?),
+                        ..Default::default()
+                    };
+                    let response = self
+                        .client
+                        .upload_part(upload_part_request)
+                        .await
+                        .map_err(s3_error)?;
+                    Ok(CompletedPart {
+                        e_tag: response.e_tag,
+                        part_number: Some(part_number),
+                    })
+                }
+            })
+            .buffer_unordered(self.config.multipart_concurrency.max(1))
+            .try_collect()
+            .await;
+
+        let completed = match uploaded {
+            Ok(mut parts) => {
+                parts.sort_by_key(|part| part.part_number);
+                let complete_request = CompleteMultipartUploadRequest {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                    upload_id: upload_id.clone(),
+                    multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                    ..Default::default()
+                };
+                self.client
+                    .complete_multipart_upload(complete_request)
+                    .await
+                    .map(|_| ())
+                    .map_err(s3_error)
+            }
+            Err(error) => Err(error),
+        };
+        if let Err(error) = &completed {
+            error!("Aborting multipart upload of {}/{}: {}", bucket, key, error);
+            let abort_request = AbortMultipartUploadRequest {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                upload_id,
+                ..Default::default()
+            };
+            if let Err(abort_error) = self.client.abort_multipart_upload(abort_request).await {
+                error!(
+                    "Unable to abort multipart upload of {}/{}: {:?}",
+                    bucket, key, abort_error
+                );
+            }
         }
+        completed
     }
 
-    #[allow(dead_code)]
-    pub async fn create_bucket(&self, bucket_name: &str) {
-        // Create a new bucket
-        let create_bucket_request = CreateBucketRequest {
-            bucket: bucket_name.to_string(),
+    async fn put_body(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: StreamingBody,
+        options: &PutOptions,
+    ) -> Result<(), S3Error> {
+        let put_object_request = PutObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            body: Some(body),
+            content_encoding: options.content_encoding.clone(),
+            content_type: options.content_type.clone(),
             ..Default::default()
         };
 
         self.client
-            .create_bucket(create_bucket_request)
+            .put_object(put_object_request)
             .await
-            .expect("Failed to create bucket");
+            .map(|_| ())
+            .map_err(s3_error)
     }
+}
 
-    #[allow(dead_code)]
-    pub async fn list_objects(&self, bucket_name: &str) {
-        // List objects in the bucket
+/* Signs the request described by `options` with the credentials, nothing is sent */
+fn presigned_url(
+    region: &Region,
+    access_key: &str,
+    secret_key: &str,
+    bucket: &str,
+    key: &str,
+    options: &PresignOptions,
+) -> String {
+    let expires_in = options.expires_in.min(MAX_PRESIGN_EXPIRY);
+    let presign_options = PreSignedRequestOption { expires_in };
+
+    // If you have a session token (for temporary credentials), add it here, otherwise use None.
+    let session_token: Option<String> = None;
+
+    // Create an instance of AwsCredentials, valid as long as the URL
+    let credentials = AwsCredentials::new(
+        access_key.to_string(), // AWS Access Key
+        secret_key.to_string(), // AWS Secret Key
+        session_token,          // Optional session token
+        Some(Utc::now() + Duration::seconds(expires_in.as_secs() as i64)),
+    );
+    match options.method {
+        PresignMethod::Get => GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            response_content_type: options.content_type.clone(),
+            response_content_disposition: options.content_disposition.clone(),
+            ..Default::default()
+        }
+        .get_presigned_url(region, &credentials, &presign_options),
+        PresignMethod::Put => PutObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_type: options.content_type.clone(),
+            ..Default::default()
+        }
+        .get_presigned_url(region, &credentials, &presign_options),
+    }
+}
+
+/* (part number, offset, length) of the parts of a `size` byte object, numbered from 1 */
+fn part_ranges(size: u64, part_size: u64) -> Vec<(i64, u64, u64)> {
+    let part_size = part_size.max(MIN_PART_SIZE_BYTES);
+    (0..size.div_ceil(part_size))
+        .map(|index| {
+            let offset = index * part_size;
+            (index as i64 + 1, offset, part_size.min(size - offset))
+        })
+        .collect()
+}
+
+/* Streams `length` bytes of the file from `offset` without reading them into memory */
+async fn file_body(path: &Path, offset: u64, length: u64) -> Result<StreamingBody, io::Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    Ok(StreamingBody::new_with_size(
+        ReaderStream::new(file.take(length)),
+        length as usize,
+    ))
+}
+
+/* Classifies a failed request by how the caller should react, service specific errors such as
+NoSuchKey are matched where they are expected */
+fn s3_error<E: std::fmt::Debug>(error: RusotoError<E>) -> S3Error {
+    match error {
+        RusotoError::HttpDispatch(error) => S3Error::Network(error.to_string()),
+        RusotoError::Blocking => S3Error::Network("Blocking request failed".to_string()),
+        RusotoError::Credentials(error) => S3Error::AccessDenied(error.to_string()),
+        RusotoError::Validation(reason) => S3Error::Invalid(reason),
+        RusotoError::ParseError(reason) => S3Error::Corrupt(reason),
+        RusotoError::Service(error) => S3Error::Service(format!("{:?}", error)),
+        RusotoError::Unknown(response) => {
+            let reason = format!("HTTP {}: {}", response.status, response.body_as_str());
+            match response.status.as_u16() {
+                404 => S3Error::NotFound(reason),
+                401 | 403 => S3Error::AccessDenied(reason),
+                // S3 answers SlowDown with 503
+                429 | 503 => S3Error::Throttled(reason),
+                400 => S3Error::Invalid(reason),
+                _ => S3Error::Service(reason),
+            }
+        }
+    }
+}
+
+// Last-Modified header, an HTTP date
+fn http_date(value: Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|value| DateTime::parse_from_rfc2822(&value).ok())
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+fn not_found(bucket: &str, key: &str) -> S3Error {
+    S3Error::NotFound(format!("Object {}/{} not found", bucket, key))
+}
+
+impl S3ObjectStore {
+    /* None when the request had conditions and the object still matches them */
+    async fn get_object(&self, get_object_request: GetObjectRequest) -> Result<Option<Object>, S3Error> {
+        let (bucket, key) = (get_object_request.bucket.clone(), get_object_request.key.clone());
+        match self.client.get_object(get_object_request).await {
+            Ok(response) => {
+                let mut bytes = Vec::new();
+                if let Some(body) = response.body {
+                    body.into_async_read().read_to_end(&mut bytes).await?;
+                }
+                Ok(Some(Object {
+                    head: ObjectHead {
+                        size: bytes.len() as u64,
+                        last_modified: http_date(response.last_modified),
+                        etag: response.e_tag,
+                        content_type: response.content_type,
+                        content_encoding: response.content_encoding,
+                    },
+                    body: bytes,
+                }))
+            }
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => Err(not_found(&bucket, &key)),
+            Err(RusotoError::Unknown(response)) => match response.status.as_u16() {
+                // Not Modified
+                304 => Ok(None),
+                404 => Err(not_found(&bucket, &key)),
+                _ => Err(s3_error(RusotoError::<GetObjectError>::Unknown(response))),
+            },
+            Err(error) => Err(s3_error(error)),
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn get(&self, bucket: &str, key: &str) -> Result<Object, S3Error> {
+        let get_object_request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        self.get_object(get_object_request)
+            .await?
+            .ok_or_else(|| S3Error::Service(format!("Object {}/{} not returned", bucket, key)))
+    }
+
+    /* Conditional GET, a 304 moves no body */
+    async fn get_if_modified(
+        &self,
+        bucket: &str,
+        key: &str,
+        cached: &ObjectHead,
+    ) -> Result<Option<Object>, S3Error> {
+        let get_object_request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            if_none_match: cached.etag.clone(),
+            // Only when there is no ETag, If-None-Match takes precedence
+            if_modified_since: cached
+                .last_modified
+                .filter(|_| cached.etag.is_none())
+                .map(|last_modified| last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+            ..Default::default()
+        };
+        self.get_object(get_object_request).await
+    }
+
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<(), S3Error> {
+        let size = body.len() as u64;
+        if !self.is_multipart(size) {
+            return self.put_body(bucket, key, StreamingBody::from(body), options).await;
+        }
+        // Parts are slices of the same buffer
+        let body = bytes::Bytes::from(body);
+        self.put_multipart(bucket, key, options, size, |offset, length| {
+            let part = body.slice(offset as usize..(offset + length) as usize);
+            async move {
+                Ok(StreamingBody::new_with_size(
+                    stream::once(async move { Ok(part) }),
+                    length as usize,
+                ))
+            }
+        })
+        .await
+    }
+
+    async fn put_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        options: &PutOptions,
+    ) -> Result<(), S3Error> {
+        let size = tokio::fs::metadata(path).await?.len();
+        if !self.is_multipart(size) {
+            let body = file_body(path, 0, size).await?;
+            return self.put_body(bucket, key, body, options).await;
+        }
+        self.put_multipart(bucket, key, options, size, |offset, length| {
+            file_body(path, offset, length)
+        })
+        .await
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<Option<ObjectHead>, S3Error> {
+        let head_req = HeadObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        match self.client.head_object(head_req).await {
+            Ok(result) => Ok(Some(ObjectHead {
+                size: result.content_length.unwrap_or_default().max(0) as u64,
+                // Get the LastModified date from the metadata
+                last_modified: http_date(result.last_modified),
+                etag: result.e_tag,
+                content_type: result.content_type,
+                content_encoding: result.content_encoding,
+            })),
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(None), // Object does not exist
+            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => {
+                Ok(None) // Handle other cases where a 404 status indicates the object or bucket doesn't exist
+            }
+            Err(e) => Err(s3_error(e)), // Some other error occurred
+        }
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), S3Error> {
+        let delete_object_request = DeleteObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        self.client
+            .delete_object(delete_object_request)
+            .await
+            .map(|_| ())
+            .map_err(s3_error)
+    }
+
+    async fn list(&self, bucket: &str, options: &ListOptions) -> Result<ListPage, S3Error> {
         let list_objects_request = ListObjectsV2Request {
-            bucket: bucket_name.to_string(),
+            bucket: bucket.to_string(),
+            prefix: Some(options.prefix.clone()).filter(|prefix| !prefix.is_empty()),
+            delimiter: options.delimiter.clone(),
+            continuation_token: options.continuation_token.clone(),
+            max_keys: options.max_keys.map(|max_keys| max_keys as i64),
             ..Default::default()
         };
 
-        let result = self
-            .client
-            .list_objects_v2(list_objects_request)
+        let result = match self.client.list_objects_v2(list_objects_request).await {
+            Ok(result) => result,
+            Err(RusotoError::Service(ListObjectsV2Error::NoSuchBucket(_))) => {
+                return Err(S3Error::NotFound(format!("Bucket {} not found", bucket)))
+            }
+            Err(error) => return Err(s3_error(error)),
+        };
+        Ok(ListPage {
+            objects: result
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|obj| {
+                    Some(ObjectSummary {
+                        key: obj.key?,
+                        head: ObjectHead {
+                            size: obj.size.unwrap_or_default().max(0) as u64,
+                            // ISO 8601 in listings, unlike the HTTP date of HeadObject
+                            last_modified: obj
+                                .last_modified
+                                .and_then(|last_modified| DateTime::parse_from_rfc3339(&last_modified).ok())
+                                .map(|dt| dt.with_timezone(&Utc)),
+                            etag: obj.e_tag,
+                            // Listings do not include headers
+                            ..Default::default()
+                        },
+                    })
+                })
+                .collect(),
+            common_prefixes: result
+                .common_prefixes
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|common_prefix| common_prefix.prefix)
+                .collect(),
+            next_continuation_token: result
+                .next_continuation_token
+                .filter(|_| result.is_truncated.unwrap_or_default()),
+        })
+    }
+
+    async fn presign(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PresignOptions,
+    ) -> Result<String, S3Error> {
+        Ok(presigned_url(
+            &self.region,
+            &self.access_key,
+            &self.secret_key,
+            bucket,
+            key,
+            options,
+        ))
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> Result<(), S3Error> {
+        let create_bucket_request = CreateBucketRequest {
+            bucket: bucket.to_string(),
+            ..Default::default()
+        };
+
+        self.client
+            .create_bucket(create_bucket_request)
             .await
-            .expect("Failed to list objects");
+            .map(|_| ())
+            .map_err(s3_error)
+    }
+}
+
+/* Object storage for the services, on the ObjectStore chosen by `object_store` in the config */
+pub struct S3Module {
+    store: Arc<dyn ObjectStore>,
+    // Expiry of the URLs from output_url and upload_url
+    presign_expiry: std::time::Duration,
+    // Downloads are served from here after revalidation, see [config.object_cache]
+    cache: Option<ObjectCache>,
+}
+
+impl S3Module {
+    #[cfg(test)]
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            presign_expiry: std::time::Duration::from_secs(S3Config::default().presign_expiry_secs),
+            cache: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_cache(mut self, cache: ObjectCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /* S3 needs CONTABO_KEY and CONTABO_SECRET for [config.s3], local serves [config.local_store] root */
+    pub fn from_config(config: &AppConfig) -> Result<Self, S3Error> {
+        let store: Arc<dyn ObjectStore> = match config.config.object_store {
+            ObjectStoreKind::S3 => Arc::new(S3ObjectStore::new(&config.config.s3)?),
+            ObjectStoreKind::Local => {
+                Arc::new(LocalObjectStore::new(&config.config.local_store.root))
+            }
+        };
+        let cache_config = &config.config.object_cache;
+        let cache = if cache_config.enabled {
+            Some(ObjectCache::open(&cache_config.directory, cache_config.max_size_bytes)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            store,
+            presign_expiry: std::time::Duration::from_secs(config.config.s3.presign_expiry_secs),
+            cache,
+        })
+    }
+
+    /* The object from the cache when the store confirms it is unchanged, otherwise downloaded
+    and cached */
+    async fn get_object(&self, bucket_name: &str, object_key: &str) -> Result<Object, S3Error> {
+        let Some(cache) = &self.cache else {
+            return self.store.get(bucket_name, object_key).await;
+        };
+        if let Some(cached) = cache.get(bucket_name, object_key).await {
+            match self.store.get_if_modified(bucket_name, object_key, &cached.head).await {
+                Ok(None) => return Ok(cached),
+                Ok(Some(object)) => {
+                    self.cache_object(cache, bucket_name, object_key, &object).await;
+                    return Ok(object);
+                }
+                Err(S3Error::NotFound(reason)) => {
+                    cache.remove(bucket_name, object_key).await;
+                    return Err(S3Error::NotFound(reason));
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        let object = self.store.get(bucket_name, object_key).await?;
+        self.cache_object(cache, bucket_name, object_key, &object).await;
+        Ok(object)
+    }
+
+    // A failure to cache does not fail the download
+    async fn cache_object(&self, cache: &ObjectCache, bucket_name: &str, object_key: &str, object: &Object) {
+        if let Err(error) = cache.put(bucket_name, object_key, object).await {
+            error!("Unable to cache {}/{}: {}", bucket_name, object_key, error);
+        }
+    }
 
-        println!("Objects in the bucket:");
-        for obj in result.contents.unwrap_or_default() {
-            println!("{}", obj.key.unwrap_or_default());
+    // The cached copy is stale once the object is written through this module
+    async fn invalidate(&self, bucket_name: &str, object_key: &str) {
+        if let Some(cache) = &self.cache {
+            cache.remove(bucket_name, object_key).await;
         }
     }
 
     #[allow(dead_code)]
-    pub async fn upload_object(
+    pub async fn create_bucket(&self, bucket_name: &str) -> Result<(), S3Error> {
+        // Create a new bucket
+        self.store.create_bucket(bucket_name).await
+    }
+
+    /* All objects under prefix, following continuation tokens. With a delimiter, keys below the
+    next delimiter are returned as common prefixes instead, e.g. "historical/" with "/" lists one
+    directory level. */
+    #[allow(dead_code)]
+    pub async fn list_objects(
         &self,
         bucket_name: &str,
-        object_key: &str,
-        content: &str,
-    ) -> Result<(), io::Error> {
-        let put_object_request: PutObjectRequest = PutObjectRequest {
-            bucket: bucket_name.to_string(),
-            key: object_key.to_string(),
-            body: Some(StreamingBody::from(self.compress_string(content).unwrap())),
-            content_encoding: Some("gzip".to_owned()),
-            content_type: Some("application/json".to_owned()),
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Result<ListPage, S3Error> {
+        let mut options = ListOptions {
+            prefix: prefix.to_string(),
+            delimiter: delimiter.map(str::to_string),
             ..Default::default()
         };
+        let mut listing = ListPage::default();
+        loop {
+            let page = self.list_page(bucket_name, &options).await?;
+            listing.objects.extend(page.objects);
+            listing.common_prefixes.extend(page.common_prefixes);
+            match page.next_continuation_token {
+                Some(token) => options.continuation_token = Some(token),
+                None => return Ok(listing),
+            }
+        }
+    }
 
-        self.client
-            .put_object(put_object_request)
-            .await
-            .expect("Failed to upload object");
-
-        Ok(())
+    /* A single page, for callers paging through large buckets themselves */
+    pub async fn list_page(
+        &self,
+        bucket_name: &str,
+        options: &ListOptions,
+    ) -> Result<ListPage, S3Error> {
+        self.store.list(bucket_name, options).await
     }
 
-    pub async fn generate_presigned_url(
+    /* Stores content with the given headers, the content type is guessed from the key when not
+    set. Content is gzip compressed first when the encoding is gzip and it is not already. */
+    pub async fn upload_object(
         &self,
         bucket_name: &str,
         object_key: &str,
-    ) -> Result<String, std::io::Error> {
-        let options = PreSignedRequestOption {
-            expires_in: std::time::Duration::from_secs(300),
+        content: &[u8],
+        options: &PutOptions,
+    ) -> Result<(), S3Error> {
+        let options = PutOptions {
+            content_type: options
+                .content_type
+                .clone()
+                .or_else(|| guess_content_type(object_key).map(str::to_string)),
+            content_encoding: options.content_encoding.clone(),
         };
-        let req = GetObjectRequest {
-            bucket: bucket_name.to_string(),
-            key: object_key.to_string(),
-            ..Default::default()
+        let body = if options.content_encoding.as_deref() == Some("gzip") && !is_gzip(content) {
+            self.compress_gzip(content)?
+        } else {
+            content.to_vec()
         };
 
-        // If you have a session token (for temporary credentials), add it here, otherwise use None.
-        let session_token: Option<String> = None;
+        self.invalidate(bucket_name, object_key).await;
+        self.store.put(bucket_name, object_key, body, &options).await
+    }
 
-        // Optionally, you can set an expiration time. For example, set expiration 1 hour from now.
-        let expiration = Utc::now() + Duration::hours(1);
+    pub async fn presign(
+        &self,
+        bucket_name: &str,
+        object_key: &str,
+        options: &PresignOptions,
+    ) -> Result<String, S3Error> {
+        self.store.presign(bucket_name, object_key, options).await
+    }
 
-        // Create an instance of AwsCredentials
-        let credentials = AwsCredentials::new(
-            self.access_key.to_string(), // AWS Access Key
-            self.secret_key.to_string(), // AWS Secret Key
-            session_token,               // Optional session token
-            Some(expiration),            // Optional expiration time
-        );
-        let url = req.get_presigned_url(&self.region, &credentials, &options);
+    /* URL of an uploaded handler output for DO.signed_url: presigned when [config.s3] presign_outputs
+    is set, so it works on private buckets, otherwise under public_url_prefix */
+    pub async fn output_url(
+        &self,
+        config: &S3Config,
+        file_name: &str,
+        content_type: &str,
+    ) -> Result<String, S3Error> {
+        if !config.presign_outputs {
+            return Ok(format!("{}{}", config.public_url_prefix(), file_name));
+        }
+        let options = PresignOptions {
+            content_type: Some(content_type.to_string()),
+            content_disposition: Some(format!("inline; filename=\"{}\"", file_name)),
+            ..PresignOptions::get(self.presign_expiry)
+        };
+        self.presign(&config.output_bucket, &config.output_key(file_name), &options)
+            .await
+    }
 
-        Ok(url.to_string())
+    /* Presigned PUT a client uploads `file_name` with. The key is under [config.s3] upload_prefix
+    and a fresh uuid so uploads never overwrite each other, returned with the URL. */
+    pub async fn upload_url(
+        &self,
+        config: &S3Config,
+        file_name: &str,
+        content_type: &str,
+    ) -> Result<(String, String), S3Error> {
+        let key = config.upload_key(&format!("{}/{}", uuid::Uuid::new_v4(), file_name));
+        let options = PresignOptions {
+            content_type: Some(content_type.to_string()),
+            ..PresignOptions::put(self.presign_expiry)
+        };
+        let url = self.presign(&config.data_bucket, &key, &options).await?;
+        Ok((key, url))
     }
 
-    #[allow(dead_code)]
-    pub fn compress_string(&self, input: &str) -> Result<Vec<u8>, std::io::Error> {
+    pub fn compress_gzip(&self, input: &[u8]) -> Result<Vec<u8>, S3Error> {
         // Create a Gzip encoder
         let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
 
-        // Write the input string to the encoder
-        encoder.write_all(input.as_bytes())?;
+        // Write the input to the encoder
+        encoder.write_all(input)?;
 
         // Finish the encoding and retrieve the compressed data
         let compressed_data = encoder.finish()?;
@@ -154,129 +744,65 @@ impl S3Module {
         Ok(compressed_data)
     }
 
-    pub fn decompress_gzip(&self, compressed_data: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+    pub fn decompress_gzip(&self, compressed_data: Vec<u8>) -> Result<Vec<u8>, S3Error> {
         // Create a GzDecoder and feed the compressed data into it
         let mut decoder = GzDecoder::new(compressed_data.as_slice());
         // Read the decompressed data into a Vec<u8>
         let mut decompressed_data = Vec::new();
-        decoder.read_to_end(&mut decompressed_data)?;
+        decoder
+            .read_to_end(&mut decompressed_data)
+            .map_err(|error| S3Error::Corrupt(format!("Invalid gzip data: {}", error)))?;
 
         Ok(decompressed_data)
     }
 
+    /* Content of the object, decompressed when it is stored with Content-Encoding gzip or, for
+    objects uploaded without headers, starts with the gzip magic number */
     pub async fn download_object(
         &self,
         bucket_name: &str,
         object_key: &str,
-        decompress: bool,
-    ) -> Result<Vec<u8>, io::Error> {
+    ) -> Result<Vec<u8>, S3Error> {
         // Download an object from the bucket
-        let get_object_request = GetObjectRequest {
-            bucket: bucket_name.to_string(),
-            key: object_key.to_string(),
-            ..Default::default()
-        };
-
-        match self.client.get_object(get_object_request).await {
-            Ok(response) => {
-                let body = response.body.expect("Object body not found");
-
-                let mut bytes = Vec::new();
-                body.into_async_read()
-                    .read_to_end(&mut bytes)
-                    .await
-                    .expect("Failed to read object body");
-
-                if decompress {
-                    match self.decompress_gzip(bytes) {
-                        Ok(data) => {
-                            return Ok(data);
-                        }
-                        Err(error) => {
-                            return Err(error);
-                        }
-                    }
-                }
-                Ok(bytes)
-            }
-            Err(error) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Service error: {:?}", error),
-            )),
+        let object = self.get_object(bucket_name, object_key).await?;
+        let gzip_encoded = object
+            .head
+            .content_encoding
+            .as_deref()
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("gzip"));
+        if gzip_encoded || is_gzip(&object.body) {
+            return self.decompress_gzip(object.body);
         }
+        Ok(object.body)
     }
 
     #[allow(dead_code)]
-    pub async fn delete_object(&self, bucket_name: &str, object_key: &str) {
+    pub async fn delete_object(&self, bucket_name: &str, object_key: &str) -> Result<(), S3Error> {
         // Delete an object from the bucket
-        let delete_object_request = DeleteObjectRequest {
-            bucket: bucket_name.to_string(),
-            key: object_key.to_string(),
-            ..Default::default()
-        };
-
-        self.client
-            .delete_object(delete_object_request)
-            .await
-            .expect("Failed to delete object");
-    }
-
-    pub async fn read_local_file(&self, file_path: &str) -> Result<Vec<u8>, std::io::Error> {
-        let mut file = File::open(file_path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        Ok(buffer)
+        self.invalidate(bucket_name, object_key).await;
+        self.store.delete(bucket_name, object_key).await
     }
 
+    /* Streams the file, labelled with the content type guessed from the key */
     pub async fn upload_file(
         &self,
         bucket_name: &str,
         object_key: &str,
         file_path: &str,
-    ) -> Result<(), std::io::Error> {
-        // Read the local file content
-        // let file_content = self.read_local_file(file_path).await?;
-        let file_content = match self.read_local_file(file_path).await {
-            Ok(value) => value,
-            Err(error) => {
-                return Err(error);
-            }
-        };
-
-        let put_object_request = PutObjectRequest {
-            bucket: bucket_name.to_string(),
-            key: object_key.to_string(),
-            body: Some(file_content.into()),
+    ) -> Result<(), S3Error> {
+        let options = PutOptions {
+            content_type: guess_content_type(object_key).map(str::to_string),
             ..Default::default()
         };
-
-        match self.client.put_object(put_object_request).await {
-            Ok(_) => Ok(()),
-            Err(error) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to put object: {:?}", error),
-            )),
-        }
+        self.invalidate(bucket_name, object_key).await;
+        self.store
+            .put_file(bucket_name, object_key, Path::new(file_path), &options)
+            .await
     }
 
-    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, std::io::Error> {
-        let head_req = HeadObjectRequest {
-            bucket: bucket.to_string(),
-            key: key.to_string(),
-            ..Default::default()
-        };
-
-        match self.client.head_object(head_req).await {
-            Ok(_) => Ok(true), // Object exists
-            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false), // Object does not exist
-            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => {
-                Ok(false) // Handle other cases where a 404 status indicates the object or bucket doesn't exist
-            }
-            Err(e) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to check if object exists: {:?}", e),
-            )), // Some other error occurred
-        }
+    #[allow(dead_code)]
+    async fn object_exists(&self, bucket: &str, key: &str) -> Result<bool, S3Error> {
+        Ok(self.store.head(bucket, key).await?.is_some())
     }
 
     pub async fn is_blob_older_than_mins(
@@ -284,47 +810,54 @@ impl S3Module {
         bucket: &str,
         key: &str,
         mins: i64,
-    ) -> Result<bool, std::io::Error> {
-        let head_req = HeadObjectRequest {
-            bucket: bucket.to_string(),
-            key: key.to_string(),
-            ..Default::default()
-        };
+    ) -> Result<bool, S3Error> {
+        match self.store.head(bucket, key).await? {
+            // Compare the LastModified date with the cutoff date
+            Some(ObjectHead {
+                last_modified: Some(last_modified_date),
+                ..
+            }) => Ok(last_modified_date < Utc::now() - Duration::minutes(mins)),
+            // Object does not exist or has no LastModified
+            _ => Ok(false),
+        }
+    }
+}
 
-        match self.client.head_object(head_req).await {
-            Ok(result) => {
-                // Get the LastModified date from the metadata
-                if let Some(last_modified) = result.last_modified {
-                    // Parse the LastModified date into a chrono::DateTime object
-                    // match last_modified.parse::<chrono::DateTime<Utc>>() {
-                    match DateTime::parse_from_rfc2822(&last_modified)
-                        .map(|dt| dt.with_timezone(&Utc))
-                    {
-                        Ok(last_modified_date) => {
-                            let cutoff_date = Utc::now() - Duration::minutes(mins);
-
-                            // Compare the LastModified date with the cutoff date
-                            Ok(last_modified_date < cutoff_date)
-                        } // Successfully parsed the date
-                        Err(e) => Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("Unable to parse data: {:?}", e),
-                        )),
-                    }
-                } else {
-                    Ok(false)
-                }
-            } // Object exists
-            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false), // Object does not exist
-            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => {
-                Ok(false) // Handle other cases where a 404 status indicates the object or bucket doesn't exist
+/* Config of NODE_ENV, for tests of the services that call the data provider or Python */
+#[cfg(test)]
+pub(crate) fn configured_app_config() -> Arc<AppConfig> {
+    Arc::new(AppConfig::new().expect("Unable to load config"))
+}
+
+/* Config with the default [config.s3] buckets and prefixes, which the fixtures are laid out in */
+#[cfg(test)]
+pub(crate) fn fixture_app_config() -> Arc<AppConfig> {
+    Arc::new(AppConfig::for_tests("backend_rust_topic = \"test\""))
+}
+
+/* S3Module over a copy of tests/fixtures, <bucket>/<key> as in LocalObjectStore, so tests run
+offline and their uploads never land in the repo. The copy is removed with the TempDir. */
+#[cfg(test)]
+pub(crate) fn fixture_s3_module() -> (Arc<S3Module>, tempfile::TempDir) {
+    fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            let target = to.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                copy_dir(&entry.path(), &target)?;
+            } else {
+                std::fs::copy(entry.path(), target)?;
             }
-            Err(e) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to check if object exists: {:?}", e),
-            )), // Some other error occurred
         }
+        Ok(())
     }
+
+    let root = tempfile::tempdir().unwrap();
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    copy_dir(&fixtures, root.path()).expect("Unable to copy the fixtures");
+    let store = LocalObjectStore::new(root.path());
+    (Arc::new(S3Module::new(Arc::new(store))), root)
 }
 
 #[cfg(test)]
@@ -337,34 +870,101 @@ mod tests {
     use tempfile::NamedTempFile;
     use tokio::test;
 
+    #[test]
+    async fn test_part_ranges_cover_the_object() {
+        const MIB: u64 = 1024 * 1024;
+        assert_eq!(
+            part_ranges(20 * MIB + 1, 8 * MIB),
+            vec![(1, 0, 8 * MIB), (2, 8 * MIB, 8 * MIB), (3, 16 * MIB, 4 * MIB + 1)]
+        );
+        // Parts are never smaller than the S3 minimum
+        assert_eq!(part_ranges(6 * MIB, MIB), vec![(1, 0, 5 * MIB), (2, 5 * MIB, MIB)]);
+        assert_eq!(part_ranges(16 * MIB, 8 * MIB).len(), 2);
+    }
+
+    #[test]
+    async fn test_presigned_url_options() {
+        let region = Region::Custom {
+            name: "custom".to_string(),
+            endpoint: "https://usc1.contabostorage.com".to_string(),
+        };
+        let options = PresignOptions {
+            content_type: Some("image/png".to_string()),
+            content_disposition: Some("attachment".to_string()),
+            ..PresignOptions::get(std::time::Duration::from_secs(600))
+        };
+        let url = presigned_url(&region, "key", "secret", "sravz", "a.png", &options);
+        assert!(url.starts_with("https://usc1.contabostorage.com/sravz/a.png?"));
+        assert!(url.contains("X-Amz-Expires=600"));
+        assert!(url.contains("response-content-type=image%2Fpng"));
+        assert!(url.contains("response-content-disposition=attachment"));
+
+        // Expiry is capped at the SigV4 maximum
+        let options = PresignOptions::put(std::time::Duration::from_secs(30 * 24 * 60 * 60));
+        let url = presigned_url(&region, "key", "secret", "sravz", "a.png", &options);
+        assert!(url.contains("X-Amz-Expires=604800"));
+        assert!(!url.contains("response-content-type"));
+    }
+
+    /* S3Module on a temporary directory, removed when the TempDir is dropped */
+    fn local_s3_module() -> (S3Module, tempfile::TempDir) {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path());
+        (S3Module::new(Arc::new(store)), root)
+    }
+
     // Test the create_bucket method
     #[test]
     async fn test_create_bucket() {
-        let s3_module = S3Module::new();
+        let (s3_module, _root) = local_s3_module();
 
         // Generate a unique bucket name for testing
         let bucket_name = format!("test-bucket-{}", uuid::Uuid::new_v4());
 
         // Ensure the bucket is created without errors
-        s3_module.create_bucket(&bucket_name).await;
+        s3_module.create_bucket(&bucket_name).await.unwrap();
     }
 
     // Test the list_objects method
     #[test]
     async fn test_list_objects() {
-        let s3_module = S3Module::new();
+        let (s3_module, _root) = local_s3_module();
 
         // Provide an existing bucket name for testing
         let bucket_name = "existing-bucket";
+        for key in ["historical/a.json", "historical/b.json", "historical/1d/c.json", "eod/d.json"] {
+            s3_module
+                .upload_object(bucket_name, key, b"{}", &PutOptions::gzip("application/json"))
+                .await
+                .unwrap();
+        }
+
+        let listing = s3_module.list_objects(bucket_name, "historical/", None).await.unwrap();
+        assert_eq!(listing.objects.len(), 3);
+        assert!(listing.objects.iter().all(|object| object.head.size > 0));
+
+        let listing = s3_module
+            .list_objects(bucket_name, "historical/", Some("/"))
+            .await
+            .unwrap();
+        let keys: Vec<&str> = listing.objects.iter().map(|object| object.key.as_str()).collect();
+        assert_eq!(keys, vec!["historical/a.json", "historical/b.json"]);
+        assert_eq!(listing.common_prefixes, vec!["historical/1d/"]);
 
-        // Ensure listing objects in the bucket doesn't result in errors
-        s3_module.list_objects(&bucket_name).await;
+        // Paging by hand
+        let options = ListOptions {
+            max_keys: Some(1),
+            ..Default::default()
+        };
+        let page = s3_module.list_page(bucket_name, &options).await.unwrap();
+        assert_eq!(page.objects.len(), 1);
+        assert!(page.next_continuation_token.is_some());
     }
 
     // Test the upload_object and download_object methods
     #[test]
     async fn test_upload_and_download_object() {
-        let s3_module = S3Module::new();
+        let (s3_module, _root) = local_s3_module();
 
         // Provide a unique bucket and object key for testing
         let bucket_name = "sravz";
@@ -383,13 +983,13 @@ mod tests {
         let content = json_string_literal.to_string();
         // Upload an object to S3
         s3_module
-            .upload_object(&bucket_name, object_key, &content)
+            .upload_object(bucket_name, object_key, content.as_bytes(), &PutOptions::gzip("application/json"))
             .await
             .unwrap();
 
         // Download the object from S3
         let downloaded_content = s3_module
-            .download_object(&bucket_name, object_key, true)
+            .download_object(bucket_name, object_key)
             .await
             .unwrap();
 
@@ -397,10 +997,69 @@ mod tests {
         assert_eq!(downloaded_content, content.as_bytes().to_vec());
     }
 
+    #[test]
+    async fn test_download_object_decompresses_by_content() {
+        let (s3_module, _root) = local_s3_module();
+        let plain = b"{\"plain\": true}";
+        s3_module
+            .upload_object("sravz", "trash/plain.json", plain, &PutOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(s3_module.download_object("sravz", "trash/plain.json").await.unwrap(), plain);
+
+        // Compressed before upload, stored without Content-Encoding
+        let compressed = s3_module.compress_gzip(plain).unwrap();
+        s3_module
+            .upload_object("sravz", "trash/compressed", &compressed, &PutOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(s3_module.download_object("sravz", "trash/compressed").await.unwrap(), plain);
+    }
+
+    #[test]
+    async fn test_errors_are_typed() {
+        let (s3_module, _root) = local_s3_module();
+        let err = s3_module.download_object("sravz", "trash/missing.json").await.unwrap_err();
+        assert!(matches!(err, S3Error::NotFound(_)));
+
+        // Gzip magic number followed by garbage
+        s3_module
+            .upload_object("sravz", "trash/corrupt", &[0x1f, 0x8b, 0, 1], &PutOptions::default())
+            .await
+            .unwrap();
+        let err = s3_module.download_object("sravz", "trash/corrupt").await.unwrap_err();
+        assert!(matches!(err, S3Error::Corrupt(_)));
+    }
+
+    #[test]
+    async fn test_cached_downloads_are_revalidated() {
+        let root = tempfile::tempdir().unwrap();
+        let cache_directory = tempfile::tempdir().unwrap();
+        let s3_module = S3Module::new(Arc::new(LocalObjectStore::new(root.path())))
+            .with_cache(ObjectCache::open(cache_directory.path(), 1024).unwrap());
+        // Writes that bypass the module and its cache
+        let store = LocalObjectStore::new(root.path());
+        let options = PutOptions::default();
+
+        s3_module
+            .upload_object("sravz", "historical/a.json", b"[1]", &options)
+            .await
+            .unwrap();
+        assert_eq!(s3_module.download_object("sravz", "historical/a.json").await.unwrap(), b"[1]");
+        assert_eq!(s3_module.download_object("sravz", "historical/a.json").await.unwrap(), b"[1]");
+
+        store.put("sravz", "historical/a.json", b"[1, 2]".to_vec(), &options).await.unwrap();
+        assert_eq!(s3_module.download_object("sravz", "historical/a.json").await.unwrap(), b"[1, 2]");
+
+        store.delete("sravz", "historical/a.json").await.unwrap();
+        let err = s3_module.download_object("sravz", "historical/a.json").await.unwrap_err();
+        assert!(matches!(err, S3Error::NotFound(_)));
+    }
+
     // Test the delete_object method
     #[test]
     async fn test_delete_object() {
-        let s3_module = S3Module::new();
+        let (s3_module, _root) = local_s3_module();
 
         // Provide a unique bucket and object key for testing
         let bucket_name = "sravz";
@@ -408,17 +1067,17 @@ mod tests {
 
         // Upload an object to S3
         s3_module
-            .upload_object(&bucket_name, object_key, "")
+            .upload_object(bucket_name, object_key, b"", &PutOptions::default())
             .await
             .unwrap();
 
         // Ensure the object is deleted without errors
-        s3_module.delete_object(&bucket_name, object_key).await;
+        s3_module.delete_object(bucket_name, object_key).await.unwrap();
     }
 
     #[tokio::test]
     async fn test_object_exists_true() {
-        let s3_module = S3Module::new();
+        let (s3_module, _root) = local_s3_module();
 
         // Provide a unique bucket and object key for testing
         let bucket_name = "sravz";
@@ -437,7 +1096,7 @@ mod tests {
         let content = json_string_literal.to_string();
         // Upload an object to S3
         s3_module
-            .upload_object(&bucket_name, object_key, &content)
+            .upload_object(bucket_name, object_key, content.as_bytes(), &PutOptions::gzip("application/json"))
             .await
             .unwrap();
 
@@ -450,7 +1109,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_object_exists_false() {
-        let s3_module = S3Module::new();
+        let (s3_module, _root) = local_s3_module();
         let bucket_name = "sravz";
         let object_key = "trash/test-object1.json";
 
@@ -462,13 +1121,17 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_generate_presigned_url() {
-        let s3_module = S3Module::new();
+    async fn test_presign() {
+        let (s3_module, _root) = local_s3_module();
         let bucket_name = "sravz";
         let object_key = "trash/test-object1.json";
 
         let presinged_url = s3_module
-            .generate_presigned_url(bucket_name, object_key)
+            .presign(
+                bucket_name,
+                object_key,
+                &PresignOptions::get(std::time::Duration::from_secs(300)),
+            )
             .await
             .unwrap();
         println!("Genereated Presigned URL: {}", presinged_url);
@@ -494,7 +1157,7 @@ mod tests {
 
     #[test]
     async fn test_upload_file() -> io::Result<()> {
-        let s3_module = S3Module::new();
+        let (s3_module, _root) = local_s3_module();
 
         // Provide a unique bucket and object key for testing
         let bucket_name = "sravz";
@@ -514,7 +1177,7 @@ mod tests {
             // Now you can pass the &str to the function
             // Upload an object to S3
             match s3_module
-                .upload_file(&bucket_name, object_key, path_str)
+                .upload_file(bucket_name, object_key, path_str)
                 .await
             {
                 Ok(_) => {
@@ -529,7 +1192,7 @@ mod tests {
         }
 
         // Ensure the object is deleted without errors
-        s3_module.delete_object(&bucket_name, object_key).await;
+        s3_module.delete_object(bucket_name, object_key).await.unwrap();
 
         Ok(())
     }
@@ -537,7 +1200,7 @@ mod tests {
     #[tokio::test]
     async fn test_is_blob_older_than_mins_blob_is_older() -> Result<(), Box<dyn Error>> {
         // Mock the S3 client
-        let s3_module = S3Module::new();
+        let (s3_module, _root) = local_s3_module();
 
         // Provide a unique bucket and object key for testing
         let bucket_name = "sravz";
@@ -550,7 +1213,7 @@ mod tests {
             .unwrap();
 
         if exists {
-            s3_module.delete_object(&bucket_name, object_key).await;
+            s3_module.delete_object(bucket_name, object_key).await.unwrap();
         }
 
         // let content = "{\"Message\": \"Hello, this is a test content.\"}";
@@ -567,7 +1230,7 @@ mod tests {
         let content = json_string_literal.to_string();
         // Upload an object to S3
         s3_module
-            .upload_object(&bucket_name, object_key, &content)
+            .upload_object(bucket_name, object_key, content.as_bytes(), &PutOptions::gzip("application/json"))
             .await
             .unwrap();
 
@@ -584,7 +1247,7 @@ mod tests {
             .unwrap();
 
         if exists {
-            s3_module.delete_object(&bucket_name, object_key).await;
+            s3_module.delete_object(bucket_name, object_key).await.unwrap();
         }
 
         Ok(())
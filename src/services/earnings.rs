@@ -1,8 +1,11 @@
 use crate::{
+    cache_key::ArgRules,
     config::AppConfig,
     dataframe_service::DataFrameCache,
+    handler_registry::{HandlerCachePolicy, MessageHandler},
     models::Message,
-    py03_service::{run_py_module, PyMessage},
+    progress::Progress,
+    py03_service::{spawn_py_module, PyMessage},
     s3_service::S3Module,
 };
 use log::error;
@@ -10,17 +13,17 @@ use log::info;
 use polars::prelude::*;
 use std::error::Error;
 use std::io;
+use std::sync::Arc;
 
 pub struct Earnings {
     dataframe_service: DataFrameCache,
-    s3_module: S3Module,
-    config: AppConfig,
+    s3_module: Arc<S3Module>,
+    config: Arc<AppConfig>,
 }
 
 impl Earnings {
-    pub fn new(config: AppConfig) -> Self {
-        let dataframe_service = DataFrameCache::new();
-        let s3_module = S3Module::new();
+    pub fn new(config: Arc<AppConfig>, s3_module: Arc<S3Module>) -> Self {
+        let dataframe_service = DataFrameCache::new(config.clone(), s3_module.clone());
         Earnings {
             dataframe_service,
             s3_module,
@@ -29,68 +32,53 @@ impl Earnings {
     }
 
     pub async fn get_earnings_s3_url(
-        &mut self,
+        &self,
         sravz_id: &str,
         code: &str,
-    ) -> Result<Option<String>, Box<dyn Error>> {
-        let result = self.get_earnings(sravz_id, code).await;
-        match result.unwrap() {
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        match self.get_earnings(sravz_id, code).await? {
             Some(df) => {
-                let result = self
-                    .dataframe_service
-                    .save_dataframe_to_s3(&df, &format!("historical/earnings/{}.json", sravz_id))
-                    .await;
-
-                match result.unwrap() {
-                    presigned_url => Ok(presigned_url),
-                }
+                self.dataframe_service
+                    .save_dataframe_to_s3(
+                        &df,
+                        &self
+                            .config
+                            .config
+                            .s3
+                            .historical_key(&format!("earnings/{}.json", sravz_id)),
+                    )
+                    .await
             }
             None => Ok(None),
         }
     }
 
     pub async fn get_earnings_df_parquet(
-        &mut self,
+        &self,
         sravz_id: &str,
         code: &str,
-    ) -> Result<Option<String>, Box<dyn Error>> {
-        let result = self.get_earnings(sravz_id, code).await;
-        match result.unwrap() {
-            Some(df) => {
-                let result = self.dataframe_service.dataframe_to_parquet(df).await;
-
-                match result.unwrap() {
-                    parquet_file_path => Ok(parquet_file_path),
-                }
-            }
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        match self.get_earnings(sravz_id, code).await? {
+            Some(df) => self.dataframe_service.dataframe_to_parquet(df).await,
             None => Ok(None),
         }
     }
 
     /* Get earning dataframe */
     pub async fn get_earnings(
-        &mut self,
+        &self,
         sravz_id: &str,
         code: &str,
-    ) -> Result<Option<DataFrame>, Box<dyn Error>> {
-        let mut data_frame_cache: DataFrameCache = DataFrameCache::new();
-        let historical_result = data_frame_cache.get_dataframe(sravz_id.to_string()).await;
-
-        // Handle error from get_dataframe
-        let historical_df_opt = match historical_result {
-            Ok(opt) => opt,
-            Err(e) => {
-                error!("Error fetching historical dataframe: {}", e);
-                return Ok(None);
-            }
-        };
-
+    ) -> Result<Option<DataFrame>, Box<dyn Error + Send + Sync>> {
+        let historical_df_opt = self
+            .dataframe_service
+            .get_dataframe(sravz_id.to_string())
+            .await?;
 
         match historical_df_opt {
             Some(historical_df) => {
                 println!("Historical Dateframe Head {}", historical_df.head(Some(10)));
-                let earnings_result = data_frame_cache.get_earnings_dataframe(code).await;
-                match earnings_result.unwrap() {
+                match self.dataframe_service.get_earnings_dataframe(code).await? {
                     Some(earnings_df) => {
                         info!("Earnings Dateframe Head {}", earnings_df.head(Some(10)));
 
@@ -112,14 +100,12 @@ impl Earnings {
                             .collect()?;
 
                         // Perform the join on DateTime and report_date columns
-                        let joined_df_result = historical_df.join(
+                        let joined_df = historical_df.join(
                             &earnings_df,
                             ["DateTime"],
                             ["ReportDateTime"],
                             JoinArgs::new(JoinType::Outer),
-                        );
-
-                        let joined_df = joined_df_result.unwrap();
+                        )?;
                         info!("Dateframe Head {}", joined_df.head(Some(10)));
 
                         // let joined_df = joined_df.slice(0, 10);
@@ -185,18 +171,20 @@ impl Earnings {
     }
 
     pub async fn get_earnings_plot(
-        &mut self,
+        &self,
         mut message: Message,
-    ) -> Result<Message, Box<dyn Error>> {
+        progress: &Progress,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
         let object_keys = message.p_i.args.clone();
         match &object_keys[..] {
             [sravz_id, code, ..] => {
                 info!("sravz_id: {}, code: {}", sravz_id, code);
-                let result = self.get_earnings_df_parquet(sravz_id, code).await;
-                match result.unwrap() {
+                progress.report("loading_earnings", 10);
+                match self.get_earnings_df_parquet(sravz_id, code).await? {
                     Some(url) => {
                         info!("Parquet file path: {}", url);
-                        match run_py_module(PyMessage::new(
+                        progress.report("plotting", 40);
+                        match spawn_py_module(PyMessage::new(
                             message.id.to_string(),
                             message.key.to_string(),
                             sravz_id.to_string(),
@@ -204,25 +192,33 @@ impl Earnings {
                             url.to_string(),
                             message.p_i.kwargs.json_keys.as_ref().map(|keys| keys.join(",")),
                             Some(message.p_i.kwargs.llm_query.clone().unwrap_or_default()),
-                        )) {
+                        ), progress.clone())
+                        .await
+                        {
                             Ok(_) => {
+                                progress.report("uploading", 90);
                                 self.s3_module
                                     .upload_file(
-                                        "sravz",
-                                        &format!("rust-backend/{}.png", message.key),
+                                        &self.config.config.s3.output_bucket,
+                                        &self.config.config.s3.output_key(&format!("{}.png", message.key)),
                                         &format!("/tmp/data/{}.png", message.key),
                                     )
                                     .await?;
+                                let file_name = format!("{}.png", message.key);
+                                let signed_url = self
+                                    .s3_module
+                                    .output_url(&self.config.config.s3, &file_name, "image/png")
+                                    .await?;
                                 message.update_s3_location(
-                                    self.config.contabo_bucket.clone(),
-                                    self.config.contabo_object_url_prefix.clone(),
-                                    format!("{}.png", message.key),
+                                    self.config.config.s3.output_bucket.clone(),
+                                    file_name,
+                                    signed_url,
                                 )
                             }
                             Err(err) => {
                                 error!("Error executing Python code: {:?}", err);
                                 return Err(Box::new(io::Error::new(
-                                    io::ErrorKind::Other,
+                                    err.kind(),
                                     format!("Service error: {:?}", err),
                                 )));
                             }
@@ -240,11 +236,53 @@ impl Earnings {
     }
 }
 
+#[async_trait::async_trait]
+impl MessageHandler for Earnings {
+    fn name(&self) -> &'static str {
+        "earnings"
+    }
+
+    fn description(&self) -> &'static str {
+        "Earnings plot, args are [sravz_id, code]"
+    }
+
+    fn message_ids(&self) -> std::ops::RangeInclusive<f64> {
+        3.0..=3.009
+    }
+
+    fn validate(&self, message: &Message) -> Result<(), String> {
+        if message.p_i.args.len() < 2 {
+            return Err("Earnings requires args [sravz_id, code]".to_string());
+        }
+        Ok(())
+    }
+
+    fn cache_policy(&self) -> HandlerCachePolicy {
+        // [sravz_id, code] is positional
+        HandlerCachePolicy {
+            arg_rules: ArgRules {
+                order_insensitive: false,
+                case_insensitive: true,
+                case_insensitive_kwargs: false,
+            },
+            default_ttl_secs: None,
+        }
+    }
+
+    async fn handle(
+        &self,
+        message: Message,
+        progress: Progress,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        self.get_earnings_plot(message, &progress).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        config::AppConfig,
         models::{Kwargs, Message},
+        s3_service::{configured_app_config, fixture_s3_module},
         services::earnings::Earnings,
     };
     use chrono::Utc;
@@ -252,15 +290,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_earnings() {
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut earnings: Earnings = Earnings::new(config);
+        let config = configured_app_config();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let earnings: Earnings = Earnings::new(config, s3_module);
         // Perform the GET request using the mock server URL
         let result = earnings.get_earnings("stk_us_nvda", "NVDA").await;
         match result.unwrap() {
@@ -276,15 +308,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_earnings_json_string() {
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut earnings: Earnings = Earnings::new(config);
+        let config = configured_app_config();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let earnings: Earnings = Earnings::new(config, s3_module);
 
         // Perform the GET request using the mock server URL
         let result = earnings.get_earnings("stk_us_nvda", "NVDA").await;
@@ -309,15 +335,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_earnings_s3_url() {
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut earnings: Earnings = Earnings::new(config);
+        let config = configured_app_config();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let earnings: Earnings = Earnings::new(config, s3_module);
 
         // Perform the GET request using the mock server URL
         let result = earnings.get_earnings_s3_url("stk_us_nvda", "NVDA").await;
@@ -333,15 +353,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_earnings_parquet_file() {
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut earnings: Earnings = Earnings::new(config);
+        let config = configured_app_config();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let earnings: Earnings = Earnings::new(config, s3_module);
 
         // Perform the GET request using the mock server URL
         let result = earnings
@@ -359,15 +373,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_earnings_plot() {
-        let config = match AppConfig::new() {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                // Handle the error or exit the program
-                std::process::exit(1);
-            }
-        };
-        let mut earnings: Earnings = Earnings::new(config);
+        let config = configured_app_config();
+        let (s3_module, _fixtures) = fixture_s3_module();
+        let earnings: Earnings = Earnings::new(config, s3_module);
 
         // Perform the GET request using the mock server URL
         let result = earnings
@@ -380,6 +388,7 @@ mod tests {
                         upload_to_aws: true,
                         json_keys: Some(Vec::new()),
                         llm_query: Some(String::new()),
+                        force_refresh: None,
                     },
                 },
                 t_o: String::new(),
@@ -392,13 +401,16 @@ mod tests {
                 e: String::new(),
                 key: "3".to_string(),
                 exception_message: String::new(),
+                attempts: 0,
+                retry_errors: Vec::new(),
                 d_o: Some(crate::models::DO {
                     bucket_name: "Fake".to_string(),
                     key_name: "Fake".to_string(),
                     data: serde_json::Value::String("Fake".to_string()),
                     signed_url: "Fake".to_string(),
+                    data_ref: None,
                 }),
-            })
+            }, &crate::progress::Progress::disabled())
             .await;
 
         match result {
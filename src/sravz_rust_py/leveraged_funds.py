@@ -7,7 +7,12 @@ import numpy as np
 import pandas as pd
 
 
-def process(price_df: pd.DataFrame, message_key: str):
+def process(price_df: pd.DataFrame, message_key: str, progress=None):
+    '''
+        progress(stage, percent) reports the stages between 30% and 90%
+    '''
+    progress = progress or (lambda stage, percent: None)
+    progress("returns", 35)
     price_col_name = 'AdjustedClose'
     base_etf_or_index_col = 'etf_us_qqq_AdjustedClose'
     col = 'etf_us_qqq_AdjustedClose'
@@ -138,7 +143,9 @@ def process(price_df: pd.DataFrame, message_key: str):
 
     chart_index = chart_index + 1
     garch_conditional_vol = plt.subplot(gs[chart_index, :])
-    for col in adjusted_closed_columns:
+    for index, col in enumerate(adjusted_closed_columns):
+        progress(f"garch_fit_{col}", 50 + 30 * index //
+                 max(len(adjusted_closed_columns), 1))
         returns_df = price_df[f'{col}_Daily_Return'].dropna()
         model = arch.arch_model(returns_df, vol='Garch', p=1, q=1)
         # Fit the model
@@ -154,6 +161,7 @@ def process(price_df: pd.DataFrame, message_key: str):
         print(price_df.groupby(price_df.index.year)[
               f'{col}_Cumulative_Returns'].last().pct_change() * 100)
 
+    progress("saving_chart", 85)
     logging.info(f"Saving file to /tmp/data/{message_key}.png")
     plt.savefig(f"/tmp/data/{message_key}", bbox_inches='tight')
     return f"/tmp/data/{message_key}.png"
@@ -30,11 +30,19 @@ logger.addHandler(file_handler)
 logger.addHandler(console_handler)
 
 
-def run(py_message: PyMessage) -> PyMessage:
+def _no_progress(stage: str, percent: int) -> None:
     '''
-        Run function for the given py_message.message_id
+        Used when the caller did not pass a progress callback
+    '''
+
+
+def run(py_message: PyMessage, progress=None) -> PyMessage:
+    '''
+        Run function for the given py_message.message_id.
+        progress(stage, percent) publishes a progress event on the message's reply topic.
     '''
     logger.info("Message recevied: %s", py_message)
+    progress = progress or _no_progress
     message_id = float(py_message.message_id)
     try:
         if message_id == 1.0:
@@ -46,11 +54,12 @@ def run(py_message: PyMessage) -> PyMessage:
                 df = pd.read_parquet(file_path)
                 import leveraged_funds
                 py_message.output = leveraged_funds.process(
-                    df, py_message.key)
+                    df, py_message.key, progress)
             else:
                 logger.info("The file %s does not exist.", file_path)
         elif message_id == 2.0:
             import agent_supervisor
+            progress("llm_agents", 20)
             py_message.output = agent_supervisor.query(
                 py_message.sravz_ids.split(","),
                 py_message.json_keys.split(","),
@@ -60,6 +69,7 @@ def run(py_message: PyMessage) -> PyMessage:
             file_path = f"/tmp/data/{py_message.key}.png"
             logger.info("Processing file path and id: %s - file_path %s",
                         py_message, file_path)
+            progress("earnings_plot", 50)
             py_message.output = earnings.main(py_message.sravz_ids,
                                               py_message.df_parquet_file_path, file_path)
         else:
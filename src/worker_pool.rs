@@ -0,0 +1,159 @@
+use log::{error, info};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/* Bounded pool of tokio tasks used to process NSQ messages concurrently */
+pub struct WorkerPool {
+    semaphore: Arc<Semaphore>,
+    tasks: JoinSet<()>,
+    size: usize,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        // A pool of zero workers would never make progress
+        let size = size.max(1);
+        info!("Starting worker pool with {} workers", size);
+        WorkerPool {
+            semaphore: Arc::new(Semaphore::new(size)),
+            tasks: JoinSet::new(),
+            size,
+        }
+    }
+
+    /* Waits for a free worker and runs the job on it.
+    The caller is held here while all workers are busy, which keeps the consumer
+    from pulling more messages than it can process. */
+    pub async fn spawn<F>(&mut self, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if self.semaphore.available_permits() == 0 {
+            info!("All {} workers are busy, waiting for a free worker", self.size);
+        }
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Worker pool semaphore closed");
+        self.reap_finished();
+        self.tasks.spawn(async move {
+            job.await;
+            drop(permit);
+        });
+    }
+
+    /* Waits for every running job to complete */
+    pub async fn join_all(&mut self) {
+        while let Some(result) = self.tasks.join_next().await {
+            if let Err(err) = result {
+                if !err.is_cancelled() {
+                    error!("Worker task failed: {}", err);
+                }
+            }
+        }
+    }
+
+    /* Lets running jobs finish within the grace period and aborts the rest.
+    Returns true when every job completed on its own. */
+    pub async fn shutdown(&mut self, grace_period: Duration) -> bool {
+        info!(
+            "Waiting up to {:?} for {} in-flight jobs",
+            grace_period,
+            self.tasks.len()
+        );
+        if tokio::time::timeout(grace_period, self.join_all()).await.is_ok() {
+            return true;
+        }
+        error!(
+            "Grace period expired, aborting {} in-flight jobs",
+            self.tasks.len()
+        );
+        self.tasks.abort_all();
+        self.join_all().await;
+        false
+    }
+
+    fn reap_finished(&mut self) {
+        while let Some(result) = self.tasks.try_join_next() {
+            if let Err(err) = result {
+                error!("Worker task failed: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_worker_pool_limits_concurrency() {
+        let mut worker_pool = WorkerPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        for _ in 0..6 {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            worker_pool
+                .spawn(async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+                .await;
+            assert!(worker_pool.semaphore.available_permits() <= 2);
+        }
+        worker_pool.join_all().await;
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert_eq!(worker_pool.semaphore.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_worker_pool_zero_size_uses_one_worker() {
+        let mut worker_pool = WorkerPool::new(0);
+        assert_eq!(worker_pool.size, 1);
+        worker_pool.spawn(async {}).await;
+        worker_pool.join_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_worker_pool_shutdown_waits_for_jobs() {
+        let mut worker_pool = WorkerPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let done = done.clone();
+            worker_pool
+                .spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    done.fetch_add(1, Ordering::SeqCst);
+                })
+                .await;
+        }
+        assert!(worker_pool.shutdown(Duration::from_secs(5)).await);
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_worker_pool_shutdown_aborts_after_grace_period() {
+        let mut worker_pool = WorkerPool::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+        let job_done = done.clone();
+        worker_pool
+            .spawn(async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                job_done.fetch_add(1, Ordering::SeqCst);
+            })
+            .await;
+        assert!(!worker_pool.shutdown(Duration::from_millis(20)).await);
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+        // The aborted job must give its worker back
+        assert_eq!(worker_pool.semaphore.available_permits(), 1);
+    }
+}